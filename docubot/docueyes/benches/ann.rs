@@ -0,0 +1,103 @@
+/*
+ *
+ * Compares the HNSW index against exact brute force search on synthetic clustered embeddings,
+ * reporting recall@10 and mean query latency for a few `ef_search` settings.
+ *
+ * Run with `cargo bench --no-default-features --bench ann`, sizes can be overridden with
+ * `ANN_BENCH_PAGES`, `ANN_BENCH_DIMENSION` and `ANN_BENCH_QUERIES`.
+ *
+ */
+
+use docueyes::ann::{GraphSource, HnswIndex, HnswParams};
+use docueyes::engine::top_k;
+use docueyes::store::EmbeddingStore;
+use std::collections::HashSet;
+use std::env;
+use std::time::{Duration, Instant};
+
+const K: usize = 10;
+const CLUSTERS: usize = 64;
+
+fn main() {
+    let pages = env_usize("ANN_BENCH_PAGES", 20_000);
+    let dimension = env_usize("ANN_BENCH_DIMENSION", 384);
+    let query_count = env_usize("ANN_BENCH_QUERIES", 200);
+
+    let mut rng = Rng(42);
+    let centers: Vec<Vec<f32>> = (0..CLUSTERS).map(|_| rng.vector(dimension, 1.0)).collect();
+    let rows: Vec<Vec<f32>> = (0..pages)
+        .map(|i| jitter(&centers[i % CLUSTERS], &mut rng, 0.35))
+        .collect();
+    let queries: Vec<Vec<f32>> = (0..query_count)
+        .map(|i| jitter(&centers[(i * 7) % CLUSTERS], &mut rng, 0.35))
+        .collect();
+    let store = EmbeddingStore::from_rows((0..pages as i64).collect(), rows).expect("valid synthetic store");
+
+    println!("{} pages, {} dimensions, {} queries, recall@{}", pages, dimension, query_count, K);
+
+    let started = Instant::now();
+    let exact: Vec<HashSet<i64>> = queries
+        .iter()
+        .map(|query| top_k(store.scores(query), K).iter().map(|s| s.id).collect())
+        .collect();
+    let brute_force = started.elapsed();
+    println!("brute force          {:>10.3} ms/query", per_query(brute_force, query_count));
+
+    for ef_search in [16, 32, 64, 128, 256] {
+        let params = HnswParams {
+            ef_search,
+            ..HnswParams::default()
+        };
+        let started = Instant::now();
+        let index = HnswIndex::build(&store, params, GraphSource::default());
+        let build = started.elapsed();
+
+        let started = Instant::now();
+        let mut found = 0;
+        for (query, exact) in queries.iter().zip(&exact) {
+            found += index
+                .search(&store, query, K)
+                .iter()
+                .filter(|score| exact.contains(&score.id))
+                .count();
+        }
+        let search = started.elapsed();
+        println!(
+            "hnsw ef_search={:<4} {:>10.3} ms/query  recall {:.3}  build {:.1} s",
+            ef_search,
+            per_query(search, query_count),
+            found as f32 / (query_count * K) as f32,
+            build.as_secs_f32()
+        );
+    }
+}
+
+fn per_query(elapsed: Duration, queries: usize) -> f64 {
+    elapsed.as_secs_f64() * 1000.0 / queries.max(1) as f64
+}
+
+fn env_usize(name: &str, default: usize) -> usize {
+    env::var(name).ok().and_then(|value| value.parse().ok()).unwrap_or(default)
+}
+
+fn jitter(center: &[f32], rng: &mut Rng, scale: f32) -> Vec<f32> {
+    center.iter().map(|x| x + rng.uniform() * scale).collect()
+}
+
+///
+/// Xorshift generator so every run benchmarks the same data.
+///
+struct Rng(u64);
+
+impl Rng {
+    fn uniform(&mut self) -> f32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 40) as f32 / (1u64 << 24) as f32 * 2.0 - 1.0
+    }
+
+    fn vector(&mut self, dimension: usize, scale: f32) -> Vec<f32> {
+        (0..dimension).map(|_| self.uniform() * scale).collect()
+    }
+}
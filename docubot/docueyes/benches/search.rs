@@ -0,0 +1,88 @@
+/*
+ *
+ * Compares exhaustive query scoring over separately allocated rows, recomputing both norms per page,
+ * against the contiguous pre-normalized EmbeddingStore matrix in every quantization mode.
+ *
+ * Run with `cargo bench --no-default-features --bench search`, add `--features parallel` to score
+ * across threads. Sizes can be overridden with `SEARCH_BENCH_PAGES`, `SEARCH_BENCH_DIMENSION` and
+ * `SEARCH_BENCH_QUERIES`.
+ *
+ */
+
+use docueyes::quantize::{Quantization, QuantizationParams};
+use docueyes::store::EmbeddingStore;
+use std::env;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+fn main() {
+    let pages = env_usize("SEARCH_BENCH_PAGES", 50_000);
+    let dimension = env_usize("SEARCH_BENCH_DIMENSION", 384);
+    let query_count = env_usize("SEARCH_BENCH_QUERIES", 100);
+
+    let mut rng = Rng(42);
+    let rows: Vec<Vec<f32>> = (0..pages).map(|_| rng.vector(dimension)).collect();
+    let queries: Vec<Vec<f32>> = (0..query_count).map(|_| rng.vector(dimension)).collect();
+    println!("{} pages, {} dimensions, {} queries", pages, dimension, query_count);
+
+    let started = Instant::now();
+    for query in &queries {
+        let scores: Vec<f32> = rows.iter().map(|row| per_row_cosine(query, row)).collect();
+        black_box(scores);
+    }
+    let per_row = started.elapsed();
+    println!("per row cosine       {:>10.3} ms/query", per_query(per_row, query_count));
+
+    for mode in Quantization::ALL {
+        let params = QuantizationParams { mode, rescore: 0 };
+        let store = EmbeddingStore::quantized((0..pages as i64).collect(), rows.clone(), params)
+            .expect("valid synthetic store");
+        let started = Instant::now();
+        for query in &queries {
+            black_box(store.scores(query));
+        }
+        let matrix = started.elapsed();
+        println!(
+            "{:<12} matrix  {:>10.3} ms/query  {:.1}x",
+            mode.name(),
+            per_query(matrix, query_count),
+            per_row.as_secs_f64() / matrix.as_secs_f64().max(f64::EPSILON)
+        );
+    }
+}
+
+///
+/// The scoring every page went through before the store was a matrix, both norms recomputed per row.
+///
+fn per_row_cosine(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a: f32 = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b: f32 = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 { 0.0 } else { dot / (norm_a * norm_b) }
+}
+
+fn per_query(elapsed: Duration, queries: usize) -> f64 {
+    elapsed.as_secs_f64() * 1000.0 / queries.max(1) as f64
+}
+
+fn env_usize(name: &str, default: usize) -> usize {
+    env::var(name).ok().and_then(|value| value.parse().ok()).unwrap_or(default)
+}
+
+///
+/// Xorshift generator so every run benchmarks the same data.
+///
+struct Rng(u64);
+
+impl Rng {
+    fn uniform(&mut self) -> f32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 40) as f32 / (1u64 << 24) as f32 * 2.0 - 1.0
+    }
+
+    fn vector(&mut self, dimension: usize) -> Vec<f32> {
+        (0..dimension).map(|_| self.uniform()).collect()
+    }
+}
@@ -0,0 +1,647 @@
+/*
+ *
+ * Ann is an approximate nearest neighbor index over the embedding store, built as a
+ * Hierarchical Navigable Small World graph (Malkov & Yashunin, 2016).
+ *
+ * The graph only holds row numbers, the vectors themselves stay in the EmbeddingStore.
+ * The persisted layout is a header followed by every node's neighbor lists:
+ *
+ *   magic            [u8; 8]   b"DOCUHNSW"
+ *   version          u32
+ *   m, ef construction, ef search   u32 each
+ *   model id         u32 length followed by that many UTF-8 bytes
+ *   corpus hash      u64
+ *   passages hash    u64       FNV-1a over the text hash of every passage, in row order
+ *   fields           u8        the field strategy tag
+ *   node count       u64
+ *   entry point      u32       u32::MAX when the graph is empty
+ *   max level        u32
+ *   checksum         u64       FNV-1a over the node bytes
+ *   nodes            per node: u32 layer count, then per layer a u32 count followed by u32 neighbors
+ *
+ */
+
+use crate::cache::ByteReader;
+use crate::engine::PageScore;
+use crate::fields::FieldStrategy;
+use crate::hashed::fnv1a;
+use crate::quantize::PreparedQuery;
+use crate::store::EmbeddingStore;
+use anyhow::Result;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+
+pub const ANN_MAGIC: &[u8; 8] = b"DOCUHNSW";
+pub const ANN_VERSION: u32 = 2;
+
+// Levels are drawn from an exponential distribution, this only guards against a pathological draw
+const MAX_LEVEL: usize = 16;
+const NO_ENTRY_POINT: u32 = u32::MAX;
+
+///
+/// Tuning parameters of the HNSW graph.
+///
+/// # Fields
+/// * `m` - Neighbors kept per node on the upper layers, layer 0 keeps twice as many
+/// * `ef_construction` - Candidate list size while inserting, higher builds a better graph more slowly
+/// * `ef_search` - Candidate list size while searching, raised to `k` when a search asks for more
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HnswParams {
+    pub m: usize,
+    pub ef_construction: usize,
+    pub ef_search: usize,
+}
+
+impl Default for HnswParams {
+    fn default() -> Self {
+        HnswParams {
+            m: 16,
+            ef_construction: 200,
+            ef_search: 64,
+        }
+    }
+}
+
+///
+/// A node reached during a graph search, ordered by similarity to the query.
+///
+#[derive(Debug, Clone, Copy)]
+struct Candidate {
+    similarity: f32,
+    node: u32,
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate {}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.similarity
+            .total_cmp(&other.similarity)
+            .then_with(|| other.node.cmp(&self.node))
+    }
+}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+///
+/// What the indexed embeddings were generated from, recorded in the graph so it is never served over other embeddings.
+///
+/// # Fields
+/// * `model_id` - The model that produced the embeddings
+/// * `corpus_hash` - Hash of the corpus the embeddings belong to
+/// * `passages_hash` - Hash over the text hash of every embedded passage in row order, which changes with chunking and fields
+/// * `fields` - Which page fields the passages were embedded from
+///
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct GraphSource {
+    pub model_id: String,
+    pub corpus_hash: u64,
+    pub passages_hash: u64,
+    pub fields: FieldStrategy,
+}
+
+///
+/// HnswIndex is a layered proximity graph over the rows of an EmbeddingStore.
+///
+/// # Fields
+/// * `params` - The parameters the graph was built with
+/// * `source` - What the indexed embeddings were generated from
+/// * `entry_point` - The node on the top layer every search starts from
+/// * `max_level` - The top layer of the graph
+/// * `neighbors` - Neighbor lists, indexed by node then layer
+///
+#[derive(Debug, Clone)]
+pub struct HnswIndex {
+    params: HnswParams,
+    source: GraphSource,
+    entry_point: Option<u32>,
+    max_level: usize,
+    neighbors: Vec<Vec<Vec<u32>>>,
+}
+
+impl HnswIndex {
+    ///
+    /// Build a graph over every row of the store.
+    ///
+    /// # Arguments
+    /// * `store` - The embeddings to index.
+    /// * `params` - The graph parameters.
+    /// * `source` - What the embeddings were generated from, recorded to detect stale indexes.
+    ///
+    /// # Returns
+    /// * `HnswIndex` - The built graph. Builds are deterministic for the same store and parameters.
+    ///
+    pub fn build(store: &EmbeddingStore, params: HnswParams, source: GraphSource) -> Self {
+        let params = HnswParams {
+            m: params.m.max(2),
+            ef_construction: params.ef_construction.max(1),
+            ef_search: params.ef_search.max(1),
+        };
+        let mut index = HnswIndex {
+            params,
+            source,
+            entry_point: None,
+            max_level: 0,
+            neighbors: Vec::with_capacity(store.len()),
+        };
+        let mut rng = SplitMix64(0x5eed_d0c5);
+        let level_multiplier = 1.0 / (params.m as f64).ln();
+        for node in 0..store.len() {
+            let level = ((-(1.0 - rng.next_f64()).ln() * level_multiplier) as usize).min(MAX_LEVEL);
+            index.insert(store, node as u32, level);
+        }
+        index
+    }
+
+    ///
+    /// The parameters the graph was built with.
+    ///
+    pub fn params(&self) -> HnswParams {
+        self.params
+    }
+
+    ///
+    /// Whether the graph indexes exactly these embeddings, generated from this source.
+    ///
+    pub fn matches(&self, store: &EmbeddingStore, source: &GraphSource) -> bool {
+        self.neighbors.len() == store.len() && self.source == *source
+    }
+
+    ///
+    /// Find the approximate `k` nearest rows to a query.
+    ///
+    /// # Arguments
+    /// * `store` - The embeddings the graph was built over.
+    /// * `query` - The query embedding.
+    /// * `k` - The number of results to return.
+    ///
+    /// # Returns
+    /// * `Vec<PageScore>` - Up to `k` passages, most similar first.
+    ///
+    pub fn search(&self, store: &EmbeddingStore, query: &[f32], k: usize) -> Vec<PageScore> {
+        let Some(entry_point) = self.entry_point else {
+            return Vec::new();
+        };
+        if k == 0 {
+            return Vec::new();
+        }
+        let query = &store.prepare(query);
+
+        let mut entry = vec![candidate(store, query, entry_point)];
+        for layer in (1..=self.max_level).rev() {
+            entry = self.search_layer(store, query, &entry, 1, layer);
+        }
+        let found = self.search_layer(store, query, &entry, self.params.ef_search.max(k), 0);
+        found
+            .into_iter()
+            .take(k)
+            .map(|c| store.row_score(c.node as usize, c.similarity))
+            .collect()
+    }
+
+    ///
+    /// Insert a node, linking it into every layer up to its level.
+    ///
+    fn insert(&mut self, store: &EmbeddingStore, node: u32, level: usize) {
+        self.neighbors.push(vec![Vec::new(); level + 1]);
+        let Some(entry_point) = self.entry_point else {
+            self.entry_point = Some(node);
+            self.max_level = level;
+            return;
+        };
+
+        let query = &store.prepare(&store.vector(node as usize));
+        let mut entry = vec![candidate(store, query, entry_point)];
+        for layer in (level + 1..=self.max_level).rev() {
+            entry = self.search_layer(store, query, &entry, 1, layer);
+        }
+
+        for layer in (0..=level.min(self.max_level)).rev() {
+            let found = self.search_layer(store, query, &entry, self.params.ef_construction, layer);
+            let selected = select_neighbors(store, &found, self.params.m);
+            for &neighbor in &selected {
+                let max_neighbors = self.max_neighbors(layer);
+                let links = &mut self.neighbors[neighbor as usize][layer];
+                links.push(node);
+                if links.len() > max_neighbors {
+                    self.prune(store, neighbor, layer);
+                }
+            }
+            self.neighbors[node as usize][layer] = selected;
+            entry = found;
+        }
+
+        if level > self.max_level {
+            self.entry_point = Some(node);
+            self.max_level = level;
+        }
+    }
+
+    ///
+    /// Shrink a node's neighbor list on a layer back down to the layer's limit.
+    ///
+    fn prune(&mut self, store: &EmbeddingStore, node: u32, layer: usize) {
+        let base = &store.prepare(&store.vector(node as usize));
+        let mut candidates: Vec<Candidate> = self.neighbors[node as usize][layer]
+            .iter()
+            .map(|&neighbor| candidate(store, base, neighbor))
+            .collect();
+        candidates.sort_by(|a, b| b.cmp(a));
+        self.neighbors[node as usize][layer] = select_neighbors(store, &candidates, self.max_neighbors(layer));
+    }
+
+    fn max_neighbors(&self, layer: usize) -> usize {
+        if layer == 0 { self.params.m * 2 } else { self.params.m }
+    }
+
+    ///
+    /// Best first search of a single layer.
+    ///
+    /// # Returns
+    /// * `Vec<Candidate>` - Up to `ef` nodes, most similar first.
+    ///
+    fn search_layer(
+        &self,
+        store: &EmbeddingStore,
+        query: &PreparedQuery,
+        entry: &[Candidate],
+        ef: usize,
+        layer: usize,
+    ) -> Vec<Candidate> {
+        let mut visited: HashSet<u32> = entry.iter().map(|c| c.node).collect();
+        let mut candidates: BinaryHeap<Candidate> = entry.iter().copied().collect();
+        let mut results: BinaryHeap<Reverse<Candidate>> = entry.iter().copied().map(Reverse).collect();
+        while results.len() > ef {
+            results.pop();
+        }
+
+        while let Some(current) = candidates.pop() {
+            let worst = results.peek().map(|Reverse(c)| c.similarity).unwrap_or(f32::NEG_INFINITY);
+            if current.similarity < worst && results.len() >= ef {
+                break;
+            }
+            for &neighbor in &self.neighbors[current.node as usize][layer] {
+                if !visited.insert(neighbor) {
+                    continue;
+                }
+                let next = candidate(store, query, neighbor);
+                let worst = results.peek().map(|Reverse(c)| c.similarity).unwrap_or(f32::NEG_INFINITY);
+                if results.len() < ef || next.similarity > worst {
+                    candidates.push(next);
+                    results.push(Reverse(next));
+                    if results.len() > ef {
+                        results.pop();
+                    }
+                }
+            }
+        }
+
+        // Ascending order of Reverse is descending order of similarity
+        results.into_sorted_vec().into_iter().map(|Reverse(c)| c).collect()
+    }
+
+    ///
+    /// Write the graph to a file.
+    ///
+    /// # Arguments
+    /// * `path` - The path to the file.
+    ///
+    /// # Returns
+    /// * `Result<()>` - The result of the operation.
+    ///
+    pub fn save(&self, path: &str) -> Result<()> {
+        let mut body = Vec::new();
+        for layers in &self.neighbors {
+            body.extend_from_slice(&(layers.len() as u32).to_le_bytes());
+            for links in layers {
+                body.extend_from_slice(&(links.len() as u32).to_le_bytes());
+                for neighbor in links {
+                    body.extend_from_slice(&neighbor.to_le_bytes());
+                }
+            }
+        }
+
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(ANN_MAGIC)?;
+        writer.write_all(&ANN_VERSION.to_le_bytes())?;
+        for value in [self.params.m, self.params.ef_construction, self.params.ef_search] {
+            writer.write_all(&(value as u32).to_le_bytes())?;
+        }
+        writer.write_all(&(self.source.model_id.len() as u32).to_le_bytes())?;
+        writer.write_all(self.source.model_id.as_bytes())?;
+        writer.write_all(&self.source.corpus_hash.to_le_bytes())?;
+        writer.write_all(&self.source.passages_hash.to_le_bytes())?;
+        writer.write_all(&[self.source.fields.tag()])?;
+        writer.write_all(&(self.neighbors.len() as u64).to_le_bytes())?;
+        writer.write_all(&self.entry_point.unwrap_or(NO_ENTRY_POINT).to_le_bytes())?;
+        writer.write_all(&(self.max_level as u32).to_le_bytes())?;
+        writer.write_all(&fnv1a(&body).to_le_bytes())?;
+        writer.write_all(&body)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    ///
+    /// Read a graph from a file, validating its header, checksum and links.
+    ///
+    /// # Arguments
+    /// * `path` - The path to the file.
+    ///
+    /// # Returns
+    /// * `Result<HnswIndex>` - The graph.
+    ///
+    /// # Errors
+    /// * `Error` - If the file is not a graph, has an unsupported version, is truncated or corrupt.
+    ///
+    pub fn load(path: &str) -> Result<Self> {
+        let mut bytes = Vec::new();
+        BufReader::new(File::open(path)?).read_to_end(&mut bytes)?;
+        let mut reader = ByteReader { bytes: &bytes, path };
+
+        if reader.take(ANN_MAGIC.len())? != ANN_MAGIC {
+            return Err(anyhow::anyhow!("{} is not an ANN index", path));
+        }
+        let version = reader.u32()?;
+        if version != ANN_VERSION {
+            return Err(anyhow::anyhow!(
+                "{} has ANN index version {} but version {} is expected",
+                path,
+                version,
+                ANN_VERSION
+            ));
+        }
+        let params = HnswParams {
+            m: reader.u32()? as usize,
+            ef_construction: reader.u32()? as usize,
+            ef_search: reader.u32()? as usize,
+        };
+        let model_id_len = reader.u32()? as usize;
+        let model_id = String::from_utf8(reader.take(model_id_len)?.to_vec())?;
+        let corpus_hash = reader.u64()?;
+        let passages_hash = reader.u64()?;
+        let tag = reader.take(1)?[0];
+        let fields =
+            FieldStrategy::from_tag(tag).ok_or_else(|| anyhow::anyhow!("{} has unknown field strategy {}", path, tag))?;
+        let node_count = reader.u64()? as usize;
+        let entry_point = match reader.u32()? {
+            NO_ENTRY_POINT => None,
+            node => Some(node),
+        };
+        let max_level = reader.u32()? as usize;
+        let checksum = reader.u64()?;
+        if fnv1a(reader.bytes) != checksum {
+            return Err(anyhow::anyhow!("{} failed its checksum", path));
+        }
+
+        let mut neighbors = Vec::with_capacity(node_count.min(reader.bytes.len() / 4));
+        for _ in 0..node_count {
+            let layer_count = reader.u32()? as usize;
+            let mut layers = Vec::with_capacity(layer_count.min(MAX_LEVEL + 1));
+            for _ in 0..layer_count {
+                let link_count = reader.u32()? as usize;
+                let mut links = Vec::with_capacity(link_count.min(reader.bytes.len() / 4));
+                for _ in 0..link_count {
+                    links.push(reader.u32()?);
+                }
+                layers.push(links);
+            }
+            neighbors.push(layers);
+        }
+        if !reader.bytes.is_empty() {
+            return Err(anyhow::anyhow!("{} has trailing bytes after its last node", path));
+        }
+
+        // Every link must point at a node that exists on that layer, or searches would index out of bounds
+        let valid = neighbors.iter().all(|layers| {
+            layers.iter().enumerate().all(|(layer, links)| {
+                links
+                    .iter()
+                    .all(|&neighbor| neighbors.get(neighbor as usize).is_some_and(|n| n.len() > layer))
+            })
+        }) && match entry_point {
+            Some(node) => neighbors.get(node as usize).is_some_and(|n| n.len() == max_level + 1),
+            None => neighbors.is_empty(),
+        };
+        if !valid {
+            return Err(anyhow::anyhow!("{} holds links to nodes that do not exist", path));
+        }
+
+        Ok(HnswIndex {
+            params,
+            source: GraphSource {
+                model_id,
+                corpus_hash,
+                passages_hash,
+                fields,
+            },
+            entry_point,
+            max_level,
+            neighbors,
+        })
+    }
+}
+
+fn candidate(store: &EmbeddingStore, query: &PreparedQuery, node: u32) -> Candidate {
+    Candidate {
+        similarity: store.similarity(query, node as usize),
+        node,
+    }
+}
+
+///
+/// Pick up to `m` neighbors from candidates sorted most similar first, preferring ones that are not
+/// already covered by a closer selected neighbor so the graph keeps links in every direction.
+///
+fn select_neighbors(store: &EmbeddingStore, candidates: &[Candidate], m: usize) -> Vec<u32> {
+    let mut selected: Vec<u32> = Vec::with_capacity(m);
+    let mut skipped = Vec::new();
+    for c in candidates {
+        if selected.len() >= m {
+            break;
+        }
+        let vector = store.prepare(&store.vector(c.node as usize));
+        let diverse = selected
+            .iter()
+            .all(|&s| store.similarity(&vector, s as usize) < c.similarity);
+        if diverse {
+            selected.push(c.node);
+        } else {
+            skipped.push(c.node);
+        }
+    }
+    // Top up with the closest skipped candidates so sparse regions still get `m` links
+    for node in skipped {
+        if selected.len() >= m {
+            break;
+        }
+        selected.push(node);
+    }
+    selected
+}
+
+///
+/// Small deterministic generator for drawing node levels, so rebuilding an index gives the same graph.
+///
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    ///
+    /// Uniform draw from `[0, 1)`.
+    ///
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIMENSION: usize = 16;
+
+    fn random_vectors(count: usize, seed: u64) -> Vec<Vec<f32>> {
+        let mut rng = SplitMix64(seed);
+        (0..count)
+            .map(|_| (0..DIMENSION).map(|_| rng.next_f64() as f32 * 2.0 - 1.0).collect())
+            .collect()
+    }
+
+    fn store(count: usize) -> EmbeddingStore {
+        EmbeddingStore::from_rows((0..count as i64).collect(), random_vectors(count, 7)).unwrap()
+    }
+
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("docueyes-ann-{}-{}", std::process::id(), name))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    fn source(model_id: &str, corpus_hash: u64) -> GraphSource {
+        GraphSource {
+            model_id: model_id.to_string(),
+            corpus_hash,
+            ..GraphSource::default()
+        }
+    }
+
+    fn ids(scores: &[PageScore]) -> Vec<i64> {
+        scores.iter().map(|score| score.id).collect()
+    }
+
+    #[test]
+    fn recall_against_brute_force_is_high() {
+        let store = store(600);
+        let index = HnswIndex::build(&store, HnswParams::default(), source("test", 1));
+        let k = 10;
+        let mut found = 0;
+        let queries = random_vectors(30, 99);
+        for query in &queries {
+            let mut exact = store.scores(query);
+            exact.sort_by(|a, b| b.cmp(a));
+            let exact: HashSet<i64> = exact.iter().take(k).map(|score| score.id).collect();
+            let approximate = index.search(&store, query, k);
+            assert_eq!(approximate.len(), k);
+            assert!(approximate.windows(2).all(|pair| pair[0].similarity >= pair[1].similarity));
+            found += approximate.iter().filter(|score| exact.contains(&score.id)).count();
+        }
+        let recall = found as f32 / (queries.len() * k) as f32;
+        assert!(recall >= 0.95, "recall {}", recall);
+    }
+
+    #[test]
+    fn builds_are_deterministic_and_empty_graphs_find_nothing() {
+        let store = store(100);
+        let query = &random_vectors(1, 3)[0];
+        let first = HnswIndex::build(&store, HnswParams::default(), source("test", 1));
+        let second = HnswIndex::build(&store, HnswParams::default(), source("test", 1));
+        assert_eq!(ids(&first.search(&store, query, 5)), ids(&second.search(&store, query, 5)));
+        assert!(first.search(&store, query, 0).is_empty());
+
+        let empty_store = EmbeddingStore::from_rows(Vec::new(), Vec::new()).unwrap();
+        let empty = HnswIndex::build(&empty_store, HnswParams::default(), source("test", 1));
+        assert!(empty.search(&empty_store, query, 5).is_empty());
+    }
+
+    #[test]
+    fn saved_graphs_load_and_search_the_same() {
+        let path = temp_path("round-trip");
+        let store = store(200);
+        let built_from = GraphSource {
+            model_id: String::from("test"),
+            corpus_hash: 42,
+            passages_hash: 7,
+            fields: FieldStrategy::Template,
+        };
+        let index = HnswIndex::build(&store, HnswParams { m: 8, ef_construction: 50, ef_search: 20 }, built_from.clone());
+        index.save(&path).unwrap();
+        let loaded = HnswIndex::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        let loaded = loaded.unwrap();
+        assert_eq!(loaded.params(), index.params());
+        assert!(loaded.matches(&store, &built_from));
+        assert!(!loaded.matches(&store, &GraphSource { model_id: String::from("other"), ..built_from.clone() }));
+        assert!(!loaded.matches(&store, &GraphSource { corpus_hash: 43, ..built_from.clone() }));
+        // Rechunked or differently fielded passages make other embeddings even over an unchanged corpus
+        assert!(!loaded.matches(&store, &GraphSource { passages_hash: 8, ..built_from.clone() }));
+        assert!(!loaded.matches(&store, &GraphSource { fields: FieldStrategy::Body, ..built_from.clone() }));
+        for query in &random_vectors(5, 11) {
+            assert_eq!(ids(&loaded.search(&store, query, 10)), ids(&index.search(&store, query, 10)));
+        }
+    }
+
+    #[test]
+    fn corrupt_graphs_are_rejected() {
+        let path = temp_path("corrupt");
+        let store = store(50);
+        HnswIndex::build(&store, HnswParams::default(), source("test", 1)).save(&path).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        // magic, version, three params, model id, corpus and passages hashes, fields, node count, entry point, max level, checksum
+        let body_offset = 8 + 4 + 12 + 4 + "test".len() + 16 + 1 + 8 + 4 + 4 + 8;
+        let checksum_offset = body_offset - 8;
+
+        // A flipped body byte fails the checksum
+        let mut flipped = bytes.clone();
+        flipped[body_offset + 8] ^= 0xff;
+        std::fs::write(&path, &flipped).unwrap();
+        let checksum_error = HnswIndex::load(&path).unwrap_err().to_string();
+
+        // A link to a node past the end is caught even when the checksum is made to match.
+        // The body opens with node 0's layer count, its layer 0 link count and then its first link
+        let mut dangling = bytes.clone();
+        dangling[body_offset + 8..body_offset + 12].copy_from_slice(&9999u32.to_le_bytes());
+        let checksum = fnv1a(&dangling[body_offset..]);
+        dangling[checksum_offset..body_offset].copy_from_slice(&checksum.to_le_bytes());
+        std::fs::write(&path, &dangling).unwrap();
+        let link_error = HnswIndex::load(&path).unwrap_err().to_string();
+
+        std::fs::write(&path, &bytes[..bytes.len() - 2]).unwrap();
+        let truncated = HnswIndex::load(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(checksum_error.contains("checksum"), "{}", checksum_error);
+        assert!(link_error.contains("do not exist"), "{}", link_error);
+        assert!(truncated.is_err());
+    }
+}
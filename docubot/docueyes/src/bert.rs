@@ -0,0 +1,264 @@
+/*
+ *
+ * Bert is the rust-bert backed Embedder, wrapping the SentenceEmbeddingsModel.
+ *
+ */
+
+use crate::corpus::Embeddings;
+use crate::model::{BatchParams, Embedder};
+use anyhow::{Context, Result};
+use rust_bert::pipelines::sentence_embeddings::SentenceEmbeddingsModel;
+use rust_bert::pipelines::sentence_embeddings::{
+    SentenceEmbeddingsBuilder, SentenceEmbeddingsModelType,
+};
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+// Words fed to the tokenizer to find where it truncates, well past the limit of any supported model
+const TOKEN_LIMIT_PROBE_WORDS: usize = 4096;
+
+///
+/// The sentence embedding models that can be selected from configuration.
+///
+/// Mirrors rust-bert's `SentenceEmbeddingsModelType`, adding the names used in configuration and embedding caches.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmbeddingModelType {
+    DistiluseBaseMultilingualCased,
+    BertBaseNliMeanTokens,
+    AllMiniLmL12V2,
+    AllMiniLmL6V2,
+    AllDistilrobertaV1,
+    ParaphraseAlbertSmallV2,
+    SentenceT5Base,
+}
+
+impl EmbeddingModelType {
+    pub const ALL: [EmbeddingModelType; 7] = [
+        EmbeddingModelType::DistiluseBaseMultilingualCased,
+        EmbeddingModelType::BertBaseNliMeanTokens,
+        EmbeddingModelType::AllMiniLmL12V2,
+        EmbeddingModelType::AllMiniLmL6V2,
+        EmbeddingModelType::AllDistilrobertaV1,
+        EmbeddingModelType::ParaphraseAlbertSmallV2,
+        EmbeddingModelType::SentenceT5Base,
+    ];
+
+    ///
+    /// The configuration name of the model, matching rust-bert's cache directory naming.
+    ///
+    pub fn name(&self) -> &'static str {
+        match self {
+            EmbeddingModelType::DistiluseBaseMultilingualCased => "distiluse-base-multilingual-cased",
+            EmbeddingModelType::BertBaseNliMeanTokens => "bert-base-nli-mean-tokens",
+            EmbeddingModelType::AllMiniLmL12V2 => "all-mini-lm-l12-v2",
+            EmbeddingModelType::AllMiniLmL6V2 => "all-mini-lm-l6-v2",
+            EmbeddingModelType::AllDistilrobertaV1 => "all-distilroberta-v1",
+            EmbeddingModelType::ParaphraseAlbertSmallV2 => "paraphrase-albert-small-v2",
+            EmbeddingModelType::SentenceT5Base => "sentence-t5-base",
+        }
+    }
+
+    fn rust_bert_type(&self) -> SentenceEmbeddingsModelType {
+        match self {
+            EmbeddingModelType::DistiluseBaseMultilingualCased => {
+                SentenceEmbeddingsModelType::DistiluseBaseMultilingualCased
+            }
+            EmbeddingModelType::BertBaseNliMeanTokens => SentenceEmbeddingsModelType::BertBaseNliMeanTokens,
+            EmbeddingModelType::AllMiniLmL12V2 => SentenceEmbeddingsModelType::AllMiniLmL12V2,
+            EmbeddingModelType::AllMiniLmL6V2 => SentenceEmbeddingsModelType::AllMiniLmL6V2,
+            EmbeddingModelType::AllDistilrobertaV1 => SentenceEmbeddingsModelType::AllDistilrobertaV1,
+            EmbeddingModelType::ParaphraseAlbertSmallV2 => SentenceEmbeddingsModelType::ParaphraseAlbertSmallV2,
+            EmbeddingModelType::SentenceT5Base => SentenceEmbeddingsModelType::SentenceT5Base,
+        }
+    }
+}
+
+impl fmt::Display for EmbeddingModelType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for EmbeddingModelType {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        EmbeddingModelType::ALL
+            .into_iter()
+            .find(|model_type| model_type.name().eq_ignore_ascii_case(name))
+            .ok_or_else(|| {
+                let known: Vec<&str> = EmbeddingModelType::ALL.iter().map(|m| m.name()).collect();
+                anyhow::anyhow!("Unknown embedding model {}, expected one of {}", name, known.join(", "))
+            })
+    }
+}
+
+///
+/// Where the model files come from.
+///
+/// # Variants
+/// * `Remote` - Download from Hugging Face, optionally pinning the directory files are cached in
+/// * `Local` - Load from a directory holding an exported sentence-transformers model
+///
+#[derive(Debug, Clone)]
+pub enum ModelSource {
+    Remote { cache_dir: Option<PathBuf> },
+    Local(PathBuf),
+}
+
+///
+/// Configuration used to construct a BertEmbedder.
+///
+/// # Fields
+/// * `model_type` - Which sentence embedding model to use, a `Local` source must hold this model
+/// * `source` - Where the model files come from
+/// * `offline` - When set the network is never touched, so only a `Local` source is accepted
+/// * `batch` - How corpus texts are batched when they are encoded
+/// * `instances` - How many copies of the model encode batches in parallel, each costs its full memory
+/// * `intra_op_threads` - CPU threads libtorch uses inside every operation, left to libtorch when unset
+///
+#[derive(Debug, Clone)]
+pub struct ModelConfig {
+    pub model_type: EmbeddingModelType,
+    pub source: ModelSource,
+    pub offline: bool,
+    pub batch: BatchParams,
+    pub instances: usize,
+    pub intra_op_threads: Option<usize>,
+}
+
+impl Default for ModelConfig {
+    fn default() -> Self {
+        ModelConfig {
+            model_type: EmbeddingModelType::AllMiniLmL12V2,
+            source: ModelSource::Remote { cache_dir: None },
+            offline: false,
+            batch: BatchParams::default(),
+            instances: 1,
+            intra_op_threads: None,
+        }
+    }
+}
+
+///
+/// Set how many CPU threads libtorch uses inside every operation.
+///
+/// The setting is process wide and shared by every model instance, so with a pool of instances it is usually
+/// best set to the available cores divided by the pool size.
+///
+/// # Arguments
+/// * `threads` - The number of threads, at least 1.
+///
+pub fn set_intra_op_threads(threads: usize) {
+    tch::set_num_threads(threads.max(1) as i32);
+}
+
+///
+/// This is a nice wrapper around the SentenceEmbeddingsModel from rust_bert.
+///
+///
+/// The token limit and special token count are measured once on load, rust-bert does not expose them.
+///
+pub struct BertEmbedder {
+    model: SentenceEmbeddingsModel,
+    model_id: String,
+    dimension: usize,
+    max_tokens: usize,
+    special_tokens: usize,
+}
+
+impl BertEmbedder {
+    ///
+    /// Create a new instance of the BertEmbedder struct using the configured model.
+    ///
+    /// # Arguments
+    /// * `config` - Which model to load, where from and whether the network may be used.
+    ///
+    /// # Returns
+    /// A Result containing a new instance of the BertEmbedder struct.
+    ///
+    /// # Errors
+    /// * `Error` - If offline mode is requested without a local model, or the model fails to load.
+    ///
+    pub fn new(config: &ModelConfig) -> Result<Self> {
+        let model = match &config.source {
+            ModelSource::Local(model_dir) => {
+                if !model_dir.is_dir() {
+                    return Err(anyhow::anyhow!(
+                        "Model directory {} does not exist",
+                        model_dir.display()
+                    ));
+                }
+                SentenceEmbeddingsBuilder::local(model_dir)
+                    .create_model()
+                    .with_context(|| format!("Failed to load model from {}", model_dir.display()))?
+            }
+            ModelSource::Remote { cache_dir } => {
+                if config.offline {
+                    return Err(anyhow::anyhow!(
+                        "Offline mode requires a local model directory, refusing to fetch the model remotely"
+                    ));
+                }
+                if let Some(cache_dir) = cache_dir {
+                    // rust-bert reads its cache root from this variable the first time a remote resource is resolved
+                    // SAFETY: models are built during startup before any worker threads read the environment
+                    unsafe { std::env::set_var("RUSTBERT_CACHE", cache_dir) };
+                }
+                SentenceEmbeddingsBuilder::remote(config.model_type.rust_bert_type())
+                    .create_model()
+                    .with_context(|| format!("Failed to fetch remote model {}", config.model_type))?
+            }
+        };
+        let dimension = model
+            .get_embedding_dim()
+            .context("Failed to read model embedding dimension")? as usize;
+        // Tokenizing for the model truncates to its maximum sequence length, special tokens included.
+        // Every text comes back as a one dimensional tensor of token ids
+        let tokenized_len = |text: &str| {
+            model
+                .tokenize(&[text])
+                .tokens_ids
+                .first()
+                .map_or(0, |ids| ids.size().first().map_or(0, |&len| len as usize))
+        };
+        let special_tokens = tokenized_len("");
+        let max_tokens = tokenized_len(&"a ".repeat(TOKEN_LIMIT_PROBE_WORDS));
+        Ok(BertEmbedder {
+            model,
+            model_id: config.model_type.name().to_string(),
+            dimension,
+            max_tokens,
+            special_tokens,
+        })
+    }
+}
+
+impl Embedder for BertEmbedder {
+    fn encode(&self, texts: &[&str]) -> Result<Vec<Embeddings>> {
+        Ok(self.model.encode(texts)?)
+    }
+
+    fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    fn model_id(&self) -> &str {
+        &self.model_id
+    }
+
+    fn max_tokens(&self) -> Option<usize> {
+        Some(self.max_tokens)
+    }
+
+    fn count_tokens(&self, texts: &[&str]) -> Result<Vec<usize>> {
+        Ok(self
+            .model
+            .get_tokenizer()
+            .tokenize_list(texts)
+            .iter()
+            .map(|tokens| tokens.len() + self.special_tokens)
+            .collect())
+    }
+}
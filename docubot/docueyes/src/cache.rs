@@ -0,0 +1,613 @@
+/*
+ *
+ * Cache handles reading and writing page embeddings to disk.
+ *
+ * The primary format is a compact binary index, a validated header followed by the contiguous little endian rows:
+ *
+ *   magic         [u8; 8]   b"DOCUEMB\0"
+ *   version       u32
+ *   model id      u32 length followed by that many UTF-8 bytes
+ *   dimension     u32
+ *   quantization  u8        0 f32, 1 f16, 2 int8 per dimension, 3 int8 per vector
+ *   full          u8        1 when the rows are stored as f32 whatever the quantization, so they can be rescored
+ *   fields        u8        0 body, 1 name templated into every passage, 2 body passages followed by a name row
+ *   row count     u64       one row per passage, a page that is not chunked is a single passage
+ *   corpus hash   u64       FNV-1a over the embedded corpus content
+ *   checksum      u64       FNV-1a over the text hash and row bytes
+ *   padding       zeros up to the next 64 byte boundary, so the body can be memory-mapped in place
+ *   text hashes   row count * u64, FNV-1a of the text each row was embedded from
+ *   rows          row count * dimension * f32 when full, otherwise the quantized rows with their scales first
+ *
+ * Full precision rows are written unit normalized. Version 2 files predate quantization and are read as full f32 rows,
+ * version 3 files predate the padding and version 4 files predate the fields byte, all three embedded the body alone.
+ * Versions 4 and up can be mapped.
+ *
+ * JSON import and export is kept so older `embeddings.txt` files still load.
+ *
+ */
+
+use crate::corpus::Embeddings;
+use crate::fields::FieldStrategy;
+use crate::hashed::fnv1a;
+use crate::mapped::map_file;
+use crate::quantize::{Matrix, Quantization};
+use crate::store::normalize;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+
+pub const CACHE_MAGIC: &[u8; 8] = b"DOCUEMB\0";
+pub const CACHE_VERSION: u32 = 5;
+// The last layout without the quantization fields, still accepted on read
+const UNQUANTIZED_CACHE_VERSION: u32 = 2;
+// The first layout padding the body out to BODY_ALIGNMENT, so the rows can be mapped
+const ALIGNED_CACHE_VERSION: u32 = 4;
+// The body starts on this boundary so the rows can be viewed in place through a memory map
+const BODY_ALIGNMENT: usize = 64;
+
+///
+/// Embeddings along with everything needed to tell whether they are still valid.
+///
+/// # Fields
+/// * `model_id` - Identifier of the model that generated the embeddings
+/// * `dimension` - Number of components in every embedding
+/// * `corpus_hash` - Hash of the corpus content the embeddings were generated from
+/// * `text_hashes` - Hash of the text behind each embedding, empty for files that predate them
+/// * `quantization` - How the embeddings were stored by the engine that wrote them
+/// * `full_precision` - Whether `embeddings` are the f32 originals rather than dequantized approximations
+/// * `fields` - Which page fields the embeddings were generated from
+/// * `embeddings` - The passage embeddings in corpus order
+///
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmbeddingCache {
+    pub model_id: String,
+    pub dimension: usize,
+    #[serde(default)]
+    pub corpus_hash: u64,
+    #[serde(default)]
+    pub text_hashes: Vec<u64>,
+    #[serde(default)]
+    pub quantization: Quantization,
+    #[serde(default = "full_precision_default")]
+    pub full_precision: bool,
+    #[serde(default)]
+    pub fields: FieldStrategy,
+    pub embeddings: Vec<Embeddings>,
+}
+
+fn full_precision_default() -> bool {
+    true
+}
+
+///
+/// The JSON layouts accepted on import, the bare array predates any model metadata.
+///
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum JsonCache {
+    Tagged(EmbeddingCache),
+    Bare(Vec<Embeddings>),
+}
+
+impl EmbeddingCache {
+    ///
+    /// Write the cache in the binary index format, quantizing the rows unless they are full precision.
+    ///
+    /// The index is written to a temporary file and renamed over `path`, so processes that have the old
+    /// index mapped keep reading it undisturbed.
+    ///
+    /// # Arguments
+    /// * `path` - The path to the file.
+    ///
+    /// # Returns
+    /// * `Result<()>` - The result of the operation.
+    ///
+    pub fn write_binary(&self, path: &str) -> Result<()> {
+        if self.text_hashes.len() != self.embeddings.len() {
+            return Err(anyhow::anyhow!(
+                "Refusing to cache {} embeddings with {} text hashes",
+                self.embeddings.len(),
+                self.text_hashes.len()
+            ));
+        }
+        let mut body = Vec::with_capacity(self.embeddings.len() * (self.dimension * 4 + 8));
+        for text_hash in &self.text_hashes {
+            body.extend_from_slice(&text_hash.to_le_bytes());
+        }
+        let full = self.full_precision || self.quantization == Quantization::F32;
+        let mut values = Vec::with_capacity(self.embeddings.len() * self.dimension);
+        for embedding in &self.embeddings {
+            if embedding.len() != self.dimension {
+                return Err(anyhow::anyhow!(
+                    "Refusing to cache an embedding of dimension {} in a {} dimension index",
+                    embedding.len(),
+                    self.dimension
+                ));
+            }
+            let start = values.len();
+            values.extend_from_slice(embedding);
+            // Dequantized rows are left as they are so they quantize back to the same codes
+            if full {
+                normalize(&mut values[start..]);
+            }
+        }
+        let row_mode = if full { Quantization::F32 } else { self.quantization };
+        Matrix::quantize(row_mode, values, self.dimension).write(&mut body);
+
+        let mut header = Vec::new();
+        header.extend_from_slice(CACHE_MAGIC);
+        header.extend_from_slice(&CACHE_VERSION.to_le_bytes());
+        header.extend_from_slice(&(self.model_id.len() as u32).to_le_bytes());
+        header.extend_from_slice(self.model_id.as_bytes());
+        header.extend_from_slice(&(self.dimension as u32).to_le_bytes());
+        header.extend_from_slice(&[self.quantization.tag(), full as u8, self.fields.tag()]);
+        header.extend_from_slice(&(self.embeddings.len() as u64).to_le_bytes());
+        header.extend_from_slice(&self.corpus_hash.to_le_bytes());
+        header.extend_from_slice(&fnv1a(&body).to_le_bytes());
+        header.resize(header.len().next_multiple_of(BODY_ALIGNMENT), 0);
+
+        let temporary = format!("{}.tmp", path);
+        let mut writer = BufWriter::new(File::create(&temporary)?);
+        writer.write_all(&header)?;
+        writer.write_all(&body)?;
+        writer.flush()?;
+        drop(writer);
+        fs::rename(&temporary, path)?;
+        Ok(())
+    }
+
+    ///
+    /// Read a cache in the binary index format, validating the header and checksum before use.
+    ///
+    /// # Arguments
+    /// * `path` - The path to the file.
+    ///
+    /// # Returns
+    /// * `Result<EmbeddingCache>` - The validated cache.
+    ///
+    /// # Errors
+    /// * `Error` - If the file is not an index, has an unsupported version, is truncated or fails its checksum.
+    ///
+    pub fn read_binary(path: &str) -> Result<Self> {
+        let mut bytes = Vec::new();
+        BufReader::new(File::open(path)?).read_to_end(&mut bytes)?;
+        let header = Header::read(&bytes, path)?;
+        let body = &bytes[header.body_offset..];
+        if fnv1a(body) != header.checksum {
+            return Err(anyhow::anyhow!("{} failed its checksum", path));
+        }
+
+        let rows = &body[header.page_count * 8..];
+        let matrix = Matrix::read(header.row_mode(), rows, header.dimension, header.page_count);
+        let embeddings = (0..header.page_count)
+            .map(|row| matrix.row(row, header.dimension).into_owned())
+            .collect();
+
+        Ok(EmbeddingCache {
+            text_hashes: text_hashes(&body[..header.page_count * 8]),
+            model_id: header.model_id,
+            dimension: header.dimension,
+            corpus_hash: header.corpus_hash,
+            quantization: header.quantization,
+            full_precision: header.full,
+            fields: header.fields,
+            embeddings,
+        })
+    }
+    ///
+    /// Write the cache as JSON, for tools that cannot read the binary index.
+    ///
+    /// # Arguments
+    /// * `path` - The path to the file.
+    ///
+    /// # Returns
+    /// * `Result<()>` - The result of the operation.
+    ///
+    pub fn write_json(&self, path: &str) -> Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer(&mut writer, self)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    ///
+    /// Read a JSON cache, including the bare array layout written before caches carried metadata.
+    ///
+    /// # Arguments
+    /// * `path` - The path to the file.
+    /// * `model_id` - The model to attribute a bare array to, since it does not record one.
+    ///
+    /// # Returns
+    /// * `Result<EmbeddingCache>` - The cache, with a zero corpus hash when none was recorded.
+    ///
+    pub fn read_json(path: &str, model_id: &str) -> Result<Self> {
+        let reader = BufReader::new(File::open(path)?);
+        match serde_json::from_reader(reader)? {
+            JsonCache::Tagged(cache) => Ok(cache),
+            JsonCache::Bare(embeddings) => Ok(EmbeddingCache {
+                model_id: model_id.to_string(),
+                dimension: embeddings.first().map(|e| e.len()).unwrap_or(0),
+                corpus_hash: 0,
+                text_hashes: Vec::new(),
+                quantization: Quantization::F32,
+                full_precision: true,
+                fields: FieldStrategy::Body,
+                embeddings,
+            }),
+        }
+    }
+}
+
+///
+/// A binary index viewed in place through a memory map rather than read into memory.
+///
+/// # Fields
+/// * `model_id` - Identifier of the model that generated the embeddings
+/// * `dimension` - Number of components in every embedding
+/// * `corpus_hash` - Hash of the corpus content the embeddings were generated from
+/// * `text_hashes` - Hash of the text behind each row
+/// * `fields` - Which page fields the rows were generated from
+/// * `rows` - The rows as stored, f32 when the index kept full precision and quantized otherwise
+///
+pub(crate) struct MappedCache {
+    pub(crate) model_id: String,
+    pub(crate) dimension: usize,
+    pub(crate) corpus_hash: u64,
+    pub(crate) text_hashes: Vec<u64>,
+    pub(crate) fields: FieldStrategy,
+    pub(crate) rows: Matrix,
+}
+
+impl MappedCache {
+    ///
+    /// Map a binary index read-only, reading only its header and text hashes.
+    ///
+    /// The checksum is only verified on request, as that reads every row and gives up most of the point of mapping.
+    /// The sizes are always validated, so a truncated index is rejected.
+    ///
+    /// # Arguments
+    /// * `path` - The path to the file.
+    /// * `verify` - Whether to verify the checksum.
+    ///
+    /// # Returns
+    /// * `Result<MappedCache>` - The mapped index.
+    ///
+    /// # Errors
+    /// * `Error` - If the file is not an index, is of a version without aligned rows, is truncated or fails a requested checksum.
+    ///
+    pub(crate) fn open(path: &str, verify: bool) -> Result<Self> {
+        let map = map_file(path)?;
+        let header = Header::read(&map, path)?;
+        if header.version < ALIGNED_CACHE_VERSION {
+            return Err(anyhow::anyhow!(
+                "{} has index format version {} which cannot be mapped",
+                path,
+                header.version
+            ));
+        }
+        if verify && fnv1a(&map[header.body_offset..]) != header.checksum {
+            return Err(anyhow::anyhow!("{} failed its checksum", path));
+        }
+        let rows_offset = header.body_offset + header.page_count * 8;
+        let text_hashes = text_hashes(&map[header.body_offset..rows_offset]);
+        let rows = Matrix::mapped(header.row_mode(), &map, rows_offset, header.dimension, header.page_count)?;
+        Ok(MappedCache {
+            model_id: header.model_id,
+            dimension: header.dimension,
+            corpus_hash: header.corpus_hash,
+            text_hashes,
+            fields: header.fields,
+            rows,
+        })
+    }
+}
+
+///
+/// The validated header of a binary index.
+///
+/// # Fields
+/// * `version` - Layout version of the index
+/// * `model_id` - Identifier of the model that generated the embeddings
+/// * `dimension` - Number of components in every embedding
+/// * `quantization` - How the writing engine stored the embeddings
+/// * `full` - Whether the rows are stored as f32
+/// * `fields` - Which page fields the rows were generated from
+/// * `page_count` - Number of rows
+/// * `corpus_hash` - Hash of the corpus content the embeddings were generated from
+/// * `checksum` - FNV-1a over the body
+/// * `body_offset` - Where the text hashes start, in bytes
+///
+struct Header {
+    version: u32,
+    model_id: String,
+    dimension: usize,
+    quantization: Quantization,
+    full: bool,
+    fields: FieldStrategy,
+    page_count: usize,
+    corpus_hash: u64,
+    checksum: u64,
+    body_offset: usize,
+}
+
+impl Header {
+    ///
+    /// Parse the header of an index and check the body is exactly as long as the header declares.
+    ///
+    fn read(bytes: &[u8], path: &str) -> Result<Self> {
+        let mut reader = ByteReader { bytes, path };
+
+        if reader.take(CACHE_MAGIC.len())? != CACHE_MAGIC {
+            return Err(anyhow::anyhow!("{} is not an embeddings index", path));
+        }
+        let version = reader.u32()?;
+        if !(UNQUANTIZED_CACHE_VERSION..=CACHE_VERSION).contains(&version) {
+            return Err(anyhow::anyhow!(
+                "{} has index format version {} but version {} is expected",
+                path,
+                version,
+                CACHE_VERSION
+            ));
+        }
+        let model_id_len = reader.u32()? as usize;
+        let model_id = String::from_utf8(reader.take(model_id_len)?.to_vec())?;
+        let dimension = reader.u32()? as usize;
+        let (quantization, full) = if version == UNQUANTIZED_CACHE_VERSION {
+            (Quantization::F32, true)
+        } else {
+            let fields = reader.take(2)?;
+            let quantization = Quantization::from_tag(fields[0])
+                .ok_or_else(|| anyhow::anyhow!("{} has unknown quantization {}", path, fields[0]))?;
+            (quantization, fields[1] != 0)
+        };
+        let fields = if version > ALIGNED_CACHE_VERSION {
+            let tag = reader.take(1)?[0];
+            FieldStrategy::from_tag(tag).ok_or_else(|| anyhow::anyhow!("{} has unknown field strategy {}", path, tag))?
+        } else {
+            FieldStrategy::Body
+        };
+        let page_count = reader.u64()? as usize;
+        let corpus_hash = reader.u64()?;
+        let checksum = reader.u64()?;
+        let mut body_offset = bytes.len() - reader.bytes.len();
+        if version >= ALIGNED_CACHE_VERSION {
+            body_offset = body_offset.next_multiple_of(BODY_ALIGNMENT);
+        }
+
+        let header = Header {
+            version,
+            model_id,
+            dimension,
+            quantization,
+            full,
+            fields,
+            page_count,
+            corpus_hash,
+            checksum,
+            body_offset,
+        };
+        let expected_len = header
+            .row_mode()
+            .encoded_len(dimension, page_count)
+            .and_then(|rows_len| page_count.checked_mul(8)?.checked_add(rows_len))
+            .ok_or_else(|| anyhow::anyhow!("{} declares an impossible index size", path))?;
+        let body_len = bytes.len().saturating_sub(body_offset);
+        if bytes.len() < body_offset || body_len != expected_len {
+            return Err(anyhow::anyhow!(
+                "{} holds {} bytes of embeddings but its header declares {}",
+                path,
+                body_len,
+                expected_len
+            ));
+        }
+        Ok(header)
+    }
+
+    ///
+    /// How the rows themselves are encoded, f32 whenever full precision was kept.
+    ///
+    fn row_mode(&self) -> Quantization {
+        if self.full { Quantization::F32 } else { self.quantization }
+    }
+}
+
+fn text_hashes(bytes: &[u8]) -> Vec<u64> {
+    bytes
+        .chunks_exact(8)
+        .map(|hash| {
+            let mut buf = [0u8; 8];
+            buf.copy_from_slice(hash);
+            u64::from_le_bytes(buf)
+        })
+        .collect()
+}
+
+///
+/// Small cursor over the index bytes that turns short reads into errors naming the file.
+///
+pub(crate) struct ByteReader<'a> {
+    pub(crate) bytes: &'a [u8],
+    pub(crate) path: &'a str,
+}
+
+impl<'a> ByteReader<'a> {
+    pub(crate) fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.bytes.len() < len {
+            return Err(anyhow::anyhow!("{} is truncated", self.path));
+        }
+        let (head, tail) = self.bytes.split_at(len);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32> {
+        let mut buf = [0u8; 4];
+        buf.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64> {
+        let mut buf = [0u8; 8];
+        buf.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(buf))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("docueyes-cache-{}-{}", std::process::id(), name))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    fn cache(quantization: Quantization, full_precision: bool) -> EmbeddingCache {
+        EmbeddingCache {
+            model_id: "hashed-bow-3".to_string(),
+            dimension: 3,
+            corpus_hash: 42,
+            text_hashes: vec![7, 8],
+            quantization,
+            full_precision,
+            fields: FieldStrategy::Body,
+            embeddings: vec![vec![3.0, 0.0, 4.0], vec![0.0, 1.0, 0.0]],
+        }
+    }
+
+    // An index in one of the older layouts, holding the unit rows [1, 0] and [0, 1]
+    fn legacy_index(version: u32) -> Vec<u8> {
+        let mut body = Vec::new();
+        for text_hash in [7u64, 8] {
+            body.extend_from_slice(&text_hash.to_le_bytes());
+        }
+        for value in [1.0f32, 0.0, 0.0, 1.0] {
+            body.extend_from_slice(&value.to_le_bytes());
+        }
+        let mut index = Vec::new();
+        index.extend_from_slice(CACHE_MAGIC);
+        index.extend_from_slice(&version.to_le_bytes());
+        index.extend_from_slice(&4u32.to_le_bytes());
+        index.extend_from_slice(b"test");
+        index.extend_from_slice(&2u32.to_le_bytes());
+        if version > UNQUANTIZED_CACHE_VERSION {
+            index.extend_from_slice(&[Quantization::F32.tag(), 1]);
+        }
+        index.extend_from_slice(&2u64.to_le_bytes());
+        index.extend_from_slice(&42u64.to_le_bytes());
+        index.extend_from_slice(&fnv1a(&body).to_le_bytes());
+        if version >= ALIGNED_CACHE_VERSION {
+            index.resize(index.len().next_multiple_of(BODY_ALIGNMENT), 0);
+        }
+        index.extend_from_slice(&body);
+        index
+    }
+
+    #[test]
+    fn binary_round_trip_keeps_the_metadata_and_normalizes_the_rows() {
+        let path = temp_path("round-trip");
+        cache(Quantization::F32, true).write_binary(&path).unwrap();
+        let read = EmbeddingCache::read_binary(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(read.model_id, "hashed-bow-3");
+        assert_eq!((read.dimension, read.corpus_hash), (3, 42));
+        assert_eq!(read.text_hashes, vec![7, 8]);
+        assert_eq!(read.fields, FieldStrategy::Body);
+        assert!(read.full_precision);
+        assert_eq!(read.embeddings, vec![vec![0.6, 0.0, 0.8], vec![0.0, 1.0, 0.0]]);
+    }
+
+    #[test]
+    fn quantized_round_trip_keeps_the_quantization() {
+        let path = temp_path("quantized");
+        let mut written = cache(Quantization::F16, false);
+        written.embeddings = vec![vec![0.6, 0.0, 0.8], vec![0.0, 1.0, 0.0]];
+        written.write_binary(&path).unwrap();
+        let read = EmbeddingCache::read_binary(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(read.quantization, Quantization::F16);
+        assert!(!read.full_precision);
+        for (read, written) in read.embeddings.iter().flatten().zip(written.embeddings.iter().flatten()) {
+            assert!((read - written).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn truncated_indexes_are_rejected() {
+        let path = temp_path("truncated");
+        cache(Quantization::F32, true).write_binary(&path).unwrap();
+        let bytes = fs::read(&path).unwrap();
+        fs::write(&path, &bytes[..bytes.len() - 4]).unwrap();
+        let error = EmbeddingCache::read_binary(&path).unwrap_err().to_string();
+        fs::write(&path, &bytes[..20]).unwrap();
+        let header_error = EmbeddingCache::read_binary(&path).unwrap_err().to_string();
+        fs::remove_file(&path).unwrap();
+        assert!(error.contains("header declares"), "{}", error);
+        assert!(header_error.contains("truncated"), "{}", header_error);
+    }
+
+    #[test]
+    fn checksum_mismatches_are_rejected() {
+        let path = temp_path("checksum");
+        cache(Quantization::F32, true).write_binary(&path).unwrap();
+        let mut bytes = fs::read(&path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        fs::write(&path, &bytes).unwrap();
+        let error = EmbeddingCache::read_binary(&path).unwrap_err().to_string();
+        fs::remove_file(&path).unwrap();
+        assert!(error.contains("checksum"), "{}", error);
+    }
+
+    #[test]
+    fn older_index_versions_still_read() {
+        for version in UNQUANTIZED_CACHE_VERSION..CACHE_VERSION {
+            let path = temp_path(&format!("version-{}", version));
+            fs::write(&path, legacy_index(version)).unwrap();
+            let read = EmbeddingCache::read_binary(&path);
+            fs::remove_file(&path).unwrap();
+            let read = read.unwrap_or_else(|e| panic!("version {}: {}", version, e));
+            assert_eq!(read.model_id, "test");
+            assert_eq!(read.text_hashes, vec![7, 8]);
+            assert_eq!((read.quantization, read.fields), (Quantization::F32, FieldStrategy::Body));
+            assert_eq!(read.embeddings, vec![vec![1.0, 0.0], vec![0.0, 1.0]]);
+        }
+    }
+
+    #[test]
+    fn unknown_versions_are_rejected() {
+        let path = temp_path("unknown-version");
+        fs::write(&path, legacy_index(CACHE_VERSION + 1)).unwrap();
+        let read = EmbeddingCache::read_binary(&path);
+        fs::remove_file(&path).unwrap();
+        assert!(read.unwrap_err().to_string().contains("version"));
+    }
+
+    #[test]
+    fn bare_json_arrays_read_as_hashless_body_embeddings() {
+        let path = temp_path("bare.json");
+        fs::write(&path, "[[0.6, 0.8], [1.0, 0.0]]").unwrap();
+        let read = EmbeddingCache::read_json(&path, "legacy-model");
+        fs::remove_file(&path).unwrap();
+        let read = read.unwrap();
+        assert_eq!(read.model_id, "legacy-model");
+        assert_eq!((read.dimension, read.corpus_hash), (2, 0));
+        assert!(read.text_hashes.is_empty());
+        assert_eq!(read.embeddings, vec![vec![0.6, 0.8], vec![1.0, 0.0]]);
+    }
+
+    #[test]
+    fn tagged_json_round_trips() {
+        let path = temp_path("tagged.json");
+        cache(Quantization::F32, true).write_json(&path).unwrap();
+        let read = EmbeddingCache::read_json(&path, "ignored");
+        fs::remove_file(&path).unwrap();
+        let read = read.unwrap();
+        assert_eq!(read.model_id, "hashed-bow-3");
+        assert_eq!(read.text_hashes, vec![7, 8]);
+        assert_eq!(read.embeddings, cache(Quantization::F32, true).embeddings);
+    }
+}
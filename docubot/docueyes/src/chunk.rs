@@ -0,0 +1,422 @@
+/*
+ *
+ * Chunk splits page bodies into overlapping passages, so long pages are embedded whole instead of being
+ * silently truncated at the model's maximum token length, and folds passage scores back into page scores.
+ *
+ * Tokens are approximated by whitespace separated words. Sentence pieces run longer than words, so the
+ * budget should leave headroom under the model limit.
+ *
+ */
+
+use crate::engine::PageScore;
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::iter;
+use std::ops::Range;
+use std::str::FromStr;
+
+///
+/// Where passage boundaries fall.
+///
+/// # Variants
+/// * `Whole` - The whole body is one passage, as embedded before chunking
+/// * `Sentence` - Consecutive sentences are packed into passages up to the token budget
+/// * `Paragraph` - Consecutive paragraphs, separated by blank lines, are packed up to the token budget
+/// * `Tokens` - Fixed windows of the token budget, ignoring the text structure
+///
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkStrategy {
+    #[default]
+    Whole,
+    Sentence,
+    Paragraph,
+    Tokens,
+}
+
+impl ChunkStrategy {
+    pub const ALL: [ChunkStrategy; 4] = [
+        ChunkStrategy::Whole,
+        ChunkStrategy::Sentence,
+        ChunkStrategy::Paragraph,
+        ChunkStrategy::Tokens,
+    ];
+
+    ///
+    /// The name used to select the strategy in configuration.
+    ///
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChunkStrategy::Whole => "whole",
+            ChunkStrategy::Sentence => "sentence",
+            ChunkStrategy::Paragraph => "paragraph",
+            ChunkStrategy::Tokens => "tokens",
+        }
+    }
+}
+
+impl fmt::Display for ChunkStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for ChunkStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        ChunkStrategy::ALL
+            .into_iter()
+            .find(|strategy| strategy.name().eq_ignore_ascii_case(name))
+            .ok_or_else(|| {
+                let known: Vec<&str> = ChunkStrategy::ALL.iter().map(|strategy| strategy.name()).collect();
+                anyhow::anyhow!("Unknown chunking strategy {}, expected one of {}", name, known.join(", "))
+            })
+    }
+}
+
+///
+/// How page bodies are split into passages.
+///
+/// # Fields
+/// * `strategy` - Where passage boundaries fall
+/// * `max_tokens` - The most tokens a passage holds, a sentence or paragraph longer than this is split by tokens
+/// * `overlap` - Tokens at the end of a passage repeated at the start of the next, so no match straddles a boundary
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkParams {
+    pub strategy: ChunkStrategy,
+    pub max_tokens: usize,
+    pub overlap: usize,
+}
+
+impl Default for ChunkParams {
+    fn default() -> Self {
+        ChunkParams {
+            strategy: ChunkStrategy::Whole,
+            max_tokens: 128,
+            overlap: 16,
+        }
+    }
+}
+
+///
+/// How the scores of a page's passages combine into the page score.
+///
+/// # Variants
+/// * `Max` - The score of the best passage
+/// * `MeanTopN` - The mean score of the best `n` passages, favouring pages that match throughout
+///
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Aggregation {
+    #[default]
+    Max,
+    MeanTopN(usize),
+}
+
+impl fmt::Display for Aggregation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Aggregation::Max => f.write_str("max"),
+            Aggregation::MeanTopN(n) => write!(f, "mean:{}", n),
+        }
+    }
+}
+
+impl FromStr for Aggregation {
+    type Err = anyhow::Error;
+
+    ///
+    /// Parse `max`, or `mean:<n>` for the mean of the best `n` passages.
+    ///
+    fn from_str(name: &str) -> Result<Self> {
+        if name.eq_ignore_ascii_case("max") {
+            return Ok(Aggregation::Max);
+        }
+        match name.split_once(':') {
+            Some((mean, n)) if mean.eq_ignore_ascii_case("mean") => match n.parse() {
+                Ok(n) if n > 0 => Ok(Aggregation::MeanTopN(n)),
+                _ => Err(anyhow::anyhow!("Aggregation {} needs a passage count above 0", name)),
+            },
+            _ => Err(anyhow::anyhow!("Unknown aggregation {}, expected max or mean:<n>", name)),
+        }
+    }
+}
+
+///
+/// The passage of a page that matched a query.
+///
+/// # Fields
+/// * `index` - Position of the passage within its page, counting from 0
+/// * `start` - Character offset of the passage start within `Page.body`
+/// * `end` - Character offset just past the passage end within `Page.body`
+/// * `text` - The passage text
+///
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Passage {
+    pub index: usize,
+    pub start: usize,
+    pub end: usize,
+    pub text: String,
+}
+
+///
+/// Split a text into passages.
+///
+/// Every text yields at least one passage, an empty or blank text yields one empty passage, so every page keeps a row.
+///
+/// # Arguments
+/// * `text` - The text to split.
+/// * `params` - The strategy, token budget and overlap.
+///
+/// # Returns
+/// * `Vec<Range<usize>>` - Byte ranges of the passages within `text`, in order.
+///
+pub fn split(text: &str, params: &ChunkParams) -> Vec<Range<usize>> {
+    let max_tokens = params.max_tokens.max(1);
+    let units = match params.strategy {
+        ChunkStrategy::Whole => return iter::once(0..text.len()).collect(),
+        ChunkStrategy::Sentence => sentences(text),
+        ChunkStrategy::Paragraph => paragraphs(text),
+        ChunkStrategy::Tokens => words(text, 0..text.len()),
+    };
+    // Units over the budget are cut into token windows, so packing never has to emit an oversized passage
+    let units: Vec<(Range<usize>, usize)> = units
+        .into_iter()
+        .flat_map(|unit| {
+            let unit_words = words(text, unit.clone());
+            if unit_words.len() <= max_tokens {
+                vec![(unit, unit_words.len())]
+            } else {
+                unit_words
+                    .chunks(max_tokens)
+                    .map(|window| (window[0].start..window[window.len() - 1].end, window.len()))
+                    .collect()
+            }
+        })
+        .collect();
+    if units.is_empty() {
+        return iter::once(0..0).collect();
+    }
+
+    let mut passages = Vec::new();
+    let mut start = 0;
+    loop {
+        let mut end = start;
+        let mut tokens = 0;
+        while end < units.len() && (end == start || tokens + units[end].1 <= max_tokens) {
+            tokens += units[end].1;
+            end += 1;
+        }
+        passages.push(units[start].0.start..units[end - 1].0.end);
+        if end == units.len() {
+            return passages;
+        }
+        // Step back over trailing units that fit the overlap, always moving forward at least one unit
+        let mut next = end;
+        let mut carried = 0;
+        while next > start + 1 && carried + units[next - 1].1 <= params.overlap {
+            carried += units[next - 1].1;
+            next -= 1;
+        }
+        start = next;
+    }
+}
+
+///
+/// Fold passage scores into one score per page.
+///
+/// # Arguments
+/// * `scores` - Passage scores, any number per page, in any order.
+/// * `aggregation` - How a page's passage scores combine.
+///
+/// # Returns
+/// * `Vec<PageScore>` - One score per page, carrying its best passage, in the order pages first appear.
+///
+pub fn aggregate(scores: Vec<PageScore>, aggregation: Aggregation) -> Vec<PageScore> {
+    let mut pages: Vec<Vec<PageScore>> = Vec::new();
+    let mut page_index: HashMap<i64, usize> = HashMap::new();
+    for score in scores {
+        let index = *page_index.entry(score.id).or_insert_with(|| {
+            pages.push(Vec::new());
+            pages.len() - 1
+        });
+        pages[index].push(score);
+    }
+
+    pages
+        .into_iter()
+        .filter_map(|mut passages| {
+            passages.sort_by(|a, b| b.cmp(a));
+            let best = *passages.first()?;
+            let similarity = match aggregation {
+                Aggregation::Max => best.similarity,
+                Aggregation::MeanTopN(n) => {
+                    let top = &passages[..n.clamp(1, passages.len())];
+                    top.iter().map(|score| score.similarity).sum::<f32>() / top.len() as f32
+                }
+            };
+            Some(PageScore { similarity, ..best })
+        })
+        .collect()
+}
+
+///
+/// Sentences end at `.`, `!` or `?` followed by whitespace, and at blank lines.
+///
+fn sentences(text: &str) -> Vec<Range<usize>> {
+    let mut ranges = Vec::new();
+    let mut start = 0;
+    let mut chars = text.char_indices().peekable();
+    while let Some((index, c)) = chars.next() {
+        let next = chars.peek().map(|&(_, next)| next);
+        let ends = match c {
+            '.' | '!' | '?' => next.is_none_or(char::is_whitespace),
+            '\n' => next == Some('\n'),
+            _ => false,
+        };
+        if ends {
+            ranges.push(start..index + c.len_utf8());
+            start = index + c.len_utf8();
+        }
+    }
+    ranges.push(start..text.len());
+    trimmed(text, ranges)
+}
+
+///
+/// Paragraphs are separated by lines holding nothing but whitespace.
+///
+fn paragraphs(text: &str) -> Vec<Range<usize>> {
+    let mut ranges = Vec::new();
+    let mut start = 0;
+    let mut offset = 0;
+    for line in text.split_inclusive('\n') {
+        if line.trim().is_empty() {
+            ranges.push(start..offset);
+            start = offset + line.len();
+        }
+        offset += line.len();
+    }
+    ranges.push(start..text.len());
+    trimmed(text, ranges)
+}
+
+///
+/// The whitespace separated words within a range of the text.
+///
+fn words(text: &str, range: Range<usize>) -> Vec<Range<usize>> {
+    let mut ranges = Vec::new();
+    let mut start = None;
+    for (index, c) in text[range.clone()].char_indices() {
+        match (c.is_whitespace(), start) {
+            (false, None) => start = Some(range.start + index),
+            (true, Some(word)) => {
+                ranges.push(word..range.start + index);
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(word) = start {
+        ranges.push(word..range.end);
+    }
+    ranges
+}
+
+///
+/// Trim surrounding whitespace off every range, dropping those left empty.
+///
+fn trimmed(text: &str, ranges: Vec<Range<usize>>) -> Vec<Range<usize>> {
+    ranges
+        .into_iter()
+        .filter_map(|range| {
+            let slice = &text[range.clone()];
+            let leading = slice.len() - slice.trim_start().len();
+            let trailing = slice.len() - slice.trim_end().len();
+            (leading < slice.len()).then(|| range.start + leading..range.end - trailing)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn passages(text: &str, strategy: ChunkStrategy, max_tokens: usize, overlap: usize) -> Vec<&str> {
+        let params = ChunkParams {
+            strategy,
+            max_tokens,
+            overlap,
+        };
+        split(text, &params).into_iter().map(|range| &text[range]).collect()
+    }
+
+    #[test]
+    fn whole_keeps_the_body_as_one_passage() {
+        assert_eq!(passages("One. Two.", ChunkStrategy::Whole, 1, 0), vec!["One. Two."]);
+    }
+
+    #[test]
+    fn sentences_pack_up_to_the_budget_with_overlap() {
+        let text = "Alpha beta. Gamma delta. Epsilon zeta.";
+        assert_eq!(
+            passages(text, ChunkStrategy::Sentence, 4, 0),
+            vec!["Alpha beta. Gamma delta.", "Epsilon zeta."]
+        );
+        assert_eq!(
+            passages(text, ChunkStrategy::Sentence, 4, 2),
+            vec!["Alpha beta. Gamma delta.", "Gamma delta. Epsilon zeta."]
+        );
+    }
+
+    #[test]
+    fn paragraphs_split_on_blank_lines() {
+        let text = "First line\nstill first.\n\n  \nSecond one.";
+        assert_eq!(
+            passages(text, ChunkStrategy::Paragraph, 4, 0),
+            vec!["First line\nstill first.", "Second one."]
+        );
+    }
+
+    #[test]
+    fn token_windows_step_by_budget_less_overlap() {
+        assert_eq!(
+            passages("a b c d e f g", ChunkStrategy::Tokens, 3, 1),
+            vec!["a b c", "c d e", "e f g"]
+        );
+    }
+
+    #[test]
+    fn oversized_sentences_are_cut_into_windows() {
+        assert_eq!(
+            passages("a b c d e. f.", ChunkStrategy::Sentence, 2, 0),
+            vec!["a b", "c d", "e. f."]
+        );
+    }
+
+    #[test]
+    fn blank_text_still_yields_a_passage() {
+        assert_eq!(passages("  \n ", ChunkStrategy::Sentence, 8, 0), vec![""]);
+        assert_eq!(passages("", ChunkStrategy::Tokens, 8, 0), vec![""]);
+    }
+
+    #[test]
+    fn aggregation_folds_passages_into_pages() {
+        let score = |id, passage, similarity| PageScore {
+            id,
+            similarity,
+            passage: Some(passage),
+        };
+        let scores = vec![score(1, 0, 0.2), score(2, 0, 0.5), score(1, 1, 0.8), score(1, 2, 0.6)];
+
+        let max = aggregate(scores.clone(), Aggregation::Max);
+        assert_eq!(max.iter().map(|s| (s.id, s.passage)).collect::<Vec<_>>(), vec![(1, Some(1)), (2, Some(0))]);
+        assert_eq!(max[0].similarity, 0.8);
+
+        let mean = aggregate(scores, Aggregation::MeanTopN(2));
+        assert!((mean[0].similarity - 0.7).abs() < 1e-6);
+        assert_eq!(mean[1].similarity, 0.5);
+    }
+}
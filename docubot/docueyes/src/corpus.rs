@@ -4,8 +4,11 @@
  *
  */
 
+use crate::hashed::fnv1a;
 use anyhow::Result;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
 use std::fs;
 
 // Custom type for embeddings instead of an ungly Vec<Vec<f32>>
@@ -25,21 +28,80 @@ pub struct Corpus {
 ///
 /// Create a new instance of the Page struct.
 ///
+/// Only `id`, `name`, `body` and `link` are required, every metadata field may be left out of the corpus.
+///
+/// # Fields
+/// * `id` - Unique id of the page
+/// * `name` - Title of the page
+/// * `body` - Text of the page, the part that gets embedded
+/// * `link` - Where the page lives
+/// * `tags` - Free form labels
+/// * `section` - Breadcrumb path of the sections holding the page, outermost first
+/// * `version` - Product version the page documents
+/// * `language` - Language of the page, as a tag like `en` or `pt-BR`
+/// * `created` - When the page was first published, RFC 3339
+/// * `updated` - When the page last changed, RFC 3339
+/// * `attributes` - Any other key-value metadata, values of any JSON type
+///
 /// # Returns
 /// A Result containing a new instance of the Page struct.
 ///
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
 pub struct Page {
     pub id: i64,
     pub name: String,
     pub body: String,
     pub link: String,
-    #[serde(default = "default_similarity")]
-    pub similarity: f32,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub section: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub updated: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub attributes: BTreeMap<String, serde_json::Value>,
+}
+
+impl Corpus {
+    ///
+    /// Hash the content that gets embedded, so cached embeddings can be matched to the corpus they came from.
+    ///
+    /// # Returns
+    /// A 64 bit FNV-1a hash over every page id and text hash, in corpus order.
+    ///
+    pub fn content_hash(&self) -> u64 {
+        let mut bytes = Vec::new();
+        for page in &self.pages {
+            bytes.extend_from_slice(&page.id.to_le_bytes());
+            bytes.extend_from_slice(&page.text_hash().to_le_bytes());
+        }
+        fnv1a(&bytes)
+    }
 }
 
-fn default_similarity() -> f32 {
-    0.0
+impl Page {
+    ///
+    /// The text that gets embedded for this page.
+    ///
+    pub fn embedding_text(&self) -> &str {
+        &self.body
+    }
+
+    ///
+    /// Hash the embedded text, so a cached embedding can be reused for as long as the text is unchanged.
+    ///
+    /// # Returns
+    /// A 64 bit FNV-1a hash of `embedding_text`.
+    ///
+    pub fn text_hash(&self) -> u64 {
+        fnv1a(self.embedding_text().as_bytes())
+    }
 }
 
 ///
@@ -48,11 +110,83 @@ fn default_similarity() -> f32 {
 /// # Returns
 /// A Result containing a new instance of the Codex struct.
 ///
+/// # Errors
+/// If the file cannot be read, holds no pages or two pages share an id.
+///
 pub fn load_corpus(path: &str) -> Result<Corpus> {
     let json = fs::read_to_string(path)?;
     let corpus: Corpus = serde_json::from_str(&json)?;
     if corpus.pages.is_empty() {
         return Err(anyhow::anyhow!("No pages found in the corpus"));
     }
+    let mut seen = HashSet::new();
+    for page in &corpus.pages {
+        if !seen.insert(page.id) {
+            return Err(anyhow::anyhow!("Duplicate page id {} found in the corpus", page.id));
+        }
+    }
     Ok(corpus)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_corpus(name: &str, json: &str) -> String {
+        let path = std::env::temp_dir()
+            .join(format!("docueyes-corpus-{}-{}", std::process::id(), name))
+            .to_string_lossy()
+            .into_owned();
+        fs::write(&path, json).unwrap();
+        path
+    }
+
+    fn load(name: &str, json: &str) -> Result<Corpus> {
+        let path = temp_corpus(name, json);
+        let corpus = load_corpus(&path);
+        fs::remove_file(&path).unwrap();
+        corpus
+    }
+
+    #[test]
+    fn pages_without_metadata_load() {
+        let corpus = load("bare", r#"{"pages": [{"id": 1, "name": "CRM", "body": "What CRM means", "link": "/crm"}]}"#).unwrap();
+        let page = &corpus.pages[0];
+        assert_eq!((page.id, page.name.as_str(), page.link.as_str()), (1, "CRM", "/crm"));
+        assert!(page.tags.is_empty() && page.section.is_empty() && page.attributes.is_empty());
+        assert_eq!((page.version.as_ref(), page.created), (None, None));
+        // Absent metadata stays absent when the page is written back out
+        assert_eq!(
+            serde_json::to_value(page).unwrap(),
+            serde_json::json!({"id": 1, "name": "CRM", "body": "What CRM means", "link": "/crm"})
+        );
+    }
+
+    #[test]
+    fn timestamps_parse_as_rfc_3339() {
+        let corpus = load(
+            "dated",
+            r#"{"pages": [{"id": 1, "name": "", "body": "", "link": "",
+                "created": "2024-03-01T12:30:00Z", "updated": "2024-03-02T08:00:00+02:00"}]}"#,
+        )
+        .unwrap();
+        let page = &corpus.pages[0];
+        assert_eq!(page.created.unwrap().to_rfc3339(), "2024-03-01T12:30:00+00:00");
+        // Offsets are converted to UTC
+        assert_eq!(page.updated.unwrap().to_rfc3339(), "2024-03-02T06:00:00+00:00");
+    }
+
+    #[test]
+    fn invalid_timestamps_are_rejected() {
+        let json = r#"{"pages": [{"id": 1, "name": "", "body": "", "link": "", "created": "March 1st 2024"}]}"#;
+        assert!(load("undated", json).is_err());
+    }
+
+    #[test]
+    fn empty_corpora_and_duplicate_ids_are_rejected() {
+        assert!(load("empty", r#"{"pages": []}"#).is_err());
+        let page = r#"{"id": 4, "name": "", "body": "", "link": ""}"#;
+        let error = load("duplicate", &format!(r#"{{"pages": [{}, {}]}}"#, page, page)).unwrap_err();
+        assert!(error.to_string().contains("Duplicate page id 4"), "{}", error);
+    }
+}
@@ -0,0 +1,160 @@
+/*
+ *
+ * Cross encoder is the rust-bert backed CrossEncoder, a BERT sequence classifier that reads the query and a passage
+ * as one pair and outputs how relevant the passage is.
+ *
+ */
+
+use crate::rerank::CrossEncoder;
+use anyhow::{Context, Result};
+use rust_bert::Config;
+use rust_bert::bert::{BertConfig, BertForSequenceClassification};
+use rust_tokenizers::tokenizer::{BertTokenizer, Tokenizer, TruncationStrategy};
+use rust_tokenizers::vocab::Vocab;
+use std::iter;
+use std::path::{Path, PathBuf};
+use tch::{Device, Kind, Tensor, nn};
+
+const CONFIG_FILE: &str = "config.json";
+const VOCAB_FILE: &str = "vocab.txt";
+const WEIGHTS_FILE: &str = "rust_model.ot";
+
+///
+/// Configuration used to construct a BertCrossEncoder.
+///
+/// # Fields
+/// * `model_dir` - Directory holding the exported model: `config.json`, `vocab.txt` and `rust_model.ot`
+/// * `lower_case` - Whether the vocabulary is uncased, true for the common MS MARCO MiniLM rerankers
+/// * `max_tokens` - The most tokens read from one query and passage pair, the passage is cut first when over
+///
+#[derive(Debug, Clone)]
+pub struct CrossEncoderConfig {
+    pub model_dir: PathBuf,
+    pub lower_case: bool,
+    pub max_tokens: usize,
+}
+
+impl CrossEncoderConfig {
+    pub fn new<P: Into<PathBuf>>(model_dir: P) -> Self {
+        CrossEncoderConfig {
+            model_dir: model_dir.into(),
+            lower_case: true,
+            max_tokens: 512,
+        }
+    }
+}
+
+///
+/// A BERT cross-encoder loaded from a local directory, it never touches the network.
+///
+pub struct BertCrossEncoder {
+    model: BertForSequenceClassification,
+    tokenizer: BertTokenizer,
+    // Owns the weights the model reads from
+    _var_store: nn::VarStore,
+    device: Device,
+    model_id: String,
+    max_tokens: usize,
+    pad_id: i64,
+}
+
+impl BertCrossEncoder {
+    ///
+    /// Load a cross-encoder from a local model directory.
+    ///
+    /// # Arguments
+    /// * `config` - Where the model lives and how its inputs are tokenized.
+    ///
+    /// # Returns
+    /// A Result containing a new instance of the BertCrossEncoder struct.
+    ///
+    /// # Errors
+    /// * `Error` - If a model file is missing or the model fails to load.
+    ///
+    pub fn new(config: &CrossEncoderConfig) -> Result<Self> {
+        let model_dir = &config.model_dir;
+        let file = |name: &str| -> Result<PathBuf> {
+            let path = model_dir.join(name);
+            if !path.is_file() {
+                return Err(anyhow::anyhow!("Cross-encoder file {} does not exist", path.display()));
+            }
+            Ok(path)
+        };
+        let bert_config = BertConfig::from_file(file(CONFIG_FILE)?);
+        let tokenizer = BertTokenizer::from_file(file(VOCAB_FILE)?, config.lower_case, config.lower_case)
+            .with_context(|| format!("Failed to load cross-encoder vocabulary from {}", model_dir.display()))?;
+
+        let device = Device::cuda_if_available();
+        let mut var_store = nn::VarStore::new(device);
+        let model = BertForSequenceClassification::new(var_store.root(), &bert_config)
+            .with_context(|| format!("Failed to build cross-encoder from {}", model_dir.display()))?;
+        var_store
+            .load(file(WEIGHTS_FILE)?)
+            .with_context(|| format!("Failed to load cross-encoder weights from {}", model_dir.display()))?;
+
+        let pad_id = tokenizer.vocab().token_to_id(tokenizer.vocab().get_pad_value());
+        Ok(BertCrossEncoder {
+            model,
+            tokenizer,
+            _var_store: var_store,
+            device,
+            model_id: model_id(model_dir),
+            max_tokens: config.max_tokens,
+            pad_id,
+        })
+    }
+}
+
+impl CrossEncoder for BertCrossEncoder {
+    fn score(&self, query: &str, texts: &[&str]) -> Result<Vec<f32>> {
+        if texts.is_empty() {
+            return Ok(Vec::new());
+        }
+        let pairs: Vec<(&str, &str)> = texts.iter().map(|text| (query, *text)).collect();
+        let inputs = self
+            .tokenizer
+            .encode_pair_list(&pairs, self.max_tokens, &TruncationStrategy::LongestFirst, 0);
+
+        // Pad every pair to the longest, the mask hides the padding from attention
+        let len = inputs.iter().map(|input| input.token_ids.len()).max().unwrap_or(0);
+        let mut token_ids = Vec::with_capacity(inputs.len() * len);
+        let mut segment_ids = Vec::with_capacity(inputs.len() * len);
+        let mut mask = Vec::with_capacity(inputs.len() * len);
+        for input in &inputs {
+            let padding = len - input.token_ids.len();
+            token_ids.extend(input.token_ids.iter().copied().chain(iter::repeat_n(self.pad_id, padding)));
+            segment_ids.extend(input.segment_ids.iter().map(|&segment| segment as i64).chain(iter::repeat_n(0, padding)));
+            mask.extend(iter::repeat_n(1i64, input.token_ids.len()).chain(iter::repeat_n(0, padding)));
+        }
+        let shape = (inputs.len() as i64, len as i64);
+        let tensor = |values: &[i64]| Tensor::from_slice(values).view(shape).to_device(self.device);
+        let (token_ids, segment_ids, mask) = (tensor(&token_ids), tensor(&segment_ids), tensor(&mask));
+
+        let logits = tch::no_grad(|| {
+            self.model
+                .forward_t(Some(&token_ids), Some(&mask), Some(&segment_ids), None, None, false)
+                .logits
+        });
+        // Rerankers either emit one relevance logit or a pair of irrelevant and relevant logits
+        let relevance = match logits.size().last() {
+            Some(1) => logits.select(1, 0).sigmoid(),
+            _ => logits.softmax(-1, Kind::Float).select(1, 1),
+        };
+        let relevance = relevance.to_kind(Kind::Float).to_device(Device::Cpu);
+        Vec::<f32>::try_from(&relevance).context("Failed to read cross-encoder scores")
+    }
+
+    fn model_id(&self) -> &str {
+        &self.model_id
+    }
+}
+
+///
+/// Name a model after its directory.
+///
+fn model_id(model_dir: &Path) -> String {
+    model_dir
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| model_dir.display().to_string())
+}
@@ -4,28 +4,264 @@
  *
  */
 
+#[cfg(feature = "bert")]
+use crate::bert::ModelConfig;
+use crate::ann::{GraphSource, HnswIndex, HnswParams};
+use crate::cache::{EmbeddingCache, MappedCache};
+use crate::chunk::{Aggregation, ChunkParams, Passage, aggregate, split};
 use crate::corpus::Corpus;
 use crate::corpus::Embeddings;
 use crate::corpus::Page;
+use crate::fields::{DEFAULT_NAME_WEIGHT, FieldStrategy, TEMPLATE_SEPARATOR, blend};
+use crate::hashed::fnv1a;
+use crate::hybrid::{Fusion, HybridScore, fuse};
+use crate::lexical::{Bm25Index, Bm25Params};
+use crate::mmr::{MmrParams, mmr};
 use crate::model::EmbeddingInput;
+use crate::model::EmbeddingProgress;
 use crate::model::Model;
+use crate::quantize::QuantizationParams;
+use crate::query_cache::{QueryCache, QueryCacheParams, QueryCacheStats, normalize_query};
+use crate::rerank::{CrossEncoder, RerankParams};
+use crate::store::{EmbeddingStore, dot, normalize};
 use anyhow::Result;
-use std::fs::File;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::ops::Range;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 ///
 /// ResolveLevel enum defines the level/degree of resolution for similarity calculations.
 ///
+/// Every level works on the pages that clear the temperature, ranked most similar first.
+///
 /// # Variants
-/// * `First` - First element
-/// * `Mid` - Middle element
-/// * `Last` - Last element
-/// * `To` - For first element advance towards last element `n` steps
+/// * `First` - First element, the single best match
+/// * `Mid` - Middle element, a window centred on the median of the ranked results
+/// * `Last` - Last element, the window of weakest results that still clear the temperature
+/// * `To` - For first element advance towards last element `n` steps, then take a window
 ///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ResolveLevel {
     First,
     Mid,
     Last,
-    To,
+    To(usize),
+}
+
+impl ResolveLevel {
+    ///
+    /// Work out which slice of a ranked result list this level selects.
+    ///
+    /// # Arguments
+    /// * `ranked_len` - The number of ranked results available.
+    /// * `window_size` - The maximum number of results to select, ignored by `First`.
+    ///
+    /// # Returns
+    /// * `Range<usize>` - The selected positions within the ranked results.
+    ///
+    pub fn window(&self, ranked_len: usize, window_size: usize) -> Range<usize> {
+        let window_size = window_size.min(ranked_len);
+        match self {
+            ResolveLevel::First => 0..ranked_len.min(1),
+            ResolveLevel::Mid => {
+                let start = (ranked_len / 2)
+                    .saturating_sub(window_size / 2)
+                    .min(ranked_len - window_size);
+                start..start + window_size
+            }
+            ResolveLevel::Last => ranked_len - window_size..ranked_len,
+            ResolveLevel::To(steps) => {
+                let start = (*steps).min(ranked_len);
+                start..(start + window_size).min(ranked_len)
+            }
+        }
+    }
+}
+
+///
+/// Similarity of a single page to a query, addressed by the page id it was computed for.
+///
+/// # Fields
+/// * `id` - The `Page.id` the embedding belongs to
+/// * `similarity` - Cosine similarity between the page and the query
+/// * `passage` - Position of the passage the score came from within the page, `None` for lexical scores
+///
+#[derive(Debug, Clone, Copy)]
+pub struct PageScore {
+    pub id: i64,
+    pub similarity: f32,
+    pub passage: Option<usize>,
+}
+
+impl PartialEq for PageScore {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for PageScore {}
+
+impl Ord for PageScore {
+    ///
+    /// Orders by similarity, with the lower page id ranking higher on ties so results are deterministic.
+    ///
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.similarity
+            .total_cmp(&other.similarity)
+            .then_with(|| other.id.cmp(&self.id))
+    }
+}
+
+impl PartialOrd for PageScore {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+///
+/// Select the `k` best scores using a heap bounded to `k` entries, so large corpora are never fully sorted.
+///
+/// # Arguments
+/// * `scores` - The scores to select from.
+/// * `k` - The maximum number of scores to keep.
+///
+/// # Returns
+/// * `Vec<PageScore>` - The best scores, most similar first.
+///
+pub fn top_k<I: IntoIterator<Item = PageScore>>(scores: I, k: usize) -> Vec<PageScore> {
+    if k == 0 {
+        return Vec::new();
+    }
+    let mut heap = BinaryHeap::with_capacity(k + 1);
+    for score in scores {
+        heap.push(Reverse(score));
+        if heap.len() > k {
+            heap.pop();
+        }
+    }
+    // Ascending order of Reverse is descending order of the scores
+    heap.into_sorted_vec().into_iter().map(|Reverse(score)| score).collect()
+}
+
+///
+/// A page returned by a search, kept apart from the corpus `Page` so scores never leak into the corpus schema.
+///
+/// # Fields
+/// * `rank` - Position of the page in the whole ranking counting from 1, not its position in the returned window
+/// * `page` - The page, serialized inline
+/// * `similarity` - The score the page was resolved by
+/// * `passage` - The passage the page matched on, when it was scored by passage
+///
+#[derive(Debug, Clone, Serialize)]
+pub struct SearchResult {
+    pub rank: usize,
+    #[serde(flatten)]
+    pub page: Page,
+    pub similarity: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub passage: Option<Passage>,
+}
+
+///
+/// Summary of how a cached set of embeddings was reconciled with the current corpus.
+///
+/// # Fields
+/// * `reused` - Passages whose cached embedding still matched their text
+/// * `recomputed` - Passages that were added or changed and had to be embedded again
+/// * `removed` - Cached embeddings that no longer belong to any passage
+/// * `requantized` - Whether the cache was stored in another quantization than the engine uses
+/// * `fields_changed` - Whether the cache embedded other page fields than the engine does
+/// * `mapping_error` - Why the index was read into memory rather than mapped, if it was
+///
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RefreshSummary {
+    pub reused: usize,
+    pub recomputed: usize,
+    pub removed: usize,
+    pub requantized: bool,
+    pub fields_changed: bool,
+    pub mapping_error: Option<String>,
+}
+
+impl RefreshSummary {
+    ///
+    /// Whether the cache on disk no longer matches the engine and should be rewritten.
+    ///
+    pub fn is_stale(&self) -> bool {
+        self.recomputed > 0 || self.removed > 0 || self.requantized || self.fields_changed
+    }
+}
+
+///
+/// Summary of a full embedding build.
+///
+/// # Fields
+/// * `pages` - Pages embedded
+/// * `passages` - Passages the pages were split into, each embedded on its own
+/// * `elapsed` - Time spent embedding them
+/// * `truncated` - Passages longer than the model reads, whose ends were left out of their embeddings
+///
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct BuildSummary {
+    pub pages: usize,
+    pub passages: usize,
+    pub elapsed: Duration,
+    pub truncated: Vec<TruncatedPassage>,
+}
+
+impl BuildSummary {
+    ///
+    /// Embedding throughput of the build.
+    ///
+    pub fn pages_per_second(&self) -> f64 {
+        let seconds = self.elapsed.as_secs_f64();
+        if seconds > 0.0 { self.pages as f64 / seconds } else { 0.0 }
+    }
+}
+
+///
+/// A passage the model truncates, a page embedded whole is a single passage.
+///
+/// # Fields
+/// * `id` - The `Page.id` the passage belongs to
+/// * `passage` - Position of the passage within the page
+/// * `tokens` - Tokens in the passage, special tokens included
+/// * `excess` - Tokens past the model limit, which never reach the embedding
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TruncatedPassage {
+    pub id: i64,
+    pub passage: usize,
+    pub tokens: usize,
+    pub excess: usize,
+}
+
+///
+/// Which passages of the corpus are longer than the model reads.
+///
+/// # Fields
+/// * `max_tokens` - The model limit, `None` when the model reads texts of any length
+/// * `passages` - Passages checked
+/// * `truncated` - The passages over the limit, most tokens over first
+///
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct TruncationReport {
+    pub max_tokens: Option<usize>,
+    pub passages: usize,
+    pub truncated: Vec<TruncatedPassage>,
+}
+
+impl TruncationReport {
+    ///
+    /// The number of distinct pages with at least one truncated passage.
+    ///
+    pub fn pages(&self) -> usize {
+        self.truncated.iter().map(|passage| passage.id).collect::<HashSet<i64>>().len()
+    }
 }
 
 ///
@@ -34,200 +270,1700 @@ pub enum ResolveLevel {
 /// # Fields
 /// * `corpus` - The corpus to generate embeddings from
 /// * `model` - The model used in the embedding process
-/// * `page_embeddings` - The generated embeddings
+/// * `page_index` - Position of every page in the corpus, keyed by `Page.id`
+/// * `page_embeddings` - The generated embeddings, one row per passage, addressed by `Page.id`
+/// * `passages` - The passage behind every row of `page_embeddings`
+/// * `passage_fields` - The field strategy `passages` were cut with
+/// * `lexical_index` - The BM25 index over page names and bodies, once built or loaded
+/// * `ann_index` - The approximate nearest neighbor graph over the embeddings, once built or loaded
+/// * `quantization` - How embeddings are stored when they are next built or loaded
+/// * `chunking` - How page bodies are split into passages when embeddings are next built or loaded
+/// * `aggregation` - How passage scores combine into page scores
+/// * `fields` - Which page fields are embedded when embeddings are next built or loaded
+/// * `name_weight` - Share of the name similarity in a page score when names are embedded separately
+/// * `reranker` - The cross-encoder searches can be reranked with, if one is loaded
+/// * `query_cache` - Embeddings of recent queries, so repeated queries skip the model
+/// * `verify_mapped` - Whether the checksum of an index is verified before it is memory-mapped
 ///
 pub struct Engine {
     corpus: Corpus,
     model: Model,
-    page_embeddings: Vec<Embeddings>,
+    page_index: HashMap<i64, usize>,
+    page_embeddings: EmbeddingStore,
+    passages: Vec<PassageSpan>,
+    passage_fields: FieldStrategy,
+    lexical_index: Option<Bm25Index>,
+    ann_index: Option<HnswIndex>,
+    quantization: QuantizationParams,
+    chunking: ChunkParams,
+    aggregation: Aggregation,
+    fields: FieldStrategy,
+    name_weight: f32,
+    reranker: Option<Mutex<Box<dyn CrossEncoder>>>,
+    query_cache: QueryCache,
+    verify_mapped: bool,
+}
+
+///
+/// Where a passage lies in the corpus.
+///
+/// # Fields
+/// * `page` - Position of the page in the corpus
+/// * `bytes` - Byte range of the passage within `Page.body`, or within `Page.name` for a name row
+/// * `name` - Whether the passage is the page name, embedded on its own after the body passages
+///
+#[derive(Debug, Clone)]
+struct PassageSpan {
+    page: usize,
+    bytes: Range<usize>,
+    name: bool,
 }
 
 impl Engine {
     ///
-    /// Create a new engine with the given corpus.
+    /// Create a new engine with the given corpus, backed by the rust-bert model.
     ///
     /// # Arguments
     /// * `corpus` - The corpus to generate embeddings for.
+    /// * `config` - Where to load the model from and whether the network may be used.
     ///
     /// # Returns
-    /// * `Engine` - The created engine.
+    /// * `Result<Engine>` - The created engine.
     ///
     /// # Errors
-    /// * `Error` - If there is an error generating embeddings.
+    /// * `Error` - If the model cannot be loaded.
     ///
     /// # Examples
-    /// ```
-    /// let corpus = Corpus::new();
-    /// let mut engine = Engine::new(corpus);
-    /// engine.build_embedding();
+    /// ```no_run
+    /// # use docueyes::bert::ModelConfig;
+    /// # use docueyes::corpus::load_corpus;
+    /// # use docueyes::engine::Engine;
+    /// # fn main() -> anyhow::Result<()> {
+    /// let corpus = load_corpus("corpus.json")?;
+    /// let mut engine = Engine::new(corpus, &ModelConfig::default())?;
+    /// engine.build_embeddings()?;
+    /// # Ok(())
+    /// # }
     /// ```
     ///
-    pub fn new(corpus: Corpus) -> Self {
-        Engine {
-            corpus: corpus,
-            model: Model::new(),
-            page_embeddings: Vec::new(),
-        }
+    #[cfg(feature = "bert")]
+    pub fn new(corpus: Corpus, config: &ModelConfig) -> Result<Self> {
+        Ok(Engine::with_model(corpus, Model::new(config)?))
     }
 
     ///
-    /// Generate embeddings for the corpus using the model.
+    /// Create a new engine with the given corpus and an already constructed model.
     ///
     /// # Arguments
     /// * `corpus` - The corpus to generate embeddings for.
+    /// * `model` - The model used in the embedding process, backed by any `Embedder`.
     ///
     /// # Returns
-    /// * `Vec<Embeddings>` - The generated embeddings.
+    /// * `Engine` - The created engine.
     ///
-    /// # Errors
-    /// * `Error` - All errors are handled internally.
+    pub fn with_model(corpus: Corpus, model: Model) -> Self {
+        let page_index = corpus
+            .pages
+            .iter()
+            .enumerate()
+            .map(|(index, page)| (page.id, index))
+            .collect();
+        Engine {
+            corpus,
+            model,
+            page_index,
+            page_embeddings: EmbeddingStore::default(),
+            passages: Vec::new(),
+            passage_fields: FieldStrategy::default(),
+            lexical_index: None,
+            ann_index: None,
+            quantization: QuantizationParams::default(),
+            chunking: ChunkParams::default(),
+            aggregation: Aggregation::default(),
+            fields: FieldStrategy::default(),
+            name_weight: DEFAULT_NAME_WEIGHT,
+            reranker: None,
+            query_cache: QueryCache::default(),
+            verify_mapped: false,
+        }
+    }
+
     ///
-    /// # Examples
-    /// ```
-    /// let corpus = Corpus::new();
-    /// let mut engine = Engine::new(corpus);
-    /// engine.build_embedding();
-    /// ```
+    /// Swap the model, dropping everything embedded by the previous one: page embeddings, the ANN graph
+    /// and cached queries. Embeddings have to be built or loaded again before searching.
+    ///
+    /// # Arguments
+    /// * `model` - The model used from now on.
     ///
-    pub fn build_embeddings(&mut self) -> Result<()> {
-        let embeddings = self
-            .model
-            .generate_embeddings(EmbeddingInput::Corpus(&self.corpus))?;
+    pub fn set_model(&mut self, model: Model) {
+        self.model = model;
+        self.page_embeddings.clear();
+        self.passages.clear();
+        self.ann_index = None;
+        self.query_cache.clear();
+    }
 
-        self.page_embeddings = embeddings;
-        Ok(())
+    ///
+    /// Resize the query embedding cache, dropping every cached query and resetting its counters.
+    ///
+    /// # Arguments
+    /// * `params` - How many queries to keep and for how long, a capacity of 0 turns the cache off.
+    ///
+    pub fn set_query_cache(&mut self, params: QueryCacheParams) {
+        self.query_cache = QueryCache::new(params);
+    }
+
+    ///
+    /// The hit and miss counters of the query embedding cache, and how full it is.
+    ///
+    pub fn query_cache_stats(&self) -> QueryCacheStats {
+        self.query_cache.stats()
     }
 
     ///
-    /// Writes text embeddings to a file.
+    /// Select how embeddings are stored, taking effect when they are next built or loaded.
     ///
     /// # Arguments
-    /// * `path` - The path to the file.
+    /// * `params` - The storage mode and how many candidates to rescore in f32.
+    ///
+    pub fn set_quantization(&mut self, params: QuantizationParams) {
+        self.quantization = params;
+    }
+
+    ///
+    /// How embeddings are stored when they are next built or loaded.
+    ///
+    pub fn quantization(&self) -> QuantizationParams {
+        self.quantization
+    }
+
+    ///
+    /// Select whether an index has its checksum verified before it is memory-mapped, taking effect when embeddings are next loaded.
+    /// Verifying reads every row once, trading the near-instant load of a mapped index for protection against a corrupt one.
+    ///
+    /// # Arguments
+    /// * `verify` - Whether to verify the checksum of mapped indexes.
+    ///
+    pub fn set_verify_mapped(&mut self, verify: bool) {
+        self.verify_mapped = verify;
+    }
+
+    ///
+    /// Select how page bodies are split into passages, taking effect when embeddings are next built or loaded.
+    ///
+    /// # Arguments
+    /// * `params` - The chunking strategy, token budget and overlap.
+    ///
+    pub fn set_chunking(&mut self, params: ChunkParams) {
+        self.chunking = params;
+    }
+
+    ///
+    /// How page bodies are split into passages when embeddings are next built or loaded.
+    ///
+    pub fn chunking(&self) -> ChunkParams {
+        self.chunking
+    }
+
+    ///
+    /// Select how passage scores combine into page scores, taking effect on the next search.
+    ///
+    /// # Arguments
+    /// * `aggregation` - Best passage, or the mean of the best few.
+    ///
+    pub fn set_aggregation(&mut self, aggregation: Aggregation) {
+        self.aggregation = aggregation;
+    }
+
+    ///
+    /// How passage scores combine into page scores.
+    ///
+    pub fn aggregation(&self) -> Aggregation {
+        self.aggregation
+    }
+
+    ///
+    /// Select which page fields are embedded, taking effect when embeddings are next built or loaded.
+    ///
+    /// # Arguments
+    /// * `fields` - The body alone, the name templated into every passage, or name and body separately.
+    ///
+    pub fn set_fields(&mut self, fields: FieldStrategy) {
+        self.fields = fields;
+    }
+
+    ///
+    /// Which page fields are embedded when embeddings are next built or loaded.
+    ///
+    pub fn fields(&self) -> FieldStrategy {
+        self.fields
+    }
+
+    ///
+    /// Select the share of the name similarity in a page score, taking effect on the next search.
+    /// Only used when names are embedded separately.
+    ///
+    /// # Arguments
+    /// * `name_weight` - Between 0 for the body alone and 1 for the name alone.
+    ///
+    pub fn set_name_weight(&mut self, name_weight: f32) {
+        self.name_weight = name_weight.clamp(0.0, 1.0);
+    }
+
+    ///
+    /// The share of the name similarity in a page score when names are embedded separately.
+    ///
+    pub fn name_weight(&self) -> f32 {
+        self.name_weight
+    }
+
+    ///
+    /// Load the cross-encoder searches are reranked with, replacing any loaded before.
+    ///
+    /// # Arguments
+    /// * `reranker` - The cross-encoder, backed by any `CrossEncoder`.
+    ///
+    pub fn set_reranker<C: CrossEncoder + 'static>(&mut self, reranker: C) {
+        self.reranker = Some(Mutex::new(Box::new(reranker)));
+    }
+
+    ///
+    /// Whether a cross-encoder is loaded, so searches can be reranked.
+    ///
+    pub fn has_reranker(&self) -> bool {
+        self.reranker.is_some()
+    }
+
+    ///
+    /// The number of rows embedded, one per page unless bodies are chunked or names embedded separately.
+    ///
+    pub fn passage_count(&self) -> usize {
+        self.page_embeddings.len()
+    }
+
+    ///
+    /// Look up an embedded passage of a page.
+    ///
+    /// # Arguments
+    /// * `id` - The `Page.id` the passage belongs to.
+    /// * `index` - The position of the passage within the page, as carried by `PageScore.passage`.
     ///
     /// # Returns
-    /// * `Result<()>` - The result of the operation.
+    /// * `Option<Passage>` - The passage text with its character offsets in `Page.body`, if the page has it.
     ///
-    pub fn cache_embeddings(&mut self, path: &str) -> Result<()> {
-        let mut file = File::create(path)?;
-        serde_json::to_writer(&mut file, &self.page_embeddings)?;
-        Ok(())
+    pub fn passage(&self, id: i64, index: usize) -> Option<Passage> {
+        let rows = self.page_embeddings.rows(id)?;
+        if index >= rows.len() {
+            return None;
+        }
+        let span = self.passages.get(rows.start + index).filter(|span| !span.name)?;
+        let body = &self.corpus.pages[span.page].body;
+        let start = body[..span.bytes.start].chars().count();
+        let text = &body[span.bytes.clone()];
+        Some(Passage {
+            index,
+            start,
+            end: start + text.chars().count(),
+            text: text.to_string(),
+        })
     }
 
     ///
-    /// Reads text embeddings from a file.
+    /// Look up a page by its id.
     ///
     /// # Arguments
-    /// * `path` - The path to the file.
+    /// * `id` - The `Page.id` to look up.
     ///
     /// # Returns
-    /// * `Result<()>` - The result of the operation.
+    /// * `Option<&Page>` - The page, if the corpus holds one with that id.
     ///
-    pub fn load_embeddings(&mut self, path: &str) -> Result<()> {
-        let mut file = File::open(path)?;
-        self.page_embeddings = serde_json::from_reader(&mut file)?;
-        Ok(())
+    pub fn page(&self, id: i64) -> Option<&Page> {
+        self.page_index.get(&id).map(|&index| &self.corpus.pages[index])
     }
 
     ///
-    /// Helper function to clears engines stored text embeddings.
+    /// Generate embeddings for the corpus using the model.
+    ///
+    /// # Arguments
+    /// * `corpus` - The corpus to generate embeddings for.
     ///
     /// # Returns
-    /// * `Result<()>` - The result of the operation.
+    /// * `Result<BuildSummary>` - How many pages were embedded and how long it took.
     ///
-    pub fn clear_embeddings(&mut self) -> Result<()> {
-        self.page_embeddings.clear();
-        Ok(())
+    /// # Errors
+    /// * `Error` - All errors are handled internally.
+    ///
+    /// # Examples
+    /// ```
+    /// # use docueyes::corpus::{Corpus, Page};
+    /// # use docueyes::engine::Engine;
+    /// # use docueyes::hashed::HashedEmbedder;
+    /// # use docueyes::model::Model;
+    /// # fn main() -> anyhow::Result<()> {
+    /// let page = Page { id: 1, name: "Pricing".to_string(), body: "Plans and prices".to_string(), ..Page::default() };
+    /// let corpus = Corpus { pages: vec![page] };
+    /// let mut engine = Engine::with_model(corpus, Model::from_embedder(HashedEmbedder::default()));
+    /// let summary = engine.build_embeddings()?;
+    /// assert_eq!(summary.pages, 1);
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    pub fn build_embeddings(&mut self) -> Result<BuildSummary> {
+        self.build_embeddings_with_progress(&|_| {})
     }
 
-    /// Calculate the cosine similarity between two `f32` vectors.
+    ///
+    /// Generate embeddings for the corpus like `build_embeddings`, reporting progress after every batch.
     ///
     /// # Arguments
-    /// * `a` - The first vector.
-    /// * `b` - The second vector.
+    /// * `progress` - Called with the pages done, the page total and the batch that just finished.
+    ///   It may be called from several threads at once when the model encodes with a pool.
     ///
     /// # Returns
-    /// * `f32` - The cosine similarity between the two vectors.
+    /// * `Result<BuildSummary>` - How many pages and passages were embedded and how long it took.
     ///
-    fn cosine_similarity(&self, a: &[f32], b: &[f32]) -> f32 {
-        assert_eq!(a.len(), b.len(), "Vectors must have the same length");
+    pub fn build_embeddings_with_progress(
+        &mut self,
+        progress: &(dyn Fn(EmbeddingProgress) + Sync),
+    ) -> Result<BuildSummary> {
+        let started = Instant::now();
+        let passages = self.chunk_corpus();
+        let truncated = self.truncation(&passages)?.truncated;
+        let texts: Vec<Cow<str>> = passages.iter().map(|span| self.embedded_text(span, self.fields)).collect();
+        let embeddings = self.model.generate_embeddings_with_progress(
+            EmbeddingInput::Texts(texts.iter().map(|text| text.as_ref()).collect()),
+            progress,
+        )?;
+        if embeddings.len() != passages.len() {
+            return Err(anyhow::anyhow!(
+                "Model returned {} embeddings for {} passages",
+                embeddings.len(),
+                passages.len()
+            ));
+        }
+        let summary = BuildSummary {
+            pages: self.corpus.pages.len(),
+            passages: embeddings.len(),
+            elapsed: started.elapsed(),
+            truncated,
+        };
+
+        self.set_embeddings(passages, embeddings)?;
+        Ok(summary)
+    }
 
-        let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
-        let norm_a: f32 = a.iter().map(|x| x * x).sum::<f32>().sqrt();
-        let norm_b: f32 = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    ///
+    /// Tokenize every passage with the model and report those longer than it reads, without embedding anything.
+    ///
+    /// Unless bodies are chunked every page is a single passage, so this shows which pages are partly ignored.
+    ///
+    /// # Returns
+    /// * `Result<TruncationReport>` - The model limit and the passages over it, most tokens over first.
+    ///
+    pub fn truncation_report(&self) -> Result<TruncationReport> {
+        self.truncation(&self.chunk_corpus())
+    }
 
-        if norm_a == 0.0 || norm_b == 0.0 {
-            0.0 // or handle divide-by-zero as you see fit
-        } else {
-            dot / (norm_a * norm_b)
+    fn truncation(&self, passages: &[PassageSpan]) -> Result<TruncationReport> {
+        let mut report = TruncationReport {
+            max_tokens: self.model.max_tokens(),
+            passages: passages.len(),
+            truncated: Vec::new(),
+        };
+        let Some(max_tokens) = report.max_tokens else {
+            return Ok(report);
+        };
+        let texts: Vec<Cow<str>> = passages.iter().map(|span| self.embedded_text(span, self.fields)).collect();
+        let counts = self.model.count_tokens(&texts.iter().map(|text| text.as_ref()).collect::<Vec<&str>>())?;
+        let mut first_row = 0;
+        for (row, (span, tokens)) in passages.iter().zip(counts).enumerate() {
+            if row > 0 && passages[row - 1].page != span.page {
+                first_row = row;
+            }
+            if tokens > max_tokens && !span.name {
+                report.truncated.push(TruncatedPassage {
+                    id: self.corpus.pages[span.page].id,
+                    passage: row - first_row,
+                    tokens,
+                    excess: tokens - max_tokens,
+                });
+            }
         }
+        report.truncated.sort_by_key(|passage| Reverse(passage.excess));
+        Ok(report)
     }
 
     ///
-    /// Search for pages similar to the query using the embeddings.
+    /// Writes text embeddings to a binary index file along with the model and corpus they belong to.
     ///
     /// # Arguments
-    /// * `query` - The query to search for.
+    /// * `path` - The path to the file.
     ///
     /// # Returns
-    /// * `Vec<String>` - The pages similar to the query.
+    /// * `Result<()>` - The result of the operation.
     ///
-    pub fn search(&self, query: &str) -> Result<Vec<f32>> {
-        let query_embedding = self
-            .model
-            .generate_embeddings(EmbeddingInput::Text(query))?;
-        // TODO fix nothing I'm a GOD... five days later and I'm trying to fix this... the issue wasn't here. I'M STILL A GOD!!
-
-        let mut similarities = Vec::new();
-        for (_, page_embedding) in self.page_embeddings.iter().enumerate() {
-            let similarity = self.cosine_similarity(&query_embedding[0], page_embedding);
-            similarities.push(similarity);
-        }
-        // similarities.sort_by(|b, a| a.partial_cmp(b).unwrap());
-        Ok(similarities)
+    pub fn cache_embeddings(&mut self, path: &str) -> Result<()> {
+        self.snapshot()?.write_binary(path)
     }
 
     ///
-    /// Resolve the similarity set to a single value based on the resolve level.
+    /// Reads text embeddings from a binary index file, re-embedding only passages added or changed since it was written.
+    ///
+    /// When the index matches the chunked corpus passage for passage and is stored the way the engine needs, it is memory-mapped
+    /// instead of read, so loading is near-instant regardless of its size. Otherwise it is read and refreshed, and the summary
+    /// records why it could not be mapped. A mapped index only has its checksum verified when `set_verify_mapped` asks for it.
     ///
     /// # Arguments
-    /// * `resolve_level` - The level of resolution.
-    /// * `set` - The similarity set.
+    /// * `path` - The path to the file.
     ///
     /// # Returns
-    /// * `Option<f32>` - The resolved similarity value.
+    /// * `Result<RefreshSummary>` - How many embeddings were reused, recomputed and dropped.
+    ///
+    /// # Errors
+    /// * `Error` - If the index is corrupt or was built by another model, the caller should rebuild.
+    ///
+    pub fn load_embeddings(&mut self, path: &str) -> Result<RefreshSummary> {
+        let mapping_error = match MappedCache::open(path, self.verify_mapped).and_then(|mapped| self.map_embeddings(mapped)) {
+            Ok(()) => {
+                return Ok(RefreshSummary {
+                    reused: self.page_embeddings.len(),
+                    ..RefreshSummary::default()
+                });
+            }
+            Err(e) => e.to_string(),
+        };
+        let cache = EmbeddingCache::read_binary(path)?;
+        Ok(RefreshSummary {
+            mapping_error: Some(mapping_error),
+            ..self.adopt(cache)?
+        })
+    }
+
     ///
-    pub fn resolve(&self, set: Vec<f32>, temperature: f32, _window_size: usize) -> Vec<Page> {
-        // TODO make resolver handle sorted returns by using the page id or something
-        let mut resolved_pages = Vec::new();
-        let mut index = 0;
+    /// Whether the embeddings are served straight out of a memory-mapped index.
+    ///
+    pub fn embeddings_mapped(&self) -> bool {
+        self.page_embeddings.is_mapped()
+    }
 
-        // All negative elements signals a complete dissimilarity and no matching is possible
-        if self.all_are_negative(&set) {
-            return resolved_pages;
+    ///
+    /// Serve the embeddings from a mapped index, provided it needs no refresh.
+    ///
+    fn map_embeddings(&mut self, mapped: MappedCache) -> Result<()> {
+        if mapped.model_id != self.model.model_id() || mapped.dimension != self.model.dimension() {
+            return Err(anyhow::anyhow!("Mapped index was built by another model"));
         }
-
-        // add in a check for complete dissimilariters
-        for similarity in set {
-            if similarity >= temperature {
-                let mut page = self.corpus.pages.get(index).unwrap().clone();
-                page.similarity = similarity;
-                resolved_pages.push(page);
-            }
-            index += 1;
+        if mapped.fields != self.fields {
+            return Err(anyhow::anyhow!(
+                "Mapped index embeds {} fields but the engine embeds {}",
+                mapped.fields,
+                self.fields
+            ));
+        }
+        // The id mapping comes from the chunked corpus, so every row has to still line up with its passage
+        let passages = self.chunk_corpus();
+        let current = passages.iter().map(|span| self.passage_hash(span, self.fields));
+        if mapped.corpus_hash != self.corpus.content_hash() || !current.eq(mapped.text_hashes.iter().copied()) {
+            return Err(anyhow::anyhow!("Mapped index does not match the corpus"));
         }
-        resolved_pages
+        let ids = self.passage_ids(&passages);
+        self.page_embeddings = EmbeddingStore::from_matrix(ids, mapped.dimension, mapped.rows, self.quantization)?;
+        self.passages = passages;
+        self.passage_fields = self.fields;
+        self.ann_index = None;
+        Ok(())
     }
 
     ///
-    /// Check if all elements in the slice are negative.
+    /// Writes text embeddings to a JSON file, the compatibility counterpart of `cache_embeddings`.
     ///
     /// # Arguments
-    /// * `data` - The slice to check.
+    /// * `path` - The path to the file.
     ///
     /// # Returns
-    /// * `bool` - True if all elements are negative, false otherwise.
+    /// * `Result<()>` - The result of the operation.
+    ///
+    pub fn export_embeddings_json(&self, path: &str) -> Result<()> {
+        self.snapshot()?.write_json(path)
+    }
+
+    ///
+    /// Reads text embeddings from a JSON file, including the bare arrays older releases wrote to `embeddings.txt`.
+    ///
+    /// Bare arrays record no text hashes, so nothing ties a row to the text it was embedded from and every
+    /// passage is embedded again. Only the model and dimension are checked against them.
+    ///
+    /// # Arguments
+    /// * `path` - The path to the file.
+    ///
+    /// # Returns
+    /// * `Result<RefreshSummary>` - How many embeddings were reused, recomputed and dropped.
+    ///
+    /// # Errors
+    /// * `Error` - If the file was written by another model, with another dimension or holds a row without a text hash.
+    ///
+    pub fn import_embeddings_json(&mut self, path: &str) -> Result<RefreshSummary> {
+        let cache = EmbeddingCache::read_json(path, self.model.model_id())?;
+        self.adopt(cache)
+    }
+
+    ///
+    /// Capture the current passage embeddings in corpus order along with the metadata needed to validate them later.
+    ///
+    fn snapshot(&self) -> Result<EmbeddingCache> {
+        if self.page_embeddings.is_empty() || self.page_embeddings.len() != self.passages.len() {
+            return Err(anyhow::anyhow!("Corpus has no embeddings to cache"));
+        }
+        let embeddings = (0..self.page_embeddings.len())
+            .map(|row| self.page_embeddings.vector(row).into_owned())
+            .collect();
+        Ok(EmbeddingCache {
+            model_id: self.model.model_id().to_string(),
+            dimension: self.model.dimension(),
+            corpus_hash: self.corpus.content_hash(),
+            text_hashes: self.passages.iter().map(|span| self.passage_hash(span, self.passage_fields)).collect(),
+            quantization: self.page_embeddings.quantization(),
+            full_precision: self.page_embeddings.is_full_precision(),
+            fields: self.passage_fields,
+            embeddings,
+        })
+    }
+
+    ///
+    /// Replace the stored embeddings with one per passage, in corpus order.
+    ///
+    fn set_embeddings(&mut self, passages: Vec<PassageSpan>, embeddings: Vec<Embeddings>) -> Result<()> {
+        let ids = self.passage_ids(&passages);
+        self.page_embeddings = EmbeddingStore::quantized(ids, embeddings, self.quantization)?;
+        self.passages = passages;
+        self.passage_fields = self.fields;
+        // The graph refers to rows by number, so it cannot survive the rows being replaced
+        self.ann_index = None;
+        Ok(())
+    }
+
+    ///
+    /// Split every page body into passages with the current chunking, in corpus order.
+    /// When names are embedded separately every page ends with a name row.
+    ///
+    fn chunk_corpus(&self) -> Vec<PassageSpan> {
+        let mut passages = Vec::with_capacity(self.corpus.pages.len());
+        for (index, page) in self.corpus.pages.iter().enumerate() {
+            passages.extend(split(&page.body, &self.chunking).into_iter().map(|bytes| PassageSpan {
+                page: index,
+                bytes,
+                name: false,
+            }));
+            if self.fields == FieldStrategy::Separate {
+                passages.push(PassageSpan {
+                    page: index,
+                    bytes: 0..page.name.len(),
+                    name: true,
+                });
+            }
+        }
+        passages
+    }
+
+    fn passage_text(&self, span: &PassageSpan) -> &str {
+        let page = &self.corpus.pages[span.page];
+        let field = if span.name { &page.name } else { &page.body };
+        &field[span.bytes.clone()]
+    }
+
+    ///
+    /// The text the model embeds for a passage, the passage itself unless the name is templated in.
+    ///
+    fn embedded_text(&self, span: &PassageSpan, fields: FieldStrategy) -> Cow<'_, str> {
+        let text = self.passage_text(span);
+        match fields {
+            FieldStrategy::Template if !span.name => {
+                Cow::Owned(format!("{}{}{}", self.corpus.pages[span.page].name, TEMPLATE_SEPARATOR, text))
+            }
+            _ => Cow::Borrowed(text),
+        }
+    }
+
+    ///
+    /// Hash the embedded text of a passage, an unchunked body hashes the same as `Page.text_hash`.
+    ///
+    fn passage_hash(&self, span: &PassageSpan, fields: FieldStrategy) -> u64 {
+        fnv1a(self.embedded_text(span, fields).as_bytes())
+    }
+
+    fn passage_ids(&self, passages: &[PassageSpan]) -> Vec<i64> {
+        passages.iter().map(|span| self.corpus.pages[span.page].id).collect()
+    }
+
+    ///
+    /// Validate a cache against the model, then take every embedding whose text is unchanged and embed the rest.
+    ///
+    fn adopt(&mut self, cache: EmbeddingCache) -> Result<RefreshSummary> {
+        if cache.model_id != self.model.model_id() {
+            return Err(anyhow::anyhow!(
+                "Embeddings cache was built with model {} but the engine uses {}",
+                cache.model_id,
+                self.model.model_id()
+            ));
+        }
+        if cache.dimension != self.model.dimension()
+            || cache.embeddings.iter().any(|embedding| embedding.len() != cache.dimension)
+        {
+            return Err(anyhow::anyhow!(
+                "Embeddings cache dimension {} does not match the model dimension {}",
+                cache.dimension,
+                self.model.dimension()
+            ));
+        }
+
+        let passages = self.chunk_corpus();
+        let current_hashes: Vec<u64> = passages.iter().map(|span| self.passage_hash(span, self.fields)).collect();
+        let cached_hashes = if cache.text_hashes.len() == cache.embeddings.len() {
+            cache.text_hashes
+        } else if cache.text_hashes.is_empty() {
+            // Files from before text hashes were recorded say nothing about which text a row came from,
+            // so even one lining up with the corpus may hold an edited page's old embedding and is embedded afresh
+            Vec::new()
+        } else {
+            return Err(anyhow::anyhow!(
+                "Embeddings cache holds {} passages but {} text hashes",
+                cache.embeddings.len(),
+                cache.text_hashes.len()
+            ));
+        };
+
+        let current: HashSet<&u64> = current_hashes.iter().collect();
+        let kept = cached_hashes.iter().filter(|text_hash| current.contains(text_hash)).count();
+        let mut summary = RefreshSummary {
+            removed: cache.embeddings.len() - kept,
+            requantized: cache.quantization != self.quantization.mode,
+            fields_changed: cache.fields != self.fields,
+            ..RefreshSummary::default()
+        };
+        // Dequantized rows cannot stand in for f32 ones, so a quantized cache is only reused by a quantized engine
+        let cached: HashMap<u64, Embeddings> = if cache.full_precision || !self.quantization.needs_full_precision() {
+            cached_hashes.into_iter().zip(cache.embeddings).collect()
+        } else {
+            HashMap::new()
+        };
+
+        let mut embeddings = Vec::with_capacity(current_hashes.len());
+        let mut stale = Vec::new();
+        for (index, text_hash) in current_hashes.iter().enumerate() {
+            match cached.get(text_hash) {
+                Some(embedding) => embeddings.push(embedding.clone()),
+                None => {
+                    stale.push(index);
+                    embeddings.push(Vec::new());
+                }
+            }
+        }
+        summary.reused = current_hashes.len() - stale.len();
+        summary.recomputed = stale.len();
+
+        if !stale.is_empty() {
+            let texts: Vec<Cow<str>> = stale.iter().map(|&index| self.embedded_text(&passages[index], self.fields)).collect();
+            let fresh = self
+                .model
+                .generate_embeddings(EmbeddingInput::Texts(texts.iter().map(|text| text.as_ref()).collect()))?;
+            if fresh.len() != stale.len() {
+                return Err(anyhow::anyhow!(
+                    "Model returned {} embeddings for {} passages",
+                    fresh.len(),
+                    stale.len()
+                ));
+            }
+            for (index, embedding) in stale.into_iter().zip(fresh) {
+                embeddings[index] = embedding;
+            }
+        }
+
+        self.set_embeddings(passages, embeddings)?;
+        Ok(summary)
+    }
+
+    ///
+    /// Build the BM25 lexical index over the corpus, replacing any index already held.
+    ///
+    /// # Arguments
+    /// * `params` - The BM25 parameters to score with.
+    ///
+    pub fn build_lexical_index(&mut self, params: Bm25Params) {
+        self.lexical_index = Some(Bm25Index::build(&self.corpus, params));
+    }
+
+    ///
+    /// Writes the lexical index to a file.
+    ///
+    /// # Arguments
+    /// * `path` - The path to the file.
+    ///
+    /// # Returns
+    /// * `Result<()>` - The result of the operation.
+    ///
+    pub fn cache_lexical_index(&self, path: &str) -> Result<()> {
+        self.lexical_index
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Lexical index has not been built"))?
+            .save(path)
+    }
+
+    ///
+    /// Reads the lexical index from a file, refusing indexes built from another corpus or with other parameters.
+    ///
+    /// # Arguments
+    /// * `path` - The path to the file.
+    /// * `params` - The BM25 parameters the index is expected to use.
+    ///
+    /// # Returns
+    /// * `Result<()>` - The result of the operation.
+    ///
+    /// # Errors
+    /// * `Error` - If the index is unreadable or stale, the caller should rebuild.
+    ///
+    pub fn load_lexical_index(&mut self, path: &str, params: Bm25Params) -> Result<()> {
+        let index = Bm25Index::load(path)?;
+        if !index.matches(&self.corpus) {
+            return Err(anyhow::anyhow!("Lexical index {} was built from a different corpus", path));
+        }
+        if index.params() != params {
+            return Err(anyhow::anyhow!(
+                "Lexical index {} was built with {:?} but {:?} is configured",
+                path,
+                index.params(),
+                params
+            ));
+        }
+        self.lexical_index = Some(index);
+        Ok(())
+    }
+
+    ///
+    /// Search for pages sharing terms with the query using the lexical index alone, without the model.
+    ///
+    /// # Arguments
+    /// * `query` - The query to search for.
+    ///
+    /// # Returns
+    /// * `Vec<PageScore>` - The BM25 score of every page matching a query term, in corpus order.
+    ///
+    pub fn search_lexical(&self, query: &str) -> Result<Vec<PageScore>> {
+        let index = self
+            .lexical_index
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Lexical index has not been built"))?;
+        Ok(index.search(query))
+    }
+
+    ///
+    /// Search with both the embeddings and the lexical index, fusing the two rankings.
+    ///
+    /// # Arguments
+    /// * `query` - The query to search for.
+    /// * `fusion` - How to combine the semantic and lexical rankings.
+    /// * `semantic_floor` - The minimum cosine similarity for a page to count as a semantic candidate.
+    ///
+    /// # Returns
+    /// * `Vec<HybridScore>` - The fused and component scores of every candidate from either ranking.
+    ///
+    pub fn search_hybrid(&self, query: &str, fusion: Fusion, semantic_floor: f32) -> Result<Vec<HybridScore>> {
+        let semantic: Vec<PageScore> = self
+            .search(query)?
+            .into_iter()
+            .filter(|score| score.similarity >= semantic_floor)
+            .collect();
+        let lexical = self.search_lexical(query)?;
+        Ok(fuse(&semantic, &lexical, fusion))
+    }
+
+    ///
+    /// Build the approximate nearest neighbor graph over the current embeddings.
+    ///
+    /// # Arguments
+    /// * `params` - The graph parameters.
+    ///
+    pub fn build_ann_index(&mut self, params: HnswParams) {
+        self.ann_index = Some(HnswIndex::build(&self.page_embeddings, params, self.graph_source()));
+    }
+
+    ///
+    /// What the current embeddings were generated from, recorded in an ANN graph built over them.
+    /// The passages hash covers chunking and fields, which change the embeddings without changing the corpus.
+    ///
+    fn graph_source(&self) -> GraphSource {
+        let hashes: Vec<u8> = self
+            .passages
+            .iter()
+            .flat_map(|span| self.passage_hash(span, self.passage_fields).to_le_bytes())
+            .collect();
+        GraphSource {
+            model_id: self.model.model_id().to_string(),
+            corpus_hash: self.corpus.content_hash(),
+            passages_hash: fnv1a(&hashes),
+            fields: self.passage_fields,
+        }
+    }
+
+    ///
+    /// Writes the approximate nearest neighbor graph to a file.
+    ///
+    /// # Arguments
+    /// * `path` - The path to the file.
+    ///
+    /// # Returns
+    /// * `Result<()>` - The result of the operation.
+    ///
+    pub fn cache_ann_index(&self, path: &str) -> Result<()> {
+        self.ann_index
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("ANN index has not been built"))?
+            .save(path)
+    }
+
+    ///
+    /// Reads the approximate nearest neighbor graph from a file, refusing graphs over other embeddings or parameters.
+    /// Embeddings count as other when the model, corpus, passage chunking or embedded fields differ.
+    ///
+    /// # Arguments
+    /// * `path` - The path to the file.
+    /// * `params` - The graph parameters the index is expected to use.
+    ///
+    /// # Returns
+    /// * `Result<()>` - The result of the operation.
+    ///
+    /// # Errors
+    /// * `Error` - If the graph is unreadable or stale, the caller should rebuild.
+    ///
+    pub fn load_ann_index(&mut self, path: &str, params: HnswParams) -> Result<()> {
+        let index = HnswIndex::load(path)?;
+        if !index.matches(&self.page_embeddings, &self.graph_source()) {
+            return Err(anyhow::anyhow!("ANN index {} was built over different embeddings", path));
+        }
+        if index.params() != params {
+            return Err(anyhow::anyhow!(
+                "ANN index {} was built with {:?} but {:?} is configured",
+                path,
+                index.params(),
+                params
+            ));
+        }
+        self.ann_index = Some(index);
+        Ok(())
+    }
+
+    ///
+    /// Whether searches can use the approximate nearest neighbor graph.
+    ///
+    pub fn has_ann_index(&self) -> bool {
+        self.ann_index.is_some()
+    }
+
+    ///
+    /// Search for the `k` pages most similar to the query, through the ANN graph when one is loaded.
+    /// Without a graph this falls back to exact brute force search.
+    ///
+    /// # Arguments
+    /// * `query` - The query to search for.
+    /// * `k` - The number of pages to return.
+    ///
+    /// # Returns
+    /// * `Vec<PageScore>` - Up to `k` pages, most similar first.
+    ///
+    pub fn search_approximate(&self, query: &str, k: usize) -> Result<Vec<PageScore>> {
+        let query_embedding = self.embed_query(query)?;
+        Ok(self.approximate(&query_embedding, k))
+    }
+
+    fn approximate(&self, query_embedding: &[f32], k: usize) -> Vec<PageScore> {
+        match &self.ann_index {
+            Some(index) => {
+                // The graph finds passages, so look far enough for several of a page to still leave k pages.
+                // Passages it does not reach are left out of a page's mean, so means are approximate too
+                let passages_per_page = self.page_embeddings.len().div_ceil(self.corpus.pages.len().max(1)).max(1);
+                let rows = k.max(self.quantization.rescore).saturating_mul(passages_per_page);
+                let mut candidates = index.search(&self.page_embeddings, query_embedding, rows);
+                self.page_embeddings
+                    .rescore(query_embedding, &mut candidates, self.quantization.rescore);
+                top_k(self.page_scores(query_embedding, candidates), k)
+            }
+            None => top_k(self.search_embedding(query_embedding), k),
+        }
+    }
+
+    ///
+    /// Find the pages most similar to a page, searching with its stored embedding instead of a query.
+    /// A chunked page searches with the centroid of its body passages. Uses the ANN graph when one is built.
+    ///
+    /// # Arguments
+    /// * `id` - The `Page.id` to find similar pages for.
+    /// * `k` - The number of pages to return.
+    ///
+    /// # Returns
+    /// * `Result<Vec<PageScore>>` - Up to `k` pages other than the page itself, most similar first.
+    ///
+    /// # Errors
+    /// * `Error` - If the page has no embeddings.
+    ///
+    pub fn similar_to(&self, id: i64, k: usize) -> Result<Vec<PageScore>> {
+        let page_embedding = self
+            .page_vector(id)
+            .ok_or_else(|| anyhow::anyhow!("Page {} has no embeddings", id))?;
+        let scores = self.approximate(&page_embedding, k.saturating_add(1));
+        Ok(top_k(scores.into_iter().filter(|score| score.id != id), k))
+    }
+
+    ///
+    /// Sum the body passage rows of a page, the name row is left out. Scoring normalizes it into their centroid.
+    ///
+    fn page_vector(&self, id: i64) -> Option<Embeddings> {
+        let rows = self.page_embeddings.rows(id)?;
+        let mut page_vector = vec![0.0; self.page_embeddings.dimension()];
+        for row in rows.filter(|&row| !self.passages.get(row).is_some_and(|span| span.name)) {
+            for (sum, value) in page_vector.iter_mut().zip(self.page_embeddings.vector(row).iter()) {
+                *sum += value;
+            }
+        }
+        Some(page_vector)
+    }
+
+    ///
+    /// Measure how many of the exact top `k` results the ANN graph finds, averaged over the queries.
+    ///
+    /// # Arguments
+    /// * `queries` - The queries to check with.
+    /// * `k` - The number of results compared per query.
+    ///
+    /// # Returns
+    /// * `Result<f32>` - Recall@k between 0 and 1.
+    ///
+    pub fn ann_recall(&self, queries: &[&str], k: usize) -> Result<f32> {
+        let index = self
+            .ann_index
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("ANN index has not been built"))?;
+        let mut found = 0;
+        let mut expected = 0;
+        for query in queries {
+            let query_embedding = self.embed_query(query)?;
+            let exact: HashSet<i64> = top_k(self.page_embeddings.scores(&query_embedding), k)
+                .iter()
+                .map(|score| score.id)
+                .collect();
+            found += index
+                .search(&self.page_embeddings, &query_embedding, k)
+                .iter()
+                .filter(|score| exact.contains(&score.id))
+                .count();
+            expected += exact.len();
+        }
+        Ok(if expected == 0 { 1.0 } else { found as f32 / expected as f32 })
+    }
+
+    ///
+    /// Helper function to clears engines stored text embeddings.
+    ///
+    /// # Returns
+    /// * `Result<()>` - The result of the operation.
+    ///
+    pub fn clear_embeddings(&mut self) -> Result<()> {
+        self.page_embeddings.clear();
+        self.passages.clear();
+        self.ann_index = None;
+        Ok(())
+    }
+
+    ///
+    /// Embed a query, checking it against the model dimension. Repeated queries are served from the query cache.
+    ///
+    fn embed_query(&self, query: &str) -> Result<Embeddings> {
+        self.embed_queries(&[query])?
+            .pop()
+            .ok_or_else(|| anyhow::anyhow!("Model returned no embedding for the query"))
+    }
+
+    ///
+    /// Embed many queries, the ones not in the query cache in one model call, checking them against the model dimension.
+    ///
+    fn embed_queries(&self, queries: &[&str]) -> Result<Vec<Embeddings>> {
+        // The query is embedded as it was asked, since a cased model reads case, and only cached under its normalized text.
+        // Queries normalizing to the same key share the embedding of the first one asked
+        let keys: Vec<String> = queries.iter().map(|query| normalize_query(query)).collect();
+        let mut query_embeddings: Vec<Option<Embeddings>> = keys.iter().map(|key| self.query_cache.get(key)).collect();
+        let mut seen: HashSet<&str> = HashSet::new();
+        let missing: Vec<(&str, &str)> = keys
+            .iter()
+            .zip(queries)
+            .zip(&query_embeddings)
+            .filter(|((key, _), embedding)| embedding.is_none() && seen.insert(key.as_str()))
+            .map(|((key, &query), _)| (key.as_str(), query))
+            .collect();
+        if !missing.is_empty() {
+            let texts: Vec<&str> = missing.iter().map(|&(_, query)| query).collect();
+            let embedded = self.model.generate_embeddings(EmbeddingInput::Texts(texts))?;
+            if embedded.len() != missing.len() {
+                return Err(anyhow::anyhow!(
+                    "Model returned {} embeddings for {} queries",
+                    embedded.len(),
+                    missing.len()
+                ));
+            }
+            if let Some(query_embedding) = embedded.iter().find(|embedding| embedding.len() != self.model.dimension()) {
+                return Err(anyhow::anyhow!(
+                    "Query embedding dimension {} does not match the model dimension {}",
+                    query_embedding.len(),
+                    self.model.dimension()
+                ));
+            }
+            let embedded: HashMap<&str, Embeddings> = missing.into_iter().map(|(key, _)| key).zip(embedded).collect();
+            for (key, query_embedding) in keys.iter().zip(&mut query_embeddings) {
+                if query_embedding.is_none() {
+                    *query_embedding = embedded.get(key.as_str()).cloned();
+                }
+            }
+            for (key, embedding) in embedded {
+                self.query_cache.insert(key.to_string(), embedding);
+            }
+        }
+        query_embeddings
+            .into_iter()
+            .map(|embedding| embedding.ok_or_else(|| anyhow::anyhow!("A query was left without an embedding")))
+            .collect()
+    }
+
+    ///
+    /// Search for pages similar to the query using the embeddings.
+    ///
+    /// # Arguments
+    /// * `query` - The query to search for.
+    ///
+    /// # Returns
+    /// * `Vec<PageScore>` - The similarity of every embedded page to the query, carrying its best passage, in corpus order.
+    ///
+    pub fn search(&self, query: &str) -> Result<Vec<PageScore>> {
+        let query_embedding = self.embed_query(query)?;
+        // TODO fix nothing I'm a GOD... five days later and I'm trying to fix this... the issue wasn't here. I'M STILL A GOD!!
+        Ok(self.search_embedding(&query_embedding))
+    }
+
+    ///
+    /// Search for pages similar to each of many queries, embedding them in one model batch and scoring them
+    /// against the embeddings in one pass.
+    ///
+    /// # Arguments
+    /// * `queries` - The queries to search for.
+    ///
+    /// # Returns
+    /// * `Vec<Vec<PageScore>>` - For every query in order, what `search` would return for it.
+    ///
+    pub fn search_batch(&self, queries: &[&str]) -> Result<Vec<Vec<PageScore>>> {
+        let query_embeddings = self.embed_queries(queries)?;
+        let scores = self.page_embeddings.scores_batch(&query_embeddings);
+        Ok(query_embeddings
+            .iter()
+            .zip(scores)
+            .map(|(query_embedding, scores)| self.fold_scores(query_embedding, scores))
+            .collect())
+    }
+
+    ///
+    /// Score every passage against an embedded query, rescoring the best quantized candidates when enabled,
+    /// then fold the passage scores into page scores.
+    ///
+    fn search_embedding(&self, query_embedding: &[f32]) -> Vec<PageScore> {
+        self.fold_scores(query_embedding, self.page_embeddings.scores(query_embedding))
+    }
+
+    fn fold_scores(&self, query_embedding: &[f32], mut scores: Vec<PageScore>) -> Vec<PageScore> {
+        self.page_embeddings
+            .rescore(query_embedding, &mut scores, self.quantization.rescore);
+        self.page_scores(query_embedding, scores)
+    }
+
+    ///
+    /// Fold passage scores into page scores, blending in the name similarity when names are embedded separately.
+    ///
+    fn page_scores(&self, query_embedding: &[f32], scores: Vec<PageScore>) -> Vec<PageScore> {
+        if self.passage_fields != FieldStrategy::Separate {
+            return aggregate(scores, self.aggregation);
+        }
+        let body = scores.into_iter().filter(|score| !self.is_name_row(score)).collect();
+        let mut query = query_embedding.to_vec();
+        normalize(&mut query);
+        aggregate(body, self.aggregation)
+            .into_iter()
+            .map(|score| {
+                // The name row closes the rows of its page, and is scored exactly whether or not a search reached it
+                let name = self
+                    .page_embeddings
+                    .rows(score.id)
+                    .map_or(0.0, |rows| dot(&query, &self.page_embeddings.vector(rows.end - 1)));
+                PageScore {
+                    similarity: blend(score.similarity, name, self.name_weight),
+                    ..score
+                }
+            })
+            .collect()
+    }
+
+    fn is_name_row(&self, score: &PageScore) -> bool {
+        match (self.page_embeddings.rows(score.id), score.passage) {
+            (Some(rows), Some(passage)) => self.passages.get(rows.start + passage).is_some_and(|span| span.name),
+            _ => false,
+        }
+    }
+
+    ///
+    /// Rerank the best pages of a search with the cross-encoder, rescoring each against the query
+    /// by the passage it matched on.
+    ///
+    /// Cross-encoder scores are relevance probabilities rather than cosine similarities, so they want their own
+    /// temperature when resolved.
+    ///
+    /// # Arguments
+    /// * `query` - The query the pages were searched with.
+    /// * `scores` - The first stage scores, from any search.
+    /// * `params` - How many of the best pages to rescore.
+    ///
+    /// # Returns
+    /// * `Result<Vec<PageScore>>` - The rescored candidates, most relevant first, the pages past the cap are dropped.
+    ///
+    /// # Errors
+    /// * `Error` - If no cross-encoder is loaded or it fails to score.
+    ///
+    pub fn rerank(&self, query: &str, scores: Vec<PageScore>, params: RerankParams) -> Result<Vec<PageScore>> {
+        let reranker = self
+            .reranker
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No cross-encoder is loaded to rerank with"))?;
+        let candidates: Vec<(PageScore, &str)> = top_k(scores, params.candidates)
+            .into_iter()
+            .filter_map(|score| Some((score, self.rerank_text(&score)?)))
+            .collect();
+        let texts: Vec<&str> = candidates.iter().map(|(_, text)| *text).collect();
+        let relevance = reranker
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Cross-encoder was poisoned by a panic in another thread"))?
+            .score(query, &texts)?;
+        if relevance.len() != candidates.len() {
+            return Err(anyhow::anyhow!(
+                "Cross-encoder returned {} scores for {} candidates",
+                relevance.len(),
+                candidates.len()
+            ));
+        }
+        let reranked = candidates
+            .into_iter()
+            .zip(relevance)
+            .map(|((score, _), similarity)| PageScore { similarity, ..score });
+        Ok(top_k(reranked, params.candidates))
+    }
+
+    ///
+    /// The text a page is reranked by: the passage it matched on, its first passage for lexical scores,
+    /// or its body when it has no embeddings.
+    ///
+    fn rerank_text(&self, score: &PageScore) -> Option<&str> {
+        let span = self
+            .page_embeddings
+            .rows(score.id)
+            .and_then(|rows| self.passages.get(rows.start + score.passage.unwrap_or(0)));
+        match span {
+            Some(span) => Some(self.passage_text(span)),
+            None => self.page(score.id).map(|page| page.body.as_str()),
+        }
+    }
+
+    ///
+    /// Resolve the similarity set to the pages that clear the temperature, selected by the resolve level.
+    ///
+    /// # Arguments
+    /// * `set` - The similarity set, addressed by page id.
+    /// * `temperature` - The minimum similarity a page needs to be returned.
+    /// * `window_size` - The maximum number of pages to return.
+    /// * `resolve_level` - Which part of the ranked results to return.
+    ///
+    /// # Returns
+    /// * `Vec<SearchResult>` - The selected pages with their similarity and matched passage, most similar first.
+    ///
+    pub fn resolve(
+        &self,
+        set: Vec<PageScore>,
+        temperature: f32,
+        window_size: usize,
+        resolve_level: ResolveLevel,
+    ) -> Vec<SearchResult> {
+        let ranked = self.rank(set, temperature, window_size, resolve_level, 0);
+        self.select_window(&ranked, window_size, resolve_level)
+    }
+
+    ///
+    /// Resolve the similarity set like `resolve`, but with the best pages reordered by maximal marginal relevance
+    /// so pages saying nearly the same thing as one ranked above them are pushed down.
+    ///
+    /// Redundancy is measured between the stored embeddings of the passages the pages matched on.
+    ///
+    /// # Arguments
+    /// * `set` - The similarity set, addressed by page id.
+    /// * `temperature` - The minimum similarity a page needs to be returned.
+    /// * `window_size` - The maximum number of pages to return.
+    /// * `resolve_level` - Which part of the diversified results to return.
+    /// * `params` - The balance of similarity against novelty, and how many of the best pages to reorder.
+    ///
+    /// # Returns
+    /// * `Vec<SearchResult>` - The selected pages with their similarity and matched passage, in diversified order.
+    ///
+    pub fn resolve_diverse(
+        &self,
+        set: Vec<PageScore>,
+        temperature: f32,
+        window_size: usize,
+        resolve_level: ResolveLevel,
+        params: MmrParams,
+    ) -> Vec<SearchResult> {
+        let ranked = self.rank(set, temperature, window_size, resolve_level, params.candidates);
+        self.select_window(&self.diversify(ranked, params), window_size, resolve_level)
+    }
+
+    ///
+    /// Rank the pages that clear the temperature, keeping at least `at_least` of them when that many clear it.
+    ///
+    fn rank(
+        &self,
+        set: Vec<PageScore>,
+        temperature: f32,
+        window_size: usize,
+        resolve_level: ResolveLevel,
+        at_least: usize,
+    ) -> Vec<PageScore> {
+        // All negative elements signals a complete dissimilarity and no matching is possible
+        let similarities: Vec<f32> = set.iter().map(|score| score.similarity).collect();
+        if self.all_are_negative(&similarities) {
+            return Vec::new();
+        }
+
+        let candidates: Vec<PageScore> = set
+            .into_iter()
+            .filter(|score| score.similarity >= temperature)
+            .collect();
+        // Only the levels reading from the front of the ranking can get away with a bounded heap
+        let k = match resolve_level {
+            ResolveLevel::First => 1,
+            ResolveLevel::To(steps) => steps.saturating_add(window_size),
+            ResolveLevel::Mid | ResolveLevel::Last => candidates.len(),
+        };
+        top_k(candidates, k.max(at_least))
+    }
+
+    fn select_window(
+        &self,
+        ranked: &[PageScore],
+        window_size: usize,
+        resolve_level: ResolveLevel,
+    ) -> Vec<SearchResult> {
+        let window = resolve_level.window(ranked.len(), window_size);
+        let offset = window.start;
+        ranked[window]
+            .iter()
+            .enumerate()
+            .filter_map(|(index, score)| {
+                Some(SearchResult {
+                    rank: offset + index + 1,
+                    page: self.page(score.id)?.clone(),
+                    similarity: score.similarity,
+                    passage: score.passage.and_then(|passage| self.passage(score.id, passage)),
+                })
+            })
+            .collect()
+    }
+
+    ///
+    /// Reorder the best of a ranking by maximal marginal relevance, the rest keep their place behind them.
+    ///
+    fn diversify(&self, mut ranked: Vec<PageScore>, params: MmrParams) -> Vec<PageScore> {
+        let rest = ranked.split_off(params.candidates.min(ranked.len()));
+        let vectors: Vec<Option<Cow<'_, [f32]>>> = ranked.iter().map(|score| self.matched_vector(score)).collect();
+        // Rows are unit normalized, so their dot product is their cosine similarity
+        let order = mmr(&ranked, params.lambda, |a, b| match (&vectors[a], &vectors[b]) {
+            (Some(a), Some(b)) => dot(a, b),
+            _ => 0.0,
+        });
+        order.into_iter().map(|position| ranked[position]).chain(rest).collect()
+    }
+
+    ///
+    /// The stored embedding of the passage a page matched on, its first passage for lexical scores.
+    ///
+    fn matched_vector(&self, score: &PageScore) -> Option<Cow<'_, [f32]>> {
+        let rows = self.page_embeddings.rows(score.id)?;
+        let row = rows.start + score.passage.unwrap_or(0);
+        rows.contains(&row).then(|| self.page_embeddings.vector(row))
+    }
+
+    ///
+    /// Check if all elements in the slice are negative.
+    ///
+    /// # Arguments
+    /// * `data` - The slice to check.
+    ///
+    /// # Returns
+    /// * `bool` - True if all elements are negative, false otherwise.
     ///
     pub fn all_are_negative(&self, data: &[f32]) -> bool {
         data.iter().all(|&x| x.is_sign_negative())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hashed::HashedEmbedder;
+    use crate::quantize::Quantization;
+    use std::sync::Arc;
+
+    const SIMILARITIES: [f32; 7] = [0.10, 0.90, 0.50, 0.70, -0.20, 0.30, 0.80];
+
+    fn engine() -> Engine {
+        let pages = (0..SIMILARITIES.len() as i64)
+            .map(|id| Page {
+                id,
+                name: format!("page {}", id),
+                body: format!("body {}", id),
+                link: String::new(),
+                ..Page::default()
+            })
+            .collect();
+        Engine::with_model(Corpus { pages }, Model::from_embedder(HashedEmbedder::default()))
+    }
+
+    fn scores() -> Vec<PageScore> {
+        SIMILARITIES
+            .iter()
+            .enumerate()
+            .map(|(id, &similarity)| PageScore {
+                id: id as i64,
+                similarity,
+                passage: None,
+            })
+            .collect()
+    }
+
+    // Ranks a page body by the page number it ends with, whatever the query
+    struct PageNumberEncoder;
+
+    impl CrossEncoder for PageNumberEncoder {
+        fn score(&self, _query: &str, texts: &[&str]) -> Result<Vec<f32>> {
+            Ok(texts
+                .iter()
+                .map(|text| text.rsplit(' ').next().and_then(|id| id.parse::<f32>().ok()).unwrap_or(0.0) / 10.0)
+                .collect())
+        }
+
+        fn model_id(&self) -> &str {
+            "page-number"
+        }
+    }
+
+    fn resolved_ids(resolve_level: ResolveLevel, temperature: f32, window_size: usize) -> Vec<i64> {
+        engine()
+            .resolve(scores(), temperature, window_size, resolve_level)
+            .iter()
+            .map(|result| result.page.id)
+            .collect()
+    }
+
+    #[test]
+    fn search_finds_the_page_sharing_the_query_terms() {
+        let mut engine = engine();
+        engine.build_embeddings().unwrap();
+        let scores = engine.search("body 3").unwrap();
+        assert_eq!(scores.len(), SIMILARITIES.len());
+        let resolved = engine.resolve(scores, 0.0, 3, ResolveLevel::To(0));
+        assert_eq!(resolved[0].page.id, 3);
+        assert!(resolved.windows(2).all(|pair| pair[0].similarity >= pair[1].similarity));
+    }
+
+    #[test]
+    fn hashless_caches_are_embedded_afresh_even_when_they_line_up() {
+        let mut engine = engine();
+        let dimension = engine.model.dimension();
+        let cache = EmbeddingCache {
+            model_id: engine.model.model_id().to_string(),
+            dimension,
+            corpus_hash: 0,
+            text_hashes: Vec::new(),
+            quantization: Quantization::F32,
+            full_precision: true,
+            fields: FieldStrategy::Body,
+            embeddings: vec![vec![0.0; dimension]; SIMILARITIES.len()],
+        };
+        let summary = engine.adopt(cache).unwrap();
+        assert_eq!((summary.reused, summary.recomputed, summary.removed), (0, 7, 7));
+        let scores = engine.search("body 3").unwrap();
+        assert_eq!(top_k(scores, 1)[0].id, 3);
+    }
+
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("docueyes-engine-{}-{}", std::process::id(), name))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn unchanged_indexes_are_mapped_and_changed_ones_say_why_not() {
+        let path = temp_path("mapped");
+        let mut built = engine();
+        built.build_embeddings().unwrap();
+        built.cache_embeddings(&path).unwrap();
+
+        let mut unchanged = engine();
+        let summary = unchanged.load_embeddings(&path).unwrap();
+        assert!(unchanged.embeddings_mapped());
+        assert_eq!(summary.mapping_error, None);
+        assert!(!summary.is_stale());
+
+        let mut changed = engine();
+        changed.corpus.pages[0].body = String::from("body changed");
+        let summary = changed.load_embeddings(&path).unwrap();
+        assert!(!changed.embeddings_mapped());
+        assert_eq!(summary.recomputed, 1);
+        assert!(summary.mapping_error.unwrap().contains("does not match the corpus"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn mapped_checksums_are_verified_only_on_request() {
+        let path = temp_path("verified");
+        let mut built = engine();
+        built.build_embeddings().unwrap();
+        built.cache_embeddings(&path).unwrap();
+        let mut bytes = std::fs::read(&path).unwrap();
+        *bytes.last_mut().unwrap() ^= 0x40;
+        std::fs::write(&path, bytes).unwrap();
+
+        let mut unverified = engine();
+        unverified.load_embeddings(&path).unwrap();
+        assert!(unverified.embeddings_mapped());
+
+        let mut verified = engine();
+        verified.set_verify_mapped(true);
+        let error = verified.load_embeddings(&path).unwrap_err().to_string();
+        assert!(error.contains("checksum"), "{}", error);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn refreshing_counts_removed_passages() {
+        let mut engine = engine();
+        engine.build_embeddings().unwrap();
+        let mut cache = engine.snapshot().unwrap();
+        // Two stale rows sharing one text still count as two removed passages
+        cache.text_hashes[0] = 1;
+        cache.text_hashes[1] = 1;
+        let summary = engine.adopt(cache).unwrap();
+        assert_eq!((summary.reused, summary.recomputed, summary.removed), (5, 2, 2));
+    }
+
+    #[test]
+    fn first_returns_only_the_best_match() {
+        assert_eq!(resolved_ids(ResolveLevel::First, 0.0, 5), vec![1]);
+    }
+
+    #[test]
+    fn mid_returns_a_window_around_the_median() {
+        // Ranked above the temperature: 1 (0.9), 6 (0.8), 3 (0.7), 2 (0.5), 5 (0.3), 0 (0.1)
+        assert_eq!(resolved_ids(ResolveLevel::Mid, 0.0, 2), vec![3, 2]);
+        assert_eq!(resolved_ids(ResolveLevel::Mid, 0.0, 3), vec![3, 2, 5]);
+    }
+
+    #[test]
+    fn last_returns_the_weakest_results_above_the_temperature() {
+        assert_eq!(resolved_ids(ResolveLevel::Last, 0.0, 2), vec![5, 0]);
+        assert_eq!(resolved_ids(ResolveLevel::Last, 0.4, 2), vec![3, 2]);
+    }
+
+    #[test]
+    fn to_advances_through_the_ranked_results() {
+        assert_eq!(resolved_ids(ResolveLevel::To(0), 0.0, 2), vec![1, 6]);
+        assert_eq!(resolved_ids(ResolveLevel::To(2), 0.0, 2), vec![3, 2]);
+        assert_eq!(resolved_ids(ResolveLevel::To(5), 0.0, 2), vec![0]);
+        assert!(resolved_ids(ResolveLevel::To(9), 0.0, 2).is_empty());
+    }
+
+    #[test]
+    fn windows_never_exceed_the_ranked_results() {
+        assert_eq!(resolved_ids(ResolveLevel::Mid, 0.0, 50).len(), 6);
+        assert_eq!(resolved_ids(ResolveLevel::Last, 0.0, 50).len(), 6);
+        assert!(resolved_ids(ResolveLevel::First, 0.95, 5).is_empty());
+    }
+
+    #[test]
+    fn ranks_count_from_the_top_of_the_ranking_not_the_window() {
+        let ranks = |resolve_level| {
+            engine()
+                .resolve(scores(), 0.0, 2, resolve_level)
+                .iter()
+                .map(|result| (result.rank, result.page.id))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ranks(ResolveLevel::To(0)), vec![(1, 1), (2, 6)]);
+        assert_eq!(ranks(ResolveLevel::To(2)), vec![(3, 3), (4, 2)]);
+        assert_eq!(ranks(ResolveLevel::Mid), vec![(3, 3), (4, 2)]);
+        assert_eq!(ranks(ResolveLevel::Last), vec![(5, 5), (6, 0)]);
+    }
+
+    #[test]
+    fn search_results_serialize_their_score_beside_the_page() {
+        let results = engine().resolve(scores(), 0.0, 1, ResolveLevel::To(0));
+        let json = serde_json::to_value(&results[0]).unwrap();
+        let fields: Vec<&str> = json.as_object().unwrap().keys().map(String::as_str).collect();
+        assert_eq!(fields, vec!["body", "id", "link", "name", "rank", "similarity"]);
+        assert_eq!((json["id"].as_i64(), json["rank"].as_u64()), (Some(1), Some(1)));
+        assert!((json["similarity"].as_f64().unwrap() - 0.9).abs() < 1e-6);
+        // The corpus page itself carries no score
+        assert!(serde_json::to_value(&results[0].page).unwrap().get("similarity").is_none());
+    }
+
+    #[test]
+    fn rerank_reorders_only_the_best_candidates() {
+        let mut engine = engine();
+        assert!(engine.rerank("query", scores(), RerankParams::default()).is_err());
+        engine.set_reranker(PageNumberEncoder);
+        // The best three by similarity are 1, 6 and 3, rescored as 0.1, 0.6 and 0.3
+        let reranked = engine.rerank("query", scores(), RerankParams { candidates: 3 }).unwrap();
+        let ids: Vec<i64> = reranked.iter().map(|score| score.id).collect();
+        assert_eq!(ids, vec![6, 3, 1]);
+        assert_eq!(reranked[0].similarity, 0.6);
+        assert!(engine.rerank("query", scores(), RerankParams { candidates: 0 }).unwrap().is_empty());
+    }
+
+    #[test]
+    fn diversifying_without_embeddings_keeps_the_ranking() {
+        let engine = engine();
+        let params = MmrParams {
+            lambda: 0.5,
+            candidates: 3,
+        };
+        let ids: Vec<i64> = engine
+            .resolve_diverse(scores(), 0.0, 5, ResolveLevel::To(0), params)
+            .iter()
+            .map(|result| result.page.id)
+            .collect();
+        assert_eq!(ids, resolved_ids(ResolveLevel::To(0), 0.0, 5));
+    }
+
+    #[test]
+    fn diversifying_a_fused_ranking_keeps_related_pages_ahead_of_unrelated_ones() {
+        // Pages 0 and 1 are duplicates, 2 is related to them and 3 is unrelated to the query
+        let bodies = ["error code lookup table", "error code lookup table", "error code meanings", "garden flowers"];
+        let pages = bodies
+            .iter()
+            .enumerate()
+            .map(|(id, body)| Page {
+                id: id as i64,
+                body: body.to_string(),
+                ..Page::default()
+            })
+            .collect();
+        let mut engine = Engine::with_model(Corpus { pages }, Model::from_embedder(HashedEmbedder::default()));
+        engine.build_embeddings().unwrap();
+        engine.build_lexical_index(Bm25Params::default());
+        let fused: Vec<PageScore> = engine
+            .search_hybrid("error code lookup", Fusion::default(), 0.0)
+            .unwrap()
+            .iter()
+            .map(HybridScore::page_score)
+            .collect();
+        let params = MmrParams {
+            lambda: 0.7,
+            candidates: 4,
+        };
+        let ids: Vec<i64> = engine
+            .resolve_diverse(fused, 0.0, 4, ResolveLevel::To(0), params)
+            .iter()
+            .map(|result| result.page.id)
+            .collect();
+        assert_eq!(ids.len(), 4);
+        assert_eq!(ids[3], 3);
+    }
+
+    // Embeds every text as its length, recording the texts it was given
+    struct RecordingEmbedder(Arc<Mutex<Vec<String>>>);
+
+    impl crate::model::Embedder for RecordingEmbedder {
+        fn encode(&self, texts: &[&str]) -> Result<Vec<Embeddings>> {
+            self.0.lock().unwrap().extend(texts.iter().map(|text| text.to_string()));
+            Ok(texts.iter().map(|text| vec![text.len() as f32]).collect())
+        }
+
+        fn dimension(&self) -> usize {
+            1
+        }
+
+        fn model_id(&self) -> &str {
+            "recording"
+        }
+    }
+
+    #[test]
+    fn queries_are_embedded_as_asked_and_cached_normalized() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let model = Model::from_embedder(RecordingEmbedder(Arc::clone(&seen)));
+        let engine = Engine::with_model(Corpus { pages: Vec::new() }, model);
+        let embedded = engine.embed_queries(&["What IS  CRM", "what is crm", "Other"]).unwrap();
+        let mut texts = seen.lock().unwrap().clone();
+        texts.sort();
+        assert_eq!(texts, vec!["Other", "What IS  CRM"]);
+        assert_eq!(embedded, vec![vec![12.0], vec![12.0], vec![5.0]]);
+        // A query normalizing to a cached key is not embedded again
+        assert_eq!(engine.embed_query(" WHAT is crm ").unwrap(), vec![12.0]);
+        assert_eq!(seen.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn ann_graphs_are_refused_over_rechunked_or_refielded_passages() {
+        let path = temp_path("graph");
+        let mut built = engine();
+        built.build_embeddings().unwrap();
+        built.build_ann_index(HnswParams::default());
+        built.cache_ann_index(&path).unwrap();
+
+        let mut same = engine();
+        same.build_embeddings().unwrap();
+        assert!(same.load_ann_index(&path, HnswParams::default()).is_ok());
+
+        // Templating the page names in keeps the corpus and the passage count but changes every embedding
+        let mut refielded = engine();
+        refielded.set_fields(FieldStrategy::Template);
+        refielded.build_embeddings().unwrap();
+        assert_eq!(refielded.passage_count(), built.passage_count());
+        let error = refielded.load_ann_index(&path, HnswParams::default()).unwrap_err().to_string();
+        assert!(error.contains("different embeddings"), "{}", error);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn batch_search_matches_searching_one_by_one() {
+        let mut engine = engine();
+        engine.build_embeddings().unwrap();
+        let queries = ["body 3", "page 5", "body 3"];
+        let batch = engine.search_batch(&queries).unwrap();
+        assert_eq!(batch.len(), queries.len());
+        for (query, scores) in queries.iter().zip(batch) {
+            let ids = |scores: Vec<PageScore>| top_k(scores, 3).iter().map(|score| score.id).collect::<Vec<_>>();
+            assert_eq!(ids(scores), ids(engine.search(query).unwrap()));
+        }
+    }
+
+    #[test]
+    fn similar_pages_never_include_the_page_itself() {
+        let mut engine = engine();
+        assert!(engine.similar_to(3, 2).is_err());
+        engine.build_embeddings().unwrap();
+        let similar = engine.similar_to(3, 2).unwrap();
+        assert_eq!(similar.len(), 2);
+        assert!(similar.iter().all(|score| score.id != 3));
+        assert!(engine.similar_to(99, 2).is_err());
+    }
+}
@@ -0,0 +1,98 @@
+/*
+ *
+ * Fields decides which parts of a page are embedded: the body alone, the name and body templated into one text,
+ * or the name and body embedded separately and blended with query time weights.
+ *
+ */
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+// Placed between the page name and each passage of its body by the template strategy
+pub const TEMPLATE_SEPARATOR: &str = ": ";
+
+pub const DEFAULT_NAME_WEIGHT: f32 = 0.3;
+
+///
+/// Which page fields are embedded and how.
+///
+/// # Variants
+/// * `Body` - Only the body, every passage on its own
+/// * `Template` - Every passage prefixed with the page name, as `name: passage`
+/// * `Separate` - The body passages and the name embedded on their own, blended by weight when searching
+///
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FieldStrategy {
+    #[default]
+    Body,
+    Template,
+    Separate,
+}
+
+impl FieldStrategy {
+    pub const ALL: [FieldStrategy; 3] = [FieldStrategy::Body, FieldStrategy::Template, FieldStrategy::Separate];
+
+    ///
+    /// The name used to select the strategy in configuration.
+    ///
+    pub fn name(&self) -> &'static str {
+        match self {
+            FieldStrategy::Body => "body",
+            FieldStrategy::Template => "template",
+            FieldStrategy::Separate => "separate",
+        }
+    }
+
+    ///
+    /// The byte recording the strategy in the binary index.
+    ///
+    pub(crate) fn tag(&self) -> u8 {
+        match self {
+            FieldStrategy::Body => 0,
+            FieldStrategy::Template => 1,
+            FieldStrategy::Separate => 2,
+        }
+    }
+
+    pub(crate) fn from_tag(tag: u8) -> Option<Self> {
+        FieldStrategy::ALL.into_iter().find(|strategy| strategy.tag() == tag)
+    }
+}
+
+impl fmt::Display for FieldStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for FieldStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        FieldStrategy::ALL
+            .into_iter()
+            .find(|strategy| strategy.name().eq_ignore_ascii_case(name))
+            .ok_or_else(|| {
+                let known: Vec<&str> = FieldStrategy::ALL.iter().map(|strategy| strategy.name()).collect();
+                anyhow::anyhow!("Unknown field strategy {}, expected one of {}", name, known.join(", "))
+            })
+    }
+}
+
+///
+/// Blend the name and body similarity of a page.
+///
+/// # Arguments
+/// * `body` - Similarity of the body, already aggregated over its passages.
+/// * `name` - Similarity of the name.
+/// * `name_weight` - Share of the name in the blend, clamped to `[0, 1]`, the body takes the rest.
+///
+/// # Returns
+/// * `f32` - The blended similarity.
+///
+pub fn blend(body: f32, name: f32, name_weight: f32) -> f32 {
+    let name_weight = name_weight.clamp(0.0, 1.0);
+    name_weight * name + (1.0 - name_weight) * body
+}
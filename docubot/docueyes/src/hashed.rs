@@ -0,0 +1,137 @@
+/*
+ *
+ * Hashed is a deterministic, dependency free Embedder built on a hashed bag-of-words.
+ * It needs no model download, which makes it useful for CI and offline machines.
+ *
+ */
+
+use crate::corpus::Embeddings;
+use crate::model::Embedder;
+use anyhow::Result;
+
+pub const DEFAULT_HASHED_DIMENSION: usize = 384;
+
+///
+/// HashedEmbedder maps every lowercase alphanumeric token onto a signed bucket of a fixed size vector.
+///
+pub struct HashedEmbedder {
+    dimension: usize,
+    model_id: String,
+}
+
+impl HashedEmbedder {
+    ///
+    /// Create a new instance of the HashedEmbedder struct.
+    ///
+    /// # Arguments
+    /// * `dimension` - The number of buckets in each embedding, must be non zero.
+    ///
+    /// # Returns
+    /// A new instance of the HashedEmbedder struct.
+    ///
+    pub fn new(dimension: usize) -> Self {
+        assert!(dimension > 0, "Embedding dimension must be non zero");
+        HashedEmbedder {
+            dimension,
+            model_id: format!("hashed-bow-{}", dimension),
+        }
+    }
+
+    ///
+    /// Embed a single text as a unit length vector, or all zeros if the text holds no tokens.
+    ///
+    fn embed(&self, text: &str) -> Embeddings {
+        let mut embedding = vec![0.0f32; self.dimension];
+        for token in tokenize(text) {
+            let hash = fnv1a(token.as_bytes());
+            let bucket = (hash % self.dimension as u64) as usize;
+            // The top bit picks the sign so unrelated tokens sharing a bucket tend to cancel out
+            let sign = if hash >> 63 == 0 { 1.0 } else { -1.0 };
+            embedding[bucket] += sign;
+        }
+
+        let norm: f32 = embedding.iter().map(|x| x * x).sum::<f32>().sqrt();
+        if norm > 0.0 {
+            embedding.iter_mut().for_each(|x| *x /= norm);
+        }
+        embedding
+    }
+}
+
+impl Default for HashedEmbedder {
+    fn default() -> Self {
+        HashedEmbedder::new(DEFAULT_HASHED_DIMENSION)
+    }
+}
+
+impl Embedder for HashedEmbedder {
+    fn encode(&self, texts: &[&str]) -> Result<Vec<Embeddings>> {
+        Ok(texts.iter().map(|text| self.embed(text)).collect())
+    }
+
+    fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    fn model_id(&self) -> &str {
+        &self.model_id
+    }
+
+    fn count_tokens(&self, texts: &[&str]) -> Result<Vec<usize>> {
+        Ok(texts.iter().map(|text| tokenize(text).count()).collect())
+    }
+}
+
+///
+/// Split text into lowercase alphanumeric tokens.
+///
+fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|token| !token.is_empty())
+        .map(|token| token.to_lowercase())
+}
+
+///
+/// 64 bit FNV-1a, used instead of the std hasher because its output is stable across Rust releases.
+///
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn embeddings_are_deterministic_and_sized_to_the_dimension() {
+        let embedder = HashedEmbedder::new(64);
+        let first = embedder.encode(&["Salesforce uses AI", ""]).unwrap();
+        let second = embedder.encode(&["Salesforce uses AI", ""]).unwrap();
+        assert_eq!(first, second);
+        assert!(first.iter().all(|embedding| embedding.len() == 64));
+        assert_eq!(embedder.dimension(), 64);
+        assert_eq!(embedder.model_id(), "hashed-bow-64");
+    }
+
+    #[test]
+    fn embeddings_are_unit_length_unless_the_text_has_no_tokens() {
+        let embedder = HashedEmbedder::default();
+        let embeddings = embedder.encode(&["the quick brown fox", "  ,;  "]).unwrap();
+        let norm = |embedding: &Embeddings| embedding.iter().map(|x| x * x).sum::<f32>().sqrt();
+        assert!((norm(&embeddings[0]) - 1.0).abs() < 1e-6);
+        assert_eq!(norm(&embeddings[1]), 0.0);
+    }
+
+    #[test]
+    fn tokens_ignore_case_and_punctuation() {
+        let embedder = HashedEmbedder::default();
+        let embeddings = embedder.encode(&["Hello, World!", "hello world"]).unwrap();
+        assert_eq!(embeddings[0], embeddings[1]);
+        assert_eq!(embedder.count_tokens(&["Hello, World!"]).unwrap(), vec![2]);
+    }
+}
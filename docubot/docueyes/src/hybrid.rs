@@ -0,0 +1,225 @@
+/*
+ *
+ * Hybrid fuses the semantic (cosine) and lexical (BM25) rankings of a query into a single ranking.
+ *
+ */
+
+use crate::engine::PageScore;
+use std::collections::BTreeMap;
+
+pub const DEFAULT_RRF_K: f32 = 60.0;
+
+///
+/// How the semantic and lexical rankings are combined.
+///
+/// # Variants
+/// * `ReciprocalRank` - Reciprocal rank fusion, summing `1 / (k + rank)` over both rankings
+/// * `WeightedBlend` - Min-max normalize both scores and blend them, `semantic_weight` of 1 is purely semantic
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fusion {
+    ReciprocalRank { k: f32 },
+    WeightedBlend { semantic_weight: f32 },
+}
+
+impl Default for Fusion {
+    fn default() -> Self {
+        Fusion::ReciprocalRank { k: DEFAULT_RRF_K }
+    }
+}
+
+///
+/// Which scorer a search runs through.
+///
+/// # Variants
+/// * `Semantic` - Cosine similarity between embeddings
+/// * `Lexical` - BM25 over page names and bodies
+/// * `Hybrid` - Both, fused into one ranking
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SearchMode {
+    Semantic,
+    Lexical,
+    Hybrid(Fusion),
+}
+
+///
+/// A fused score along with the component scores it was computed from, for tuning the blend.
+///
+/// # Fields
+/// * `id` - The `Page.id` the score belongs to
+/// * `score` - The fused score
+/// * `semantic` - The cosine similarity, if the page was a semantic candidate
+/// * `lexical` - The BM25 score, if the page matched a query term
+/// * `passage` - The best matching passage of the page, if it was a semantic candidate
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HybridScore {
+    pub id: i64,
+    pub score: f32,
+    pub semantic: Option<f32>,
+    pub lexical: Option<f32>,
+    pub passage: Option<usize>,
+}
+
+impl HybridScore {
+    ///
+    /// The fused score in the form `Engine::resolve` ranks by.
+    ///
+    pub fn page_score(&self) -> PageScore {
+        PageScore {
+            id: self.id,
+            similarity: self.score,
+            passage: self.passage,
+        }
+    }
+}
+
+///
+/// Fuse a semantic and a lexical result set into one.
+///
+/// # Arguments
+/// * `semantic` - Cosine similarities of the semantic candidates.
+/// * `lexical` - BM25 scores of the pages matching a query term.
+/// * `fusion` - How to combine the two.
+///
+/// # Returns
+/// * `Vec<HybridScore>` - One score per page present in either set, ordered by page id.
+///
+pub fn fuse(semantic: &[PageScore], lexical: &[PageScore], fusion: Fusion) -> Vec<HybridScore> {
+    let mut fused: BTreeMap<i64, HybridScore> = BTreeMap::new();
+    for score in semantic {
+        let hybrid = fused.entry(score.id).or_insert_with(|| empty(score.id));
+        hybrid.semantic = Some(score.similarity);
+        hybrid.passage = score.passage;
+    }
+    for score in lexical {
+        fused.entry(score.id).or_insert_with(|| empty(score.id)).lexical = Some(score.similarity);
+    }
+
+    match fusion {
+        Fusion::ReciprocalRank { k } => {
+            for ranking in [semantic, lexical] {
+                for (rank, score) in rank_order(ranking).into_iter().enumerate() {
+                    if let Some(hybrid) = fused.get_mut(&score.id) {
+                        hybrid.score += 1.0 / (k + rank as f32 + 1.0);
+                    }
+                }
+            }
+        }
+        Fusion::WeightedBlend { semantic_weight } => {
+            let semantic_weight = semantic_weight.clamp(0.0, 1.0);
+            let semantic_range = range(semantic);
+            let lexical_range = range(lexical);
+            for hybrid in fused.values_mut() {
+                let semantic = hybrid.semantic.map(|s| normalize(s, semantic_range)).unwrap_or(0.0);
+                let lexical = hybrid.lexical.map(|s| normalize(s, lexical_range)).unwrap_or(0.0);
+                hybrid.score = semantic_weight * semantic + (1.0 - semantic_weight) * lexical;
+            }
+        }
+    }
+
+    fused.into_values().collect()
+}
+
+fn empty(id: i64) -> HybridScore {
+    HybridScore {
+        id,
+        score: 0.0,
+        semantic: None,
+        lexical: None,
+        passage: None,
+    }
+}
+
+///
+/// Sort a result set best first, using the same tie breaking as `Engine::resolve`.
+///
+fn rank_order(scores: &[PageScore]) -> Vec<PageScore> {
+    let mut ranked = scores.to_vec();
+    ranked.sort_by(|a, b| b.cmp(a));
+    ranked
+}
+
+pub(crate) fn range(scores: &[PageScore]) -> (f32, f32) {
+    scores.iter().fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), score| {
+        (min.min(score.similarity), max.max(score.similarity))
+    })
+}
+
+///
+/// Min-max normalize into `[0, 1]`, a set where every score is equal normalizes to 1.
+///
+pub(crate) fn normalize(score: f32, (min, max): (f32, f32)) -> f32 {
+    if max > min { (score - min) / (max - min) } else { 1.0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scores(scores: &[(i64, f32)]) -> Vec<PageScore> {
+        scores
+            .iter()
+            .map(|&(id, similarity)| PageScore {
+                id,
+                similarity,
+                passage: Some(id as usize),
+            })
+            .collect()
+    }
+
+    fn fused(fusion: Fusion) -> Vec<HybridScore> {
+        // Page 1 is found by both sides, page 2 only semantically and page 3 only lexically
+        let semantic = scores(&[(1, 0.8), (2, 0.9)]);
+        let lexical = scores(&[(3, 12.0), (1, 4.0)]);
+        fuse(&semantic, &lexical, fusion)
+    }
+
+    fn score(fused: &[HybridScore], id: i64) -> f32 {
+        fused.iter().find(|hybrid| hybrid.id == id).unwrap().score
+    }
+
+    #[test]
+    fn reciprocal_rank_sums_over_both_rankings() {
+        let fused = fused(Fusion::ReciprocalRank { k: 60.0 });
+        assert_eq!(fused.iter().map(|hybrid| hybrid.id).collect::<Vec<_>>(), vec![1, 2, 3]);
+        assert!((score(&fused, 1) - (1.0 / 62.0 + 1.0 / 62.0)).abs() < 1e-6);
+        assert!((score(&fused, 2) - 1.0 / 61.0).abs() < 1e-6);
+        assert!((score(&fused, 3) - 1.0 / 61.0).abs() < 1e-6);
+        // Second on both sides beats first on one
+        assert!(score(&fused, 1) > score(&fused, 2));
+    }
+
+    #[test]
+    fn blending_min_max_normalizes_each_side() {
+        let semantic_only = fused(Fusion::WeightedBlend { semantic_weight: 1.0 });
+        assert_eq!((score(&semantic_only, 1), score(&semantic_only, 2), score(&semantic_only, 3)), (0.0, 1.0, 0.0));
+        let lexical_only = fused(Fusion::WeightedBlend { semantic_weight: 0.0 });
+        assert_eq!((score(&lexical_only, 1), score(&lexical_only, 2), score(&lexical_only, 3)), (0.0, 0.0, 1.0));
+        let even = fused(Fusion::WeightedBlend { semantic_weight: 0.5 });
+        assert_eq!((score(&even, 2), score(&even, 3)), (0.5, 0.5));
+        // Weights past the ends are clamped
+        let clamped = fused(Fusion::WeightedBlend { semantic_weight: 7.0 });
+        assert_eq!(score(&clamped, 2), 1.0);
+    }
+
+    #[test]
+    fn a_side_of_equal_scores_normalizes_to_one() {
+        let fused = fuse(&scores(&[(1, 0.4), (2, 0.4)]), &[], Fusion::WeightedBlend { semantic_weight: 1.0 });
+        assert!(fused.iter().all(|hybrid| hybrid.score == 1.0));
+    }
+
+    #[test]
+    fn components_are_kept_only_for_the_side_that_found_the_page() {
+        let fused = fused(Fusion::default());
+        let component = |id| fused.iter().find(|hybrid| hybrid.id == id).unwrap();
+        assert_eq!((component(1).semantic, component(1).lexical), (Some(0.8), Some(4.0)));
+        assert_eq!((component(2).semantic, component(2).lexical), (Some(0.9), None));
+        assert_eq!((component(3).semantic, component(3).lexical), (None, Some(12.0)));
+        // Passages only come from the semantic side
+        assert_eq!(component(1).passage, Some(1));
+        assert_eq!(component(3).passage, None);
+        assert_eq!(component(3).page_score().similarity, component(3).score);
+    }
+}
@@ -0,0 +1,375 @@
+/*
+ *
+ * Lexical is a BM25 inverted index over page names and bodies.
+ * It catches exact identifiers, error codes and product names that embeddings tend to blur, and needs no model.
+ *
+ */
+
+use crate::corpus::Corpus;
+use crate::engine::PageScore;
+use crate::hashed::fnv1a;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+
+pub const LEXICAL_INDEX_VERSION: u32 = 1;
+
+// Common English words that carry no meaning for ranking
+const STOPWORDS: &[&str] = &[
+    "a", "about", "an", "and", "are", "as", "at", "be", "but", "by", "can", "do", "does", "for", "from", "has",
+    "have", "how", "i", "if", "in", "into", "is", "it", "its", "like", "me", "my", "no", "not", "of", "on", "or",
+    "so", "such", "that", "the", "their", "then", "there", "these", "they", "this", "to", "was", "we", "what",
+    "when", "where", "which", "who", "why", "will", "with", "you", "your",
+];
+
+///
+/// Tuning parameters of the BM25 scoring function.
+///
+/// # Fields
+/// * `k1` - Term frequency saturation, higher values let repeated terms count for longer
+/// * `b` - Length normalization, 0 ignores page length and 1 fully normalizes by it
+///
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Bm25Params {
+    pub k1: f32,
+    pub b: f32,
+}
+
+impl Default for Bm25Params {
+    fn default() -> Self {
+        Bm25Params { k1: 1.2, b: 0.75 }
+    }
+}
+
+///
+/// A single occurrence list entry, the page position in the index and how often the term appears there.
+///
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct Posting {
+    doc: u32,
+    term_frequency: u32,
+}
+
+///
+/// Bm25Index is an inverted index from terms to the pages containing them.
+///
+/// # Fields
+/// * `version` - Layout version of the persisted index
+/// * `params` - The BM25 parameters the index scores with
+/// * `corpus_hash` - Hash of the page ids, names and bodies the index was built from
+/// * `page_ids` - The `Page.id` of every indexed page, by position
+/// * `page_lengths` - Number of indexed terms in every page, by position
+/// * `average_length` - Mean of `page_lengths`
+/// * `postings` - The pages each term occurs in
+///
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Bm25Index {
+    version: u32,
+    params: Bm25Params,
+    corpus_hash: u64,
+    page_ids: Vec<i64>,
+    page_lengths: Vec<u32>,
+    average_length: f32,
+    postings: HashMap<String, Vec<Posting>>,
+}
+
+impl Bm25Index {
+    ///
+    /// Build an index over the name and body of every page in the corpus.
+    ///
+    /// # Arguments
+    /// * `corpus` - The corpus to index.
+    /// * `params` - The BM25 parameters to score with.
+    ///
+    /// # Returns
+    /// * `Bm25Index` - The built index.
+    ///
+    pub fn build(corpus: &Corpus, params: Bm25Params) -> Self {
+        let mut page_ids = Vec::with_capacity(corpus.pages.len());
+        let mut page_lengths = Vec::with_capacity(corpus.pages.len());
+        let mut postings: HashMap<String, Vec<Posting>> = HashMap::new();
+
+        for (doc, page) in corpus.pages.iter().enumerate() {
+            let mut term_frequencies: HashMap<String, u32> = HashMap::new();
+            let mut length = 0;
+            for term in tokenize(&page.name).into_iter().chain(tokenize(&page.body)) {
+                *term_frequencies.entry(term).or_insert(0) += 1;
+                length += 1;
+            }
+            for (term, term_frequency) in term_frequencies {
+                postings.entry(term).or_default().push(Posting {
+                    doc: doc as u32,
+                    term_frequency,
+                });
+            }
+            page_ids.push(page.id);
+            page_lengths.push(length);
+        }
+
+        let average_length = if page_lengths.is_empty() {
+            0.0
+        } else {
+            page_lengths.iter().sum::<u32>() as f32 / page_lengths.len() as f32
+        };
+
+        Bm25Index {
+            version: LEXICAL_INDEX_VERSION,
+            params,
+            corpus_hash: lexical_hash(corpus),
+            page_ids,
+            page_lengths,
+            average_length,
+            postings,
+        }
+    }
+
+    ///
+    /// The BM25 parameters this index scores with.
+    ///
+    pub fn params(&self) -> Bm25Params {
+        self.params
+    }
+
+    ///
+    /// Whether the index was built from exactly this corpus.
+    ///
+    pub fn matches(&self, corpus: &Corpus) -> bool {
+        self.corpus_hash == lexical_hash(corpus)
+    }
+
+    ///
+    /// Score every page containing at least one query term.
+    ///
+    /// # Arguments
+    /// * `query` - The query to score pages against.
+    ///
+    /// # Returns
+    /// * `Vec<PageScore>` - The BM25 score of every matching page, in corpus order. Scores are unbounded and not comparable to cosine similarities.
+    ///
+    pub fn search(&self, query: &str) -> Vec<PageScore> {
+        let mut terms = tokenize(query);
+        terms.sort();
+        terms.dedup();
+
+        let page_count = self.page_ids.len() as f32;
+        let Bm25Params { k1, b } = self.params;
+        let mut scores: HashMap<u32, f32> = HashMap::new();
+        for term in &terms {
+            let Some(postings) = self.postings.get(term) else {
+                continue;
+            };
+            let document_frequency = postings.len() as f32;
+            let idf = ((page_count - document_frequency + 0.5) / (document_frequency + 0.5) + 1.0).ln();
+            for posting in postings {
+                let term_frequency = posting.term_frequency as f32;
+                let length_ratio = if self.average_length > 0.0 {
+                    self.page_lengths[posting.doc as usize] as f32 / self.average_length
+                } else {
+                    1.0
+                };
+                let saturation = term_frequency * (k1 + 1.0) / (term_frequency + k1 * (1.0 - b + b * length_ratio));
+                *scores.entry(posting.doc).or_insert(0.0) += idf * saturation;
+            }
+        }
+
+        let mut matches: Vec<(u32, f32)> = scores.into_iter().collect();
+        matches.sort_by_key(|(doc, _)| *doc);
+        matches
+            .into_iter()
+            .map(|(doc, similarity)| PageScore {
+                id: self.page_ids[doc as usize],
+                similarity,
+                passage: None,
+            })
+            .collect()
+    }
+
+    ///
+    /// Write the index to a JSON file.
+    ///
+    /// # Arguments
+    /// * `path` - The path to the file.
+    ///
+    /// # Returns
+    /// * `Result<()>` - The result of the operation.
+    ///
+    pub fn save(&self, path: &str) -> Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer(&mut writer, self)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    ///
+    /// Read an index from a JSON file.
+    ///
+    /// # Arguments
+    /// * `path` - The path to the file.
+    ///
+    /// # Returns
+    /// * `Result<Bm25Index>` - The index.
+    ///
+    /// # Errors
+    /// * `Error` - If the file is unreadable or was written by an incompatible version.
+    ///
+    pub fn load(path: &str) -> Result<Self> {
+        let index: Bm25Index = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+        if index.version != LEXICAL_INDEX_VERSION {
+            return Err(anyhow::anyhow!(
+                "{} has lexical index version {} but version {} is expected",
+                path,
+                index.version,
+                LEXICAL_INDEX_VERSION
+            ));
+        }
+        Ok(index)
+    }
+}
+
+///
+/// Split text into lowercase terms, keeping underscores so API names survive, and dropping stopwords.
+///
+/// # Arguments
+/// * `text` - The text to tokenize.
+///
+/// # Returns
+/// * `Vec<String>` - The terms in order of appearance.
+///
+pub fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .filter(|token| !token.is_empty())
+        .map(|token| token.to_lowercase())
+        .filter(|token| !STOPWORDS.contains(&token.as_str()))
+        .collect()
+}
+
+///
+/// Hash everything the lexical index is built from, so a stale index on disk can be detected.
+///
+fn lexical_hash(corpus: &Corpus) -> u64 {
+    let mut bytes = Vec::new();
+    for page in &corpus.pages {
+        bytes.extend_from_slice(&page.id.to_le_bytes());
+        for field in [&page.name, &page.body] {
+            bytes.extend_from_slice(&(field.len() as u64).to_le_bytes());
+            bytes.extend_from_slice(field.as_bytes());
+        }
+    }
+    fnv1a(&bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::corpus::Page;
+
+    fn corpus(bodies: &[&str]) -> Corpus {
+        let pages = bodies
+            .iter()
+            .enumerate()
+            .map(|(id, body)| Page {
+                id: id as i64 + 10,
+                body: body.to_string(),
+                ..Page::default()
+            })
+            .collect();
+        Corpus { pages }
+    }
+
+    fn score(index: &Bm25Index, query: &str, id: i64) -> f32 {
+        index
+            .search(query)
+            .iter()
+            .find(|score| score.id == id)
+            .map_or(0.0, |score| score.similarity)
+    }
+
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("docueyes-lexical-{}-{}", std::process::id(), name))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn tokens_are_lowercase_keep_underscores_and_drop_stopwords() {
+        assert_eq!(tokenize("What is the ERR_42 in My_Api?"), vec!["err_42", "my_api"]);
+        assert!(tokenize(" , . ").is_empty());
+    }
+
+    #[test]
+    fn scores_follow_the_bm25_formula() {
+        let index = Bm25Index::build(&corpus(&["apple", "banana"]), Bm25Params::default());
+        let scores = index.search("apple");
+        assert_eq!(scores.len(), 1);
+        assert_eq!(scores[0].id, 10);
+        // One of two pages holds the term once at average length, so the score is the idf ln(2)
+        assert!((scores[0].similarity - 2f32.ln()).abs() < 1e-6);
+        assert!(index.search("cherry the").is_empty());
+    }
+
+    #[test]
+    fn rare_terms_outweigh_common_ones() {
+        let index = Bm25Index::build(&corpus(&["error code", "error page", "error log"]), Bm25Params::default());
+        assert!(score(&index, "code", 10) > score(&index, "error", 10));
+    }
+
+    #[test]
+    fn k1_saturates_repeated_terms() {
+        let pages = corpus(&["api api api api other", "api other other other other", "unrelated"]);
+        let gain = |k1| {
+            let index = Bm25Index::build(&pages, Bm25Params { k1, b: 0.0 });
+            score(&index, "api", 10) / score(&index, "api", 11)
+        };
+        assert!(gain(0.1) < gain(1.2));
+        assert!(gain(1.2) < gain(100.0));
+        assert!((gain(0.0) - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn b_normalizes_by_page_length() {
+        let pages = corpus(&["api", "api filler filler filler filler filler filler", "unrelated"]);
+        let ignoring_length = Bm25Index::build(&pages, Bm25Params { k1: 1.2, b: 0.0 });
+        assert_eq!(score(&ignoring_length, "api", 10), score(&ignoring_length, "api", 11));
+        let normalized = Bm25Index::build(&pages, Bm25Params { k1: 1.2, b: 1.0 });
+        assert!(score(&normalized, "api", 10) > score(&normalized, "api", 11));
+    }
+
+    #[test]
+    fn names_are_indexed_with_bodies() {
+        let mut pages = corpus(&["body text", "body text"]);
+        pages.pages[1].name = "Pricing".to_string();
+        let index = Bm25Index::build(&pages, Bm25Params::default());
+        let scores = index.search("pricing");
+        assert_eq!(scores.len(), 1);
+        assert_eq!(scores[0].id, 11);
+    }
+
+    #[test]
+    fn saved_indexes_load_and_match_only_their_corpus() {
+        let path = temp_path("round-trip.json");
+        let pages = corpus(&["apple pie", "banana bread"]);
+        let index = Bm25Index::build(&pages, Bm25Params { k1: 1.5, b: 0.5 });
+        index.save(&path).unwrap();
+        let loaded = Bm25Index::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        let loaded = loaded.unwrap();
+        assert_eq!(loaded.params(), Bm25Params { k1: 1.5, b: 0.5 });
+        assert_eq!(loaded.search("bread")[0].similarity, index.search("bread")[0].similarity);
+        assert!(loaded.matches(&pages));
+        assert!(!loaded.matches(&corpus(&["apple pie", "banana cake"])));
+    }
+
+    #[test]
+    fn indexes_of_another_version_are_rejected() {
+        let path = temp_path("version.json");
+        let mut index = Bm25Index::build(&corpus(&["apple"]), Bm25Params::default());
+        index.version = LEXICAL_INDEX_VERSION + 1;
+        index.save(&path).unwrap();
+        let loaded = Bm25Index::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(loaded.unwrap_err().to_string().contains("version"));
+    }
+}
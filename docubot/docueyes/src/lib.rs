@@ -3,9 +3,26 @@
  * Author: Gabriel Tower
  * Date: 2025-04-09
  */
+pub mod ann;
+#[cfg(feature = "bert")]
+pub mod bert;
+pub mod cache;
+pub mod chunk;
 pub mod corpus;
+#[cfg(feature = "rerank")]
+pub mod cross_encoder;
 pub mod engine;
+pub mod fields;
+pub mod hashed;
+pub mod hybrid;
+pub mod lexical;
+mod mapped;
+pub mod mmr;
 pub mod model;
+pub mod quantize;
+pub mod query_cache;
+pub mod rerank;
+pub mod store;
 
 // #[cfg(test)]
 // mod tests {
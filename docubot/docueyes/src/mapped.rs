@@ -0,0 +1,129 @@
+/*
+ *
+ * Mapped lets the embedding matrix live either on the heap or in a read-only memory map of the index file.
+ *
+ * A mapped index is scored in place, so startup does not read the rows, processes on one host share the
+ * page cache, and indexes larger than RAM are paged in on demand.
+ *
+ */
+
+use anyhow::Result;
+use half::f16;
+use memmap2::Mmap;
+use std::fmt;
+use std::fs::File;
+use std::mem::{align_of, size_of};
+use std::ops::Deref;
+use std::sync::Arc;
+
+///
+/// Types whose every bit pattern is a valid value, so they can be viewed straight out of file bytes.
+///
+/// # Safety
+/// Implementors must be `Copy`, have no padding and no invalid bit patterns.
+///
+pub(crate) unsafe trait Plain: Copy {}
+
+unsafe impl Plain for f32 {}
+unsafe impl Plain for f16 {}
+unsafe impl Plain for i8 {}
+
+///
+/// Map a file read-only.
+///
+/// Whoever replaces the file must write a new one and rename it over the old, truncating a mapped file in place
+/// would fault every process reading it.
+///
+/// # Arguments
+/// * `path` - The path to the file.
+///
+/// # Returns
+/// * `Result<Arc<Mmap>>` - The mapping, shared by every buffer viewing it.
+///
+pub(crate) fn map_file(path: &str) -> Result<Arc<Mmap>> {
+    let file = File::open(path)?;
+    // SAFETY: the map is read-only and caches are only ever replaced by rename, never modified in place
+    let map = unsafe { Mmap::map(&file)? };
+    Ok(Arc::new(map))
+}
+
+///
+/// A slice of plain values, owned or viewed in a memory map.
+///
+/// # Variants
+/// * `Owned` - Values on the heap
+/// * `Mapped` - `len` values starting `offset` bytes into the map
+///
+#[derive(Clone)]
+pub(crate) enum Buffer<T> {
+    Owned(Vec<T>),
+    Mapped { map: Arc<Mmap>, offset: usize, len: usize },
+}
+
+impl<T: Plain> Buffer<T> {
+    ///
+    /// View `len` values starting `offset` bytes into a map.
+    ///
+    /// # Errors
+    /// * `Error` - If the values run past the map, are misaligned or the host is not little endian.
+    ///
+    pub(crate) fn mapped(map: Arc<Mmap>, offset: usize, len: usize) -> Result<Self> {
+        if cfg!(target_endian = "big") {
+            return Err(anyhow::anyhow!("Mapped indexes are little endian and cannot be used on this host"));
+        }
+        let end = len
+            .checked_mul(size_of::<T>())
+            .and_then(|bytes| bytes.checked_add(offset))
+            .ok_or_else(|| anyhow::anyhow!("Mapped buffer size overflows"))?;
+        if end > map.len() {
+            return Err(anyhow::anyhow!("Mapped buffer runs {} bytes past the end of the file", end - map.len()));
+        }
+        if !(map.as_ptr() as usize + offset).is_multiple_of(align_of::<T>()) {
+            return Err(anyhow::anyhow!("Mapped buffer at offset {} is misaligned", offset));
+        }
+        Ok(Buffer::Mapped { map, offset, len })
+    }
+
+    ///
+    /// Whether the values are viewed in a memory map.
+    ///
+    pub(crate) fn is_mapped(&self) -> bool {
+        matches!(self, Buffer::Mapped { .. })
+    }
+}
+
+impl<T> Default for Buffer<T> {
+    fn default() -> Self {
+        Buffer::Owned(Vec::new())
+    }
+}
+
+impl<T> From<Vec<T>> for Buffer<T> {
+    fn from(values: Vec<T>) -> Self {
+        Buffer::Owned(values)
+    }
+}
+
+impl<T: Plain> Deref for Buffer<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        match self {
+            Buffer::Owned(values) => values,
+            // SAFETY: bounds and alignment were checked in `Buffer::mapped`, `T: Plain` accepts any bytes,
+            // and the map is kept alive by the Arc for as long as the slice is borrowed
+            Buffer::Mapped { map, offset, len } => unsafe {
+                std::slice::from_raw_parts(map.as_ptr().add(*offset) as *const T, *len)
+            },
+        }
+    }
+}
+
+impl<T> fmt::Debug for Buffer<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Buffer::Owned(values) => write!(f, "Owned({} values)", values.len()),
+            Buffer::Mapped { offset, len, .. } => write!(f, "Mapped({} values at {})", len, offset),
+        }
+    }
+}
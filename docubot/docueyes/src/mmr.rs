@@ -0,0 +1,132 @@
+/*
+ *
+ * Mmr diversifies a ranking with maximal marginal relevance, picking each next result by its similarity to the query
+ * less its similarity to the results already picked, so near duplicates stop crowding the top.
+ *
+ */
+
+use crate::engine::PageScore;
+use crate::hybrid::{normalize, range};
+
+pub const DEFAULT_MMR_LAMBDA: f32 = 0.7;
+pub const DEFAULT_MMR_CANDIDATES: usize = 50;
+
+///
+/// How a ranking is diversified.
+///
+/// # Fields
+/// * `lambda` - Weight of query similarity against novelty, 1 keeps the ranking as it is and 0 only seeks novelty
+/// * `candidates` - How many of the best results are reordered, the rest follow in their ranked order.
+///   Every pick compares against every candidate left, so this bounds the quadratic cost
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MmrParams {
+    pub lambda: f32,
+    pub candidates: usize,
+}
+
+impl Default for MmrParams {
+    fn default() -> Self {
+        MmrParams {
+            lambda: DEFAULT_MMR_LAMBDA,
+            candidates: DEFAULT_MMR_CANDIDATES,
+        }
+    }
+}
+
+///
+/// Order a ranking by maximal marginal relevance.
+///
+/// Each pick maximises `lambda * relevance - (1 - lambda) * redundancy`, where redundancy is the highest
+/// similarity to a result already picked. The first pick is always the most similar result.
+///
+/// Relevance is the similarity min-max normalized over `ranked`, so fused, lexical and reranked scores,
+/// which live on scales of their own, weigh against the cosine redundancy the same way cosine similarities do.
+///
+/// # Arguments
+/// * `ranked` - The results to order, most similar first.
+/// * `lambda` - Weight of query similarity against novelty, clamped to `[0, 1]`.
+/// * `similarity` - Similarity between two results, addressed by their positions in `ranked`.
+///
+/// # Returns
+/// * `Vec<usize>` - Positions in `ranked`, in the order they were picked. Ties go to the higher ranked result.
+///
+pub fn mmr<F: Fn(usize, usize) -> f32>(ranked: &[PageScore], lambda: f32, similarity: F) -> Vec<usize> {
+    let lambda = lambda.clamp(0.0, 1.0);
+    let bounds = range(ranked);
+    let relevance: Vec<f32> = ranked.iter().map(|score| normalize(score.similarity, bounds)).collect();
+    let mut remaining: Vec<usize> = (0..ranked.len()).collect();
+    let mut redundancy = vec![f32::NEG_INFINITY; ranked.len()];
+    let mut picked = Vec::with_capacity(ranked.len());
+    while !remaining.is_empty() {
+        let marginal = |position: usize| {
+            // Nothing is redundant before the first pick
+            let penalty = redundancy[position].max(0.0);
+            lambda * relevance[position] - (1.0 - lambda) * penalty
+        };
+        let mut best = 0;
+        for slot in 1..remaining.len() {
+            if marginal(remaining[slot]) > marginal(remaining[best]) {
+                best = slot;
+            }
+        }
+        let pick = remaining.remove(best);
+        for &position in &remaining {
+            redundancy[position] = redundancy[position].max(similarity(position, pick));
+        }
+        picked.push(pick);
+    }
+    picked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ranked(similarities: &[f32]) -> Vec<PageScore> {
+        similarities
+            .iter()
+            .enumerate()
+            .map(|(id, &similarity)| PageScore {
+                id: id as i64,
+                similarity,
+                passage: None,
+            })
+            .collect()
+    }
+
+    // Results 0 and 1 are near duplicates, 2 is unrelated to both
+    fn duplicates(a: usize, b: usize) -> f32 {
+        match (a.min(b), a.max(b)) {
+            (0, 1) => 0.95,
+            _ => 0.1,
+        }
+    }
+
+    #[test]
+    fn lambda_of_one_keeps_the_ranking() {
+        assert_eq!(mmr(&ranked(&[0.9, 0.85, 0.6]), 1.0, duplicates), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn near_duplicates_are_pushed_down() {
+        assert_eq!(mmr(&ranked(&[0.9, 0.85, 0.6]), 0.5, duplicates), vec![0, 2, 1]);
+    }
+
+    #[test]
+    fn relevance_is_weighed_on_the_scale_of_redundancy() {
+        // Reciprocal rank fusion scores sit around 1 / 60, far below any cosine redundancy
+        let fused = ranked(&[1.0 / 30.5, 1.0 / 31.0, 1.0 / 31.5, 1.0 / 62.0]);
+        let related = |a: usize, b: usize| if a.max(b) == 3 { 0.0 } else { 0.3 };
+        // Mild redundancy must not sink the well ranked results below the unrelated last one
+        assert_eq!(mmr(&fused, 0.7, related), vec![0, 1, 2, 3]);
+        let cosine = ranked(&[0.9, 0.88, 0.86, 0.2]);
+        assert_eq!(mmr(&cosine, 0.7, related), mmr(&fused, 0.7, related));
+    }
+
+    #[test]
+    fn the_most_similar_result_is_always_picked_first() {
+        assert_eq!(mmr(&ranked(&[0.9, 0.85, 0.6]), 0.0, duplicates)[0], 0);
+        assert!(mmr(&[], 0.5, duplicates).is_empty());
+    }
+}
@@ -6,68 +6,484 @@
 
 use crate::corpus::Corpus;
 use crate::corpus::Embeddings;
+use crate::corpus::Page;
 use anyhow::Result;
-use rust_bert::pipelines::sentence_embeddings::SentenceEmbeddingsModel;
-use rust_bert::pipelines::sentence_embeddings::{
-    SentenceEmbeddingsBuilder, SentenceEmbeddingsModelType,
-};
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::{Mutex, MutexGuard};
+use std::thread;
 
 ///
 /// This provides a nice way to work with one off or corpus embeddings input.
 ///
+/// `Texts` encodes many texts in batches like `Pages` does, for passages cut out of pages.
+///
 pub enum EmbeddingInput<'a> {
     Corpus(&'a Corpus),
+    Pages(Vec<&'a Page>),
+    Texts(Vec<&'a str>),
     Text(&'a str),
 }
 
 ///
-/// This is a nice wrapper around the SentenceEmbeddingsModel from rust_bert.
+/// Embedder is the interface every embedding backend implements so the Model does not care where vectors come from.
+///
+pub trait Embedder: Send {
+    ///
+    /// Encode a batch of texts into embeddings.
+    ///
+    /// # Arguments
+    /// * `texts` - The texts to encode.
+    ///
+    /// # Returns
+    /// A Result containing one embedding per text, in the same order as the input.
+    ///
+    fn encode(&self, texts: &[&str]) -> Result<Vec<Embeddings>>;
+
+    ///
+    /// The number of components in every embedding produced by this backend.
+    ///
+    fn dimension(&self) -> usize;
+
+    ///
+    /// A stable identifier for the underlying model, used to tell embeddings from different models apart.
+    ///
+    fn model_id(&self) -> &str;
+
+    ///
+    /// The most tokens the backend reads from one text, special tokens included. Anything past it is ignored.
+    ///
+    /// # Returns
+    /// The token limit, `None` when the backend reads texts of any length.
+    ///
+    fn max_tokens(&self) -> Option<usize> {
+        None
+    }
+
+    ///
+    /// Count the tokens of every text the way the backend sees them, before any truncation.
+    ///
+    /// # Arguments
+    /// * `texts` - The texts to count.
+    ///
+    /// # Returns
+    /// A Result containing one count per text, whitespace separated words unless the backend knows better.
+    ///
+    fn count_tokens(&self, texts: &[&str]) -> Result<Vec<usize>> {
+        Ok(texts.iter().map(|text| text.split_whitespace().count()).collect())
+    }
+}
+
+///
+/// How texts are grouped into batches when many are encoded at once.
+///
+/// # Fields
+/// * `batch_size` - Texts handed to the backend per call
+/// * `sort_by_length` - Batch texts of similar length together so short texts are not padded to long ones
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatchParams {
+    pub batch_size: usize,
+    pub sort_by_length: bool,
+}
+
+impl Default for BatchParams {
+    fn default() -> Self {
+        BatchParams {
+            batch_size: 32,
+            sort_by_length: true,
+        }
+    }
+}
+
+///
+/// Progress of a batched encode, reported after every batch.
+///
+/// # Fields
+/// * `done` - Texts encoded so far
+/// * `total` - Texts being encoded
+/// * `batch` - Number of the batch that just finished, counting from 1. With a pool batches finish out of order
+/// * `batches` - Batches being encoded
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EmbeddingProgress {
+    pub done: usize,
+    pub total: usize,
+    pub batch: usize,
+    pub batches: usize,
+}
+
+///
+/// This is a nice wrapper around whichever Embedder backend is in use.
+///
+/// Holds a pool of one or more instances of the backend. Batches are spread across every instance when
+/// many texts are encoded, queries always go to the first.
 ///
 pub struct Model {
-    model: SentenceEmbeddingsModel,
+    embedders: Vec<Mutex<Box<dyn Embedder>>>,
+    model_id: String,
+    dimension: usize,
+    max_tokens: Option<usize>,
+    batch_params: BatchParams,
 }
 
 impl Model {
     ///
-    /// Create a new instance of the Model struct.
+    /// Create a new instance of the Model struct backed by the rust-bert sentence embeddings model.
+    ///
+    /// # Arguments
+    /// * `config` - Where to load the model from, whether the network may be used, and how to batch and parallelize.
     ///
     /// # Returns
     /// A Result containing a new instance of the Model struct.
     ///
-    pub fn new() -> Self {
-        let model = SentenceEmbeddingsBuilder::remote(SentenceEmbeddingsModelType::AllMiniLmL12V2)
-            .create_model()
-            .expect("Failed to create model");
-        Model { model }
+    #[cfg(feature = "bert")]
+    pub fn new(config: &crate::bert::ModelConfig) -> Result<Self> {
+        if let Some(threads) = config.intra_op_threads {
+            crate::bert::set_intra_op_threads(threads);
+        }
+        let embedders = (0..config.instances.max(1))
+            .map(|_| Ok(Box::new(crate::bert::BertEmbedder::new(config)?) as Box<dyn Embedder>))
+            .collect::<Result<Vec<_>>>()?;
+        let mut model = Model::from_pool(embedders)?;
+        model.set_batch_params(config.batch);
+        Ok(model)
+    }
+
+    ///
+    /// Create a new instance of the Model struct from any Embedder backend.
+    ///
+    /// # Arguments
+    /// * `embedder` - The backend used to generate embeddings.
+    ///
+    /// # Returns
+    /// A new instance of the Model struct.
+    ///
+    pub fn from_embedder<E: Embedder + 'static>(embedder: E) -> Self {
+        Model {
+            model_id: embedder.model_id().to_string(),
+            dimension: embedder.dimension(),
+            max_tokens: embedder.max_tokens(),
+            embedders: vec![Mutex::new(Box::new(embedder))],
+            batch_params: BatchParams::default(),
+        }
+    }
+
+    ///
+    /// Create a new instance of the Model struct from a pool of instances of the same backend.
+    ///
+    /// # Arguments
+    /// * `embedders` - The instances, each encodes batches on its own thread.
+    ///
+    /// # Returns
+    /// A Result containing a new instance of the Model struct.
+    ///
+    /// # Errors
+    /// * `Error` - If the pool is empty or its instances are not the same model.
+    ///
+    pub fn from_pool(embedders: Vec<Box<dyn Embedder>>) -> Result<Self> {
+        let first = embedders
+            .first()
+            .ok_or_else(|| anyhow::anyhow!("A model needs at least one embedder"))?;
+        let model_id = first.model_id().to_string();
+        let dimension = first.dimension();
+        let max_tokens = first.max_tokens();
+        if embedders
+            .iter()
+            .any(|embedder| embedder.model_id() != model_id || embedder.dimension() != dimension)
+        {
+            return Err(anyhow::anyhow!("Every embedder in a pool must be the same model"));
+        }
+        Ok(Model {
+            embedders: embedders.into_iter().map(Mutex::new).collect(),
+            model_id,
+            dimension,
+            max_tokens,
+            batch_params: BatchParams::default(),
+        })
+    }
+
+    ///
+    /// The number of components in every embedding produced by the model.
+    ///
+    pub fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    ///
+    /// The identifier of the model backing this instance.
+    ///
+    pub fn model_id(&self) -> &str {
+        &self.model_id
+    }
+
+    ///
+    /// The most tokens the model reads from one text, `None` when it reads texts of any length.
+    ///
+    pub fn max_tokens(&self) -> Option<usize> {
+        self.max_tokens
+    }
+
+    ///
+    /// Count the tokens of every text the way the model sees them, before any truncation.
+    ///
+    /// # Arguments
+    /// * `texts` - The texts to count.
+    ///
+    /// # Returns
+    /// A Result containing one count per text, in the same order as the input.
+    ///
+    pub fn count_tokens(&self, texts: &[&str]) -> Result<Vec<usize>> {
+        let counts = lock(&self.embedders[0])?.count_tokens(texts)?;
+        if counts.len() != texts.len() {
+            return Err(anyhow::anyhow!(
+                "Embedder returned {} token counts for {} texts",
+                counts.len(),
+                texts.len()
+            ));
+        }
+        Ok(counts)
+    }
+
+    ///
+    /// The number of backend instances encoding in parallel.
+    ///
+    pub fn instances(&self) -> usize {
+        self.embedders.len()
     }
 
     ///
-    /// Generate embeddings for a list of sentences using the SentenceEmbeddingsModel.
+    /// How texts are batched when many are encoded at once.
+    ///
+    pub fn batch_params(&self) -> BatchParams {
+        self.batch_params
+    }
+
+    ///
+    /// Change how texts are batched when many are encoded at once.
+    ///
+    /// # Arguments
+    /// * `params` - The batch size and whether to sort by length.
+    ///
+    pub fn set_batch_params(&mut self, params: BatchParams) {
+        self.batch_params = params;
+    }
+
+    ///
+    /// Generate embeddings for a corpus or a single piece of text using the Embedder backend.
     ///
     /// # Arguments
-    /// * `sentences` - A slice of strings representing the sentences to generate embeddings for.
-    /// * `model` - A reference to the SentenceEmbeddingsModel to use for generating embeddings.
+    /// * `embedding_input` - The corpus or text to generate embeddings for.
     ///
     /// # Returns
     /// A Result containing a vector of vectors of f32 representing the embeddings for each sentence.
     ///
     pub fn generate_embeddings(&self, embedding_input: EmbeddingInput) -> Result<Vec<Embeddings>> {
+        self.generate_embeddings_with_progress(embedding_input, &|_| {})
+    }
+
+    ///
+    /// Generate embeddings like `generate_embeddings`, reporting progress after every batch.
+    ///
+    /// # Arguments
+    /// * `embedding_input` - The corpus or text to generate embeddings for.
+    /// * `progress` - Called after every batch, possibly from several threads at once when encoding with a pool.
+    ///
+    /// # Returns
+    /// A Result containing a vector of vectors of f32 representing the embeddings for each sentence.
+    ///
+    pub fn generate_embeddings_with_progress(
+        &self,
+        embedding_input: EmbeddingInput,
+        progress: &(dyn Fn(EmbeddingProgress) + Sync),
+    ) -> Result<Vec<Embeddings>> {
         match embedding_input {
-            EmbeddingInput::Corpus(corpus) => {
-                let mut page_embeddings = Vec::new();
-                for page in &corpus.pages {
-                    let mut batch = self.model.encode(&[&page.body])?;
-                    if let Some(embedding) = batch.pop() {
-                        page_embeddings.push(embedding);
-                    }
-                }
-                Ok(page_embeddings)
+            EmbeddingInput::Corpus(corpus) => self
+                .generate_embeddings_with_progress(EmbeddingInput::Pages(corpus.pages.iter().collect()), progress),
+            EmbeddingInput::Pages(pages) => {
+                let texts: Vec<&str> = pages.iter().map(|page| page.embedding_text()).collect();
+                self.encode_batched(&texts, progress)
             }
+            EmbeddingInput::Texts(texts) => self.encode_batched(&texts, progress),
             EmbeddingInput::Text(text) => {
-                let mut batch = self.model.encode(&[text])?;
-                let query_embedding = batch.pop().unwrap();
+                let mut batch = lock(&self.embedders[0])?.encode(&[text])?;
+                let query_embedding = batch
+                    .pop()
+                    .ok_or_else(|| anyhow::anyhow!("Embedder returned no embedding for the query"))?;
                 Ok(vec![query_embedding])
             }
         }
     }
+
+    ///
+    /// Encode many texts in batches, spread across the pool, returning the embeddings in input order.
+    ///
+    fn encode_batched(&self, texts: &[&str], progress: &(dyn Fn(EmbeddingProgress) + Sync)) -> Result<Vec<Embeddings>> {
+        let mut order: Vec<usize> = (0..texts.len()).collect();
+        if self.batch_params.sort_by_length {
+            // Byte length is a cheap stand in for token count, close enough to keep padding down
+            order.sort_by_key(|&index| texts[index].len());
+        }
+        let batches: Vec<&[usize]> = order.chunks(self.batch_params.batch_size.max(1)).collect();
+
+        let next_batch = AtomicUsize::new(0);
+        let done = AtomicUsize::new(0);
+        let work = |embedder: &Mutex<Box<dyn Embedder>>| -> Result<Vec<(usize, Embeddings)>> {
+            let embedder = lock(embedder)?;
+            let mut encoded = Vec::new();
+            loop {
+                let batch_number = next_batch.fetch_add(1, AtomicOrdering::Relaxed);
+                let Some(batch) = batches.get(batch_number) else {
+                    break;
+                };
+                let batch_texts: Vec<&str> = batch.iter().map(|&index| texts[index]).collect();
+                let embeddings = embedder.encode(&batch_texts)?;
+                if embeddings.len() != batch.len() {
+                    return Err(anyhow::anyhow!(
+                        "Embedder returned {} embeddings for a batch of {} texts",
+                        embeddings.len(),
+                        batch.len()
+                    ));
+                }
+                encoded.extend(batch.iter().copied().zip(embeddings));
+                progress(EmbeddingProgress {
+                    done: done.fetch_add(batch.len(), AtomicOrdering::Relaxed) + batch.len(),
+                    total: texts.len(),
+                    batch: batch_number + 1,
+                    batches: batches.len(),
+                });
+            }
+            Ok(encoded)
+        };
+
+        let workers = self.embedders.len().min(batches.len());
+        let encoded: Vec<Result<Vec<(usize, Embeddings)>>> = if workers <= 1 {
+            vec![work(&self.embedders[0])]
+        } else {
+            thread::scope(|scope| {
+                let handles: Vec<_> = self.embedders[..workers]
+                    .iter()
+                    .map(|embedder| scope.spawn(|| work(embedder)))
+                    .collect();
+                handles
+                    .into_iter()
+                    .map(|handle| {
+                        handle
+                            .join()
+                            .unwrap_or_else(|_| Err(anyhow::anyhow!("Embedding worker panicked")))
+                    })
+                    .collect()
+            })
+        };
+
+        let mut embeddings: Vec<Option<Embeddings>> = vec![None; texts.len()];
+        for worker in encoded {
+            for (index, embedding) in worker? {
+                embeddings[index] = Some(embedding);
+            }
+        }
+        embeddings
+            .into_iter()
+            .map(|embedding| embedding.ok_or_else(|| anyhow::anyhow!("A text was left without an embedding")))
+            .collect()
+    }
+}
+
+fn lock(embedder: &Mutex<Box<dyn Embedder>>) -> Result<MutexGuard<'_, Box<dyn Embedder>>> {
+    embedder
+        .lock()
+        .map_err(|_| anyhow::anyhow!("Embedder was poisoned by a panic in another thread"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hashed::HashedEmbedder;
+
+    // Texts of very different lengths, out of length order so sorting reorders them
+    const TEXTS: [&str; 9] = [
+        "a much longer text about error codes and how to read them",
+        "short",
+        "a text of middling length",
+        "tiny",
+        "another fairly long text about search and ranking of pages",
+        "mid sized text here",
+        "x",
+        "the longest text of them all, rambling on about embeddings, batches and pools",
+        "medium text",
+    ];
+
+    fn pool(instances: usize, batch_size: usize) -> Model {
+        let embedders: Vec<Box<dyn Embedder>> = (0..instances)
+            .map(|_| Box::new(HashedEmbedder::default()) as Box<dyn Embedder>)
+            .collect();
+        let mut model = Model::from_pool(embedders).unwrap();
+        model.set_batch_params(BatchParams {
+            batch_size,
+            sort_by_length: true,
+        });
+        model
+    }
+
+    // Drops the last embedding of every batch
+    struct ShortEmbedder;
+
+    impl Embedder for ShortEmbedder {
+        fn encode(&self, texts: &[&str]) -> Result<Vec<Embeddings>> {
+            Ok(vec![vec![1.0]; texts.len().saturating_sub(1)])
+        }
+
+        fn dimension(&self) -> usize {
+            1
+        }
+
+        fn model_id(&self) -> &str {
+            "short"
+        }
+    }
+
+    #[test]
+    fn batched_embeddings_come_back_in_input_order() {
+        let expected = HashedEmbedder::default().encode(&TEXTS).unwrap();
+        for (instances, batch_size) in [(1, 2), (3, 2), (4, 1), (2, 100)] {
+            let model = pool(instances, batch_size);
+            let embeddings = model.generate_embeddings(EmbeddingInput::Texts(TEXTS.to_vec())).unwrap();
+            assert_eq!(embeddings, expected, "{} instances, batches of {}", instances, batch_size);
+        }
+    }
+
+    #[test]
+    fn progress_counts_every_batch() {
+        let model = pool(3, 2);
+        let reports = Mutex::new(Vec::new());
+        model
+            .generate_embeddings_with_progress(EmbeddingInput::Texts(TEXTS.to_vec()), &|progress| {
+                reports.lock().unwrap().push(progress.done)
+            })
+            .unwrap();
+        let mut reports = reports.into_inner().unwrap();
+        reports.sort();
+        assert_eq!(reports.len(), TEXTS.len().div_ceil(2));
+        assert_eq!(reports.last(), Some(&TEXTS.len()));
+    }
+
+    #[test]
+    fn batches_with_missing_embeddings_are_rejected() {
+        let mut model = Model::from_embedder(ShortEmbedder);
+        model.set_batch_params(BatchParams {
+            batch_size: 4,
+            sort_by_length: true,
+        });
+        let error = model
+            .generate_embeddings(EmbeddingInput::Texts(TEXTS.to_vec()))
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("for a batch of 4 texts"), "{}", error);
+    }
+
+    #[test]
+    fn pools_must_be_one_model() {
+        assert!(Model::from_pool(Vec::new()).is_err());
+        let mixed: Vec<Box<dyn Embedder>> = vec![Box::new(HashedEmbedder::new(8)), Box::new(HashedEmbedder::new(16))];
+        assert!(Model::from_pool(mixed).is_err());
+    }
 }
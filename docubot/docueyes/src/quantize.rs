@@ -0,0 +1,594 @@
+/*
+ *
+ * Quantize compresses the page embedding matrix, trading a little ranking accuracy for memory and disk.
+ *
+ * f16 stores every value as a half precision float. int8 scalar quantization maps every value to a signed
+ * byte and a scale, either one scale per dimension shared by every row or one scale per row. Queries are
+ * quantized the same way so int8 scoring is an integer dot product.
+ *
+ */
+
+use crate::mapped::Buffer;
+use crate::store::dot;
+use anyhow::Result;
+use half::f16;
+use half::slice::HalfFloatSliceExt;
+use memmap2::Mmap;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+// Largest magnitude of an int8 code, -128 is left unused so the range is symmetric
+const INT8_MAX: f32 = 127.0;
+
+// Accumulator lanes of the integer dot product
+const LANES: usize = 16;
+
+// Values of an f16 row converted to f32 at a time while scoring
+const F16_BLOCK: usize = 64;
+
+///
+/// Which values an int8 scale is shared between.
+///
+/// # Variants
+/// * `PerDimension` - One scale per dimension, shared by every row
+/// * `PerVector` - One scale per row, shared by its dimensions
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Scaling {
+    PerDimension,
+    PerVector,
+}
+
+///
+/// How the page embedding matrix is stored.
+///
+/// # Variants
+/// * `F32` - Full precision, 4 bytes per value
+/// * `F16` - Half precision, 2 bytes per value
+/// * `Int8` - Scalar quantized, 1 byte per value plus the scales
+///
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Quantization {
+    #[default]
+    F32,
+    F16,
+    Int8(Scaling),
+}
+
+impl Quantization {
+    pub const ALL: [Quantization; 4] = [
+        Quantization::F32,
+        Quantization::F16,
+        Quantization::Int8(Scaling::PerDimension),
+        Quantization::Int8(Scaling::PerVector),
+    ];
+
+    ///
+    /// The name used to select the mode in configuration.
+    ///
+    pub fn name(&self) -> &'static str {
+        match self {
+            Quantization::F32 => "f32",
+            Quantization::F16 => "f16",
+            Quantization::Int8(Scaling::PerDimension) => "int8",
+            Quantization::Int8(Scaling::PerVector) => "int8-vector",
+        }
+    }
+
+    ///
+    /// The byte recording the mode in the binary index.
+    ///
+    pub(crate) fn tag(&self) -> u8 {
+        match self {
+            Quantization::F32 => 0,
+            Quantization::F16 => 1,
+            Quantization::Int8(Scaling::PerDimension) => 2,
+            Quantization::Int8(Scaling::PerVector) => 3,
+        }
+    }
+
+    pub(crate) fn from_tag(tag: u8) -> Option<Self> {
+        Quantization::ALL.into_iter().find(|mode| mode.tag() == tag)
+    }
+
+    ///
+    /// The number of bytes the mode takes to store a matrix, scales included.
+    ///
+    /// # Arguments
+    /// * `dimension` - The length of every row.
+    /// * `rows` - The number of rows.
+    ///
+    /// # Returns
+    /// * `Option<usize>` - The size in bytes, `None` if it overflows.
+    ///
+    pub fn encoded_len(&self, dimension: usize, rows: usize) -> Option<usize> {
+        let values = dimension.checked_mul(rows)?;
+        match self {
+            Quantization::F32 => values.checked_mul(4),
+            Quantization::F16 => values.checked_mul(2),
+            Quantization::Int8(Scaling::PerDimension) => values.checked_add(dimension.checked_mul(4)?),
+            Quantization::Int8(Scaling::PerVector) => values.checked_add(rows.checked_mul(4)?),
+        }
+    }
+}
+
+impl fmt::Display for Quantization {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Quantization {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        Quantization::ALL
+            .into_iter()
+            .find(|mode| mode.name().eq_ignore_ascii_case(name))
+            .ok_or_else(|| {
+                let known: Vec<&str> = Quantization::ALL.iter().map(|mode| mode.name()).collect();
+                anyhow::anyhow!("Unknown quantization {}, expected one of {}", name, known.join(", "))
+            })
+    }
+}
+
+///
+/// How the engine stores embeddings and whether quantized scores are corrected.
+///
+/// # Fields
+/// * `mode` - How the embedding matrix is stored
+/// * `rescore` - How many of the best quantized candidates are rescored in f32, 0 disables rescoring.
+///   Rescoring keeps a full precision copy of every row, so it saves disk but not memory
+///
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct QuantizationParams {
+    pub mode: Quantization,
+    pub rescore: usize,
+}
+
+impl QuantizationParams {
+    ///
+    /// Whether the engine needs the f32 embeddings, either to score with or to rescore with.
+    ///
+    pub fn needs_full_precision(&self) -> bool {
+        self.mode == Quantization::F32 || self.rescore > 0
+    }
+}
+
+///
+/// The embedding rows back to back in one of the storage modes, on the heap or in a mapped index.
+///
+#[derive(Debug, Clone)]
+pub(crate) enum Matrix {
+    F32(Buffer<f32>),
+    F16(Buffer<f16>),
+    Int8 {
+        scaling: Scaling,
+        scales: Buffer<f32>,
+        codes: Buffer<i8>,
+    },
+}
+
+impl Default for Matrix {
+    fn default() -> Self {
+        Matrix::F32(Buffer::default())
+    }
+}
+
+///
+/// A query in the form a matrix scores against, built once per query.
+///
+/// # Fields
+/// * `vector` - The query, prescaled by the per dimension scales when the matrix uses them
+/// * `codes` - The int8 codes of `vector`, empty unless the matrix is int8
+/// * `scale` - The scale of `codes`
+///
+#[derive(Debug, Clone)]
+pub(crate) struct PreparedQuery {
+    vector: Vec<f32>,
+    codes: Vec<i8>,
+    scale: f32,
+}
+
+impl Matrix {
+    ///
+    /// Store row-major values in a mode.
+    ///
+    /// # Arguments
+    /// * `mode` - The storage mode.
+    /// * `values` - The rows back to back.
+    /// * `dimension` - The length of every row.
+    ///
+    pub(crate) fn quantize(mode: Quantization, values: Vec<f32>, dimension: usize) -> Self {
+        match mode {
+            Quantization::F32 => Matrix::F32(values.into()),
+            Quantization::F16 => Matrix::F16(
+                values
+                    .iter()
+                    .map(|&value| f16::from_f32(value))
+                    .collect::<Vec<f16>>()
+                    .into(),
+            ),
+            Quantization::Int8(Scaling::PerVector) => {
+                let mut scales = Vec::with_capacity(values.len() / dimension.max(1));
+                let mut codes = Vec::with_capacity(values.len());
+                for row in values.chunks_exact(dimension.max(1)) {
+                    let scale = int8_scale(row.iter().copied());
+                    scales.push(scale);
+                    codes.extend(row.iter().map(|&value| int8_code(value, scale)));
+                }
+                Matrix::Int8 {
+                    scaling: Scaling::PerVector,
+                    scales: scales.into(),
+                    codes: codes.into(),
+                }
+            }
+            Quantization::Int8(Scaling::PerDimension) => {
+                let scales: Vec<f32> = (0..dimension)
+                    .map(|d| int8_scale(values.iter().skip(d).step_by(dimension).copied()))
+                    .collect();
+                let codes: Vec<i8> = values
+                    .iter()
+                    .enumerate()
+                    .map(|(i, &value)| int8_code(value, scales[i % dimension]))
+                    .collect();
+                Matrix::Int8 {
+                    scaling: Scaling::PerDimension,
+                    scales: scales.into(),
+                    codes: codes.into(),
+                }
+            }
+        }
+    }
+
+    ///
+    /// View a matrix written by `write` in place in a mapped index.
+    ///
+    /// # Arguments
+    /// * `mode` - The storage mode the matrix was written in.
+    /// * `map` - The mapped index.
+    /// * `offset` - Where the matrix starts in the map, in bytes.
+    /// * `dimension` - The length of every row.
+    /// * `rows` - The number of rows.
+    ///
+    /// # Errors
+    /// * `Error` - If the matrix runs past the map or is misaligned.
+    ///
+    pub(crate) fn mapped(mode: Quantization, map: &Arc<Mmap>, offset: usize, dimension: usize, rows: usize) -> Result<Self> {
+        let values = dimension * rows;
+        Ok(match mode {
+            Quantization::F32 => Matrix::F32(Buffer::mapped(Arc::clone(map), offset, values)?),
+            Quantization::F16 => Matrix::F16(Buffer::mapped(Arc::clone(map), offset, values)?),
+            Quantization::Int8(scaling) => {
+                let scale_count = match scaling {
+                    Scaling::PerDimension => dimension,
+                    Scaling::PerVector => rows,
+                };
+                Matrix::Int8 {
+                    scaling,
+                    scales: Buffer::mapped(Arc::clone(map), offset, scale_count)?,
+                    codes: Buffer::mapped(Arc::clone(map), offset + scale_count * 4, values)?,
+                }
+            }
+        })
+    }
+
+    ///
+    /// Whether the matrix is viewed in a mapped index rather than held on the heap.
+    ///
+    pub(crate) fn is_mapped(&self) -> bool {
+        match self {
+            Matrix::F32(values) => values.is_mapped(),
+            Matrix::F16(values) => values.is_mapped(),
+            Matrix::Int8 { codes, .. } => codes.is_mapped(),
+        }
+    }
+
+    ///
+    /// The storage mode of the matrix.
+    ///
+    pub(crate) fn mode(&self) -> Quantization {
+        match self {
+            Matrix::F32(_) => Quantization::F32,
+            Matrix::F16(_) => Quantization::F16,
+            Matrix::Int8 { scaling, .. } => Quantization::Int8(*scaling),
+        }
+    }
+
+    ///
+    /// A row as f32, borrowed when the matrix is full precision and dequantized otherwise.
+    ///
+    pub(crate) fn row(&self, row: usize, dimension: usize) -> Cow<'_, [f32]> {
+        let range = row * dimension..(row + 1) * dimension;
+        match self {
+            Matrix::F32(values) => Cow::Borrowed(&values[range]),
+            Matrix::F16(values) => Cow::Owned(values[range].iter().map(|value| value.to_f32()).collect()),
+            Matrix::Int8 {
+                scaling: Scaling::PerVector,
+                scales,
+                codes,
+            } => Cow::Owned(codes[range].iter().map(|&code| code as f32 * scales[row]).collect()),
+            Matrix::Int8 {
+                scaling: Scaling::PerDimension,
+                scales,
+                codes,
+            } => Cow::Owned(codes[range].iter().zip(scales.iter()).map(|(&code, scale)| code as f32 * scale).collect()),
+        }
+    }
+
+    ///
+    /// Turn a unit normalized query into the form `similarity` scores with.
+    ///
+    pub(crate) fn prepare(&self, query: Vec<f32>) -> PreparedQuery {
+        match self {
+            Matrix::F32(_) | Matrix::F16(_) => PreparedQuery {
+                vector: query,
+                codes: Vec::new(),
+                scale: 1.0,
+            },
+            Matrix::Int8 { scaling, scales, .. } => {
+                let vector: Vec<f32> = match scaling {
+                    Scaling::PerVector => query,
+                    // Folding the dimension scales into the query leaves a plain integer dot product per row
+                    Scaling::PerDimension => query.iter().zip(scales.iter()).map(|(value, scale)| value * scale).collect(),
+                };
+                let scale = int8_scale(vector.iter().copied());
+                let codes = vector.iter().map(|&value| int8_code(value, scale)).collect();
+                PreparedQuery { vector, codes, scale }
+            }
+        }
+    }
+
+    ///
+    /// Score a prepared query against a row, the cosine similarity when both were unit normalized.
+    ///
+    pub(crate) fn similarity(&self, query: &PreparedQuery, row: usize, dimension: usize) -> f32 {
+        let range = row * dimension..(row + 1) * dimension;
+        match self {
+            Matrix::F32(values) => dot(&query.vector, &values[range]),
+            Matrix::F16(values) => dot_f16(&query.vector, &values[range]),
+            Matrix::Int8 {
+                scaling: Scaling::PerVector,
+                scales,
+                codes,
+            } => query.scale * scales[row] * dot_i8(&query.codes, &codes[range]) as f32,
+            Matrix::Int8 {
+                scaling: Scaling::PerDimension,
+                codes,
+                ..
+            } => query.scale * dot_i8(&query.codes, &codes[range]) as f32,
+        }
+    }
+
+    ///
+    /// Append the matrix to a buffer, little endian, scales before codes.
+    ///
+    pub(crate) fn write(&self, out: &mut Vec<u8>) {
+        match self {
+            Matrix::F32(values) => values.iter().for_each(|value| out.extend_from_slice(&value.to_le_bytes())),
+            Matrix::F16(values) => values.iter().for_each(|value| out.extend_from_slice(&value.to_le_bytes())),
+            Matrix::Int8 { scales, codes, .. } => {
+                scales.iter().for_each(|scale| out.extend_from_slice(&scale.to_le_bytes()));
+                out.extend(codes.iter().map(|&code| code as u8));
+            }
+        }
+    }
+
+    ///
+    /// Read a matrix written by `write`, `bytes` must be exactly `mode.encoded_len(dimension, rows)` long.
+    ///
+    pub(crate) fn read(mode: Quantization, bytes: &[u8], dimension: usize, rows: usize) -> Self {
+        match mode {
+            Quantization::F32 => Matrix::F32(f32s(bytes).into()),
+            Quantization::F16 => Matrix::F16(
+                bytes
+                    .chunks_exact(2)
+                    .map(|value| f16::from_le_bytes([value[0], value[1]]))
+                    .collect::<Vec<f16>>()
+                    .into(),
+            ),
+            Quantization::Int8(scaling) => {
+                let scale_count = match scaling {
+                    Scaling::PerDimension => dimension,
+                    Scaling::PerVector => rows,
+                };
+                let (scales, codes) = bytes.split_at(scale_count * 4);
+                Matrix::Int8 {
+                    scaling,
+                    scales: f32s(scales).into(),
+                    codes: codes.iter().map(|&code| code as i8).collect::<Vec<i8>>().into(),
+                }
+            }
+        }
+    }
+}
+
+///
+/// Dot product of an f32 query with a half precision row, converting the row a block at a time
+/// so the conversion can use the CPU's vector instructions.
+///
+fn dot_f16(query: &[f32], row: &[f16]) -> f32 {
+    let mut block = [0.0f32; F16_BLOCK];
+    query
+        .chunks(F16_BLOCK)
+        .zip(row.chunks(F16_BLOCK))
+        .map(|(query, row)| {
+            let block = &mut block[..row.len()];
+            row.convert_to_f32_slice(block);
+            dot(query, block)
+        })
+        .sum()
+}
+
+///
+/// Integer dot product of two int8 vectors, accumulated in `LANES` independent i32 sums so the loop vectorizes.
+///
+fn dot_i8(a: &[i8], b: &[i8]) -> i32 {
+    let a_chunks = a.chunks_exact(LANES);
+    let b_chunks = b.chunks_exact(LANES);
+    let remainder: i32 = a_chunks
+        .remainder()
+        .iter()
+        .zip(b_chunks.remainder())
+        .map(|(&x, &y)| x as i32 * y as i32)
+        .sum();
+
+    let mut sums = [0i32; LANES];
+    for (a, b) in a_chunks.zip(b_chunks) {
+        for ((sum, &x), &y) in sums.iter_mut().zip(a).zip(b) {
+            *sum += x as i32 * y as i32;
+        }
+    }
+    sums.iter().sum::<i32>() + remainder
+}
+
+///
+/// The scale mapping the largest magnitude of the values onto the largest int8 code, 0 for all zero values.
+///
+fn int8_scale(values: impl Iterator<Item = f32>) -> f32 {
+    values.fold(0.0f32, |max, value| max.max(value.abs())) / INT8_MAX
+}
+
+fn int8_code(value: f32, scale: f32) -> i8 {
+    if scale > 0.0 {
+        (value / scale).round().clamp(-INT8_MAX, INT8_MAX) as i8
+    } else {
+        0
+    }
+}
+
+fn f32s(bytes: &[u8]) -> Vec<f32> {
+    bytes
+        .chunks_exact(4)
+        .map(|value| f32::from_le_bytes([value[0], value[1], value[2], value[3]]))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::normalize;
+
+    const DIMENSION: usize = 32;
+
+    // Unit normalized rows of deterministic pseudo random values
+    fn rows(count: usize, seed: f32) -> Vec<f32> {
+        let mut values: Vec<f32> = (0..count * DIMENSION)
+            .map(|i| ((i as f32 + seed) * 12.9898).sin())
+            .collect();
+        values.chunks_exact_mut(DIMENSION).for_each(normalize);
+        values
+    }
+
+    fn max_error(mode: Quantization) -> f32 {
+        let values = rows(20, 0.0);
+        let queries = rows(5, 1000.0);
+        let matrix = Matrix::quantize(mode, values.clone(), DIMENSION);
+        let mut max_error: f32 = 0.0;
+        for query in queries.chunks_exact(DIMENSION) {
+            let prepared = matrix.prepare(query.to_vec());
+            for (row, values) in values.chunks_exact(DIMENSION).enumerate() {
+                let error = (matrix.similarity(&prepared, row, DIMENSION) - dot(query, values)).abs();
+                max_error = max_error.max(error);
+            }
+        }
+        max_error
+    }
+
+    #[test]
+    fn f32_scores_exactly() {
+        assert_eq!(max_error(Quantization::F32), 0.0);
+    }
+
+    #[test]
+    fn f16_round_trips_within_half_precision() {
+        let values = rows(3, 0.0);
+        let matrix = Matrix::quantize(Quantization::F16, values.clone(), DIMENSION);
+        for (row, values) in values.chunks_exact(DIMENSION).enumerate() {
+            for (decoded, value) in matrix.row(row, DIMENSION).iter().zip(values) {
+                assert!((decoded - value).abs() <= value.abs() * 1e-3 + 1e-7);
+            }
+        }
+        assert!(max_error(Quantization::F16) < 2e-3);
+    }
+
+    #[test]
+    fn int8_per_vector_scales_each_row_by_its_largest_magnitude() {
+        let values = vec![0.6, -1.0, 0.25, 2.0, 1.2, 0.0];
+        let Matrix::Int8 { scaling, scales, codes } = Matrix::quantize(Quantization::Int8(Scaling::PerVector), values, 3) else {
+            panic!("int8 mode should give an int8 matrix");
+        };
+        assert_eq!(scaling, Scaling::PerVector);
+        assert_eq!(&scales[..], &[1.0 / INT8_MAX, 2.0 / INT8_MAX]);
+        assert_eq!(&codes[..], &[76, -127, 32, 127, 76, 0]);
+    }
+
+    #[test]
+    fn int8_per_dimension_scales_each_column_by_its_largest_magnitude() {
+        let values = vec![0.6, -1.0, 0.25, 2.0, 1.2, 0.0];
+        let Matrix::Int8 { scaling, scales, codes } = Matrix::quantize(Quantization::Int8(Scaling::PerDimension), values, 3) else {
+            panic!("int8 mode should give an int8 matrix");
+        };
+        assert_eq!(scaling, Scaling::PerDimension);
+        assert_eq!(&scales[..], &[2.0 / INT8_MAX, 1.2 / INT8_MAX, 0.25 / INT8_MAX]);
+        assert_eq!(&codes[..], &[38, -106, 127, 127, 127, 0]);
+    }
+
+    #[test]
+    fn int8_of_all_zeros_codes_to_zero() {
+        let Matrix::Int8 { scales, codes, .. } = Matrix::quantize(Quantization::Int8(Scaling::PerVector), vec![0.0; 4], 4) else {
+            panic!("int8 mode should give an int8 matrix");
+        };
+        assert_eq!(&scales[..], &[0.0]);
+        assert_eq!(&codes[..], &[0; 4]);
+    }
+
+    #[test]
+    fn int8_dot_products_stay_close_to_f32() {
+        // Each side is off by at most half a step, 1 / 254 per value, so a unit dot product drifts by a few hundredths at worst
+        assert!(max_error(Quantization::Int8(Scaling::PerDimension)) < 0.05);
+        assert!(max_error(Quantization::Int8(Scaling::PerVector)) < 0.05);
+    }
+
+    #[test]
+    fn int8_dequantizes_within_half_a_step() {
+        for scaling in [Scaling::PerDimension, Scaling::PerVector] {
+            let values = rows(4, 0.0);
+            let matrix = Matrix::quantize(Quantization::Int8(scaling), values.clone(), DIMENSION);
+            for (row, values) in values.chunks_exact(DIMENSION).enumerate() {
+                for (decoded, value) in matrix.row(row, DIMENSION).iter().zip(values) {
+                    assert!((decoded - value).abs() <= 0.5 / INT8_MAX + 1e-6);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn write_and_read_round_trip_every_mode() {
+        let values = rows(3, 0.0);
+        for mode in Quantization::ALL {
+            let matrix = Matrix::quantize(mode, values.clone(), DIMENSION);
+            let mut bytes = Vec::new();
+            matrix.write(&mut bytes);
+            assert_eq!(Some(bytes.len()), mode.encoded_len(DIMENSION, 3));
+            let read = Matrix::read(mode, &bytes, DIMENSION, 3);
+            assert_eq!(read.mode(), mode);
+            for row in 0..3 {
+                assert_eq!(read.row(row, DIMENSION), matrix.row(row, DIMENSION));
+            }
+        }
+    }
+
+    #[test]
+    fn modes_parse_by_name_and_tag() {
+        for mode in Quantization::ALL {
+            assert_eq!(mode.name().to_uppercase().parse::<Quantization>().unwrap(), mode);
+            assert_eq!(Quantization::from_tag(mode.tag()), Some(mode));
+        }
+        assert!("int4".parse::<Quantization>().is_err());
+        assert_eq!(Quantization::from_tag(9), None);
+    }
+}
@@ -0,0 +1,275 @@
+/*
+ *
+ * Query cache keeps the embeddings of recent queries so repeated questions skip the model,
+ * evicting the least recently used query once full and expiring queries past their time to live.
+ *
+ */
+
+use crate::corpus::Embeddings;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_QUERY_CACHE_CAPACITY: usize = 1024;
+
+///
+/// How many query embeddings are kept and for how long.
+///
+/// # Fields
+/// * `capacity` - The most queries kept, 0 turns the cache off
+/// * `ttl` - How long a query is kept after it was embedded, forever when unset
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueryCacheParams {
+    pub capacity: usize,
+    pub ttl: Option<Duration>,
+}
+
+impl Default for QueryCacheParams {
+    fn default() -> Self {
+        QueryCacheParams {
+            capacity: DEFAULT_QUERY_CACHE_CAPACITY,
+            ttl: None,
+        }
+    }
+}
+
+///
+/// Counters describing how well the cache is doing.
+///
+/// # Fields
+/// * `hits` - Queries answered from the cache
+/// * `misses` - Queries that had to be embedded, expired ones included
+/// * `entries` - Queries currently kept
+/// * `capacity` - The most queries kept
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct QueryCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    pub capacity: usize,
+}
+
+impl QueryCacheStats {
+    ///
+    /// The share of lookups answered from the cache, 0 before the first lookup.
+    ///
+    pub fn hit_rate(&self) -> f64 {
+        let lookups = self.hits + self.misses;
+        if lookups == 0 { 0.0 } else { self.hits as f64 / lookups as f64 }
+    }
+}
+
+///
+/// A least recently used cache of query embeddings, keyed by normalized query text. Safe to share between threads.
+///
+#[derive(Debug, Default)]
+pub struct QueryCache {
+    params: QueryCacheParams,
+    entries: Mutex<Entries>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+///
+/// The cached queries, with their recency kept in a second map so the oldest is found without a scan.
+///
+#[derive(Debug, Default)]
+struct Entries {
+    queries: HashMap<String, Entry>,
+    recency: BTreeMap<u64, String>,
+    clock: u64,
+}
+
+#[derive(Debug)]
+struct Entry {
+    embedding: Embeddings,
+    inserted: Instant,
+    used: u64,
+}
+
+impl QueryCache {
+    ///
+    /// Create an empty cache.
+    ///
+    /// # Arguments
+    /// * `params` - The capacity and time to live.
+    ///
+    pub fn new(params: QueryCacheParams) -> Self {
+        QueryCache {
+            params,
+            ..QueryCache::default()
+        }
+    }
+
+    pub fn params(&self) -> QueryCacheParams {
+        self.params
+    }
+
+    ///
+    /// Look up the embedding of a normalized query, counting the hit or miss.
+    ///
+    /// # Arguments
+    /// * `key` - The normalized query text.
+    ///
+    /// # Returns
+    /// * `Option<Embeddings>` - The embedding, if it is cached and has not expired.
+    ///
+    pub fn get(&self, key: &str) -> Option<Embeddings> {
+        if self.params.capacity == 0 {
+            return None;
+        }
+        let found = {
+            let mut entries = self.lock();
+            let expired = entries.queries.get(key).is_some_and(|entry| self.is_expired(entry));
+            if expired {
+                entries.remove(key);
+            }
+            entries.touch(key)
+        };
+        let counter = if found.is_some() { &self.hits } else { &self.misses };
+        counter.fetch_add(1, AtomicOrdering::Relaxed);
+        found
+    }
+
+    ///
+    /// Keep the embedding of a normalized query, evicting the least recently used query when full.
+    ///
+    /// # Arguments
+    /// * `key` - The normalized query text.
+    /// * `embedding` - The embedding of the query.
+    ///
+    pub fn insert(&self, key: String, embedding: Embeddings) {
+        if self.params.capacity == 0 {
+            return;
+        }
+        let mut entries = self.lock();
+        entries.remove(&key);
+        while entries.queries.len() >= self.params.capacity {
+            let Some((_, oldest)) = entries.recency.pop_first() else {
+                break;
+            };
+            entries.queries.remove(&oldest);
+        }
+        entries.clock += 1;
+        let used = entries.clock;
+        entries.recency.insert(used, key.clone());
+        entries.queries.insert(
+            key,
+            Entry {
+                embedding,
+                inserted: Instant::now(),
+                used,
+            },
+        );
+    }
+
+    ///
+    /// Drop every cached query, keeping the counters.
+    ///
+    pub fn clear(&self) {
+        let mut entries = self.lock();
+        entries.queries.clear();
+        entries.recency.clear();
+    }
+
+    pub fn stats(&self) -> QueryCacheStats {
+        QueryCacheStats {
+            hits: self.hits.load(AtomicOrdering::Relaxed),
+            misses: self.misses.load(AtomicOrdering::Relaxed),
+            entries: self.lock().queries.len(),
+            capacity: self.params.capacity,
+        }
+    }
+
+    fn is_expired(&self, entry: &Entry) -> bool {
+        self.params.ttl.is_some_and(|ttl| entry.inserted.elapsed() > ttl)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Entries> {
+        // The entries are always left consistent, so a panic elsewhere while holding the lock leaves nothing to repair
+        self.entries.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Entries {
+    ///
+    /// Mark a query as just used and return its embedding.
+    ///
+    fn touch(&mut self, key: &str) -> Option<Embeddings> {
+        self.clock += 1;
+        let used = self.clock;
+        let entry = self.queries.get_mut(key)?;
+        self.recency.remove(&entry.used);
+        self.recency.insert(used, key.to_string());
+        entry.used = used;
+        Some(entry.embedding.clone())
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.queries.remove(key) {
+            self.recency.remove(&entry.used);
+        }
+    }
+}
+
+///
+/// Normalize a query into its cache key: trimmed, lower case, with runs of whitespace collapsed to one space.
+///
+/// # Arguments
+/// * `query` - The query as it was asked.
+///
+/// # Returns
+/// * `String` - The normalized query.
+///
+pub fn normalize_query(query: &str) -> String {
+    query.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(capacity: usize, ttl: Option<Duration>) -> QueryCache {
+        QueryCache::new(QueryCacheParams { capacity, ttl })
+    }
+
+    #[test]
+    fn queries_are_normalized() {
+        assert_eq!(normalize_query("  What IS\tCRM \n"), "what is crm");
+    }
+
+    #[test]
+    fn the_least_recently_used_query_is_evicted() {
+        let cache = cache(2, None);
+        cache.insert("a".to_string(), vec![1.0]);
+        cache.insert("b".to_string(), vec![2.0]);
+        assert_eq!(cache.get("a"), Some(vec![1.0]));
+        cache.insert("c".to_string(), vec![3.0]);
+        assert_eq!(cache.get("b"), None);
+        assert_eq!(cache.get("a"), Some(vec![1.0]));
+        assert_eq!(cache.get("c"), Some(vec![3.0]));
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (3, 1, 2));
+    }
+
+    #[test]
+    fn expired_queries_are_misses() {
+        let cache = cache(2, Some(Duration::ZERO));
+        cache.insert("a".to_string(), vec![1.0]);
+        std::thread::sleep(Duration::from_millis(1));
+        assert_eq!(cache.get("a"), None);
+        assert_eq!(cache.stats().entries, 0);
+    }
+
+    #[test]
+    fn a_zero_capacity_cache_keeps_nothing() {
+        let cache = cache(0, None);
+        cache.insert("a".to_string(), vec![1.0]);
+        assert_eq!(cache.get("a"), None);
+        assert_eq!(cache.stats(), QueryCacheStats { hits: 0, misses: 0, entries: 0, capacity: 0 });
+    }
+}
@@ -0,0 +1,52 @@
+/*
+ *
+ * Rerank rescores the best candidates of a first stage search with a cross-encoder, which reads the query and a
+ * passage together and orders them far more finely than the cosine of two separately embedded vectors.
+ *
+ */
+
+use anyhow::Result;
+
+pub const DEFAULT_RERANK_CANDIDATES: usize = 20;
+
+///
+/// CrossEncoder is the interface every reranking backend implements.
+///
+pub trait CrossEncoder: Send {
+    ///
+    /// Score how relevant every text is to the query.
+    ///
+    /// # Arguments
+    /// * `query` - The query the texts are scored against.
+    /// * `texts` - The texts to score.
+    ///
+    /// # Returns
+    /// A Result containing one relevance score between 0 and 1 per text, in the same order as the input.
+    ///
+    fn score(&self, query: &str, texts: &[&str]) -> Result<Vec<f32>>;
+
+    ///
+    /// A stable identifier for the underlying model.
+    ///
+    fn model_id(&self) -> &str;
+}
+
+///
+/// How a search is reranked.
+///
+/// # Fields
+/// * `candidates` - How many of the best first stage pages are rescored, the rest are dropped. Bounds the latency
+///   since every candidate is a full pass through the cross-encoder
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RerankParams {
+    pub candidates: usize,
+}
+
+impl Default for RerankParams {
+    fn default() -> Self {
+        RerankParams {
+            candidates: DEFAULT_RERANK_CANDIDATES,
+        }
+    }
+}
@@ -0,0 +1,558 @@
+/*
+ *
+ * Store holds page embeddings as rows addressed by page id, one row per passage of a page.
+ * Rows keep a stable position so indexes built over the store, like the ANN graph, can refer to them by number.
+ *
+ * Rows are unit normalized on the way in and packed into one contiguous row-major matrix,
+ * so scoring a query is a single dot product pass over the matrix instead of a cosine per page.
+ * With the `parallel` feature large stores are scored across threads.
+ *
+ * The matrix may be quantized to f16 or int8, in which case a full precision copy can be kept alongside
+ * to rescore the best quantized candidates. Either may be viewed in place in a memory-mapped index file.
+ *
+ */
+
+use crate::corpus::Embeddings;
+use crate::engine::PageScore;
+use crate::mapped::Buffer;
+use crate::quantize::{Matrix, PreparedQuery, Quantization, QuantizationParams};
+use anyhow::Result;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::ops::Range;
+
+// Accumulator lanes of the dot product, wide enough for the compiler to vectorize into AVX registers
+const LANES: usize = 8;
+
+// Below this many rows splitting the pass across threads costs more than it saves
+#[cfg(feature = "parallel")]
+const PARALLEL_MIN_ROWS: usize = 4096;
+
+///
+/// EmbeddingStore keeps one unit normalized embedding row per passage, addressable by row number or by `Page.id`.
+/// The passages of a page sit in consecutive rows.
+///
+/// # Fields
+/// * `ids` - The `Page.id` of every row
+/// * `dimension` - The length of every row
+/// * `matrix` - The rows back to back, `dimension` values each, in the storage mode
+/// * `full` - The f32 rows back to back, kept for rescoring when the matrix is quantized
+/// * `row_index` - The rows of every page, keyed by `Page.id`
+///
+#[derive(Debug, Default, Clone)]
+pub struct EmbeddingStore {
+    ids: Vec<i64>,
+    dimension: usize,
+    matrix: Matrix,
+    full: Option<Buffer<f32>>,
+    row_index: HashMap<i64, Range<usize>>,
+}
+
+impl EmbeddingStore {
+    ///
+    /// Create a full precision store from page ids and their embeddings, in row order. Every row is unit normalized.
+    ///
+    /// # Arguments
+    /// * `ids` - The page id of every row.
+    /// * `rows` - The embedding of every row.
+    ///
+    /// # Returns
+    /// * `Result<EmbeddingStore>` - The store.
+    ///
+    /// # Errors
+    /// * `Error` - If the lengths differ, the rows of a page are not consecutive or the rows do not share a dimension.
+    ///
+    pub fn from_rows(ids: Vec<i64>, rows: Vec<Embeddings>) -> Result<Self> {
+        EmbeddingStore::quantized(ids, rows, QuantizationParams::default())
+    }
+
+    ///
+    /// Create a store from page ids and their embeddings, in row order, stored as the params select.
+    /// Every row is unit normalized before it is quantized.
+    ///
+    /// # Arguments
+    /// * `ids` - The page id of every row.
+    /// * `rows` - The embedding of every row.
+    /// * `params` - The storage mode, and whether to keep the f32 rows for rescoring.
+    ///
+    /// # Returns
+    /// * `Result<EmbeddingStore>` - The store.
+    ///
+    /// # Errors
+    /// * `Error` - If the lengths differ, the rows of a page are not consecutive or the rows do not share a dimension.
+    ///
+    pub fn quantized(ids: Vec<i64>, rows: Vec<Embeddings>, params: QuantizationParams) -> Result<Self> {
+        if ids.len() != rows.len() {
+            return Err(anyhow::anyhow!("{} page ids given for {} embeddings", ids.len(), rows.len()));
+        }
+        let dimension = rows.first().map_or(0, |row| row.len());
+        if rows.iter().any(|row| row.len() != dimension) {
+            return Err(anyhow::anyhow!("Embeddings do not share a dimension"));
+        }
+        let row_index = row_index(&ids)?;
+
+        let mut values = Vec::with_capacity(rows.len() * dimension);
+        for row in rows {
+            let start = values.len();
+            values.extend_from_slice(&row);
+            normalize(&mut values[start..]);
+        }
+        let full = (params.mode != Quantization::F32 && params.rescore > 0).then(|| values.clone().into());
+        Ok(EmbeddingStore {
+            ids,
+            dimension,
+            matrix: Matrix::quantize(params.mode, values, dimension),
+            full,
+            row_index,
+        })
+    }
+
+    ///
+    /// Create a store over a matrix of unit normalized rows as read or mapped from an index, without copying it
+    /// when it is already in the storage mode the params select.
+    ///
+    /// # Arguments
+    /// * `ids` - The page id of every row.
+    /// * `dimension` - The length of every row.
+    /// * `stored` - The rows as they were stored in the index.
+    /// * `params` - The storage mode, and whether to keep the f32 rows for rescoring.
+    ///
+    /// # Returns
+    /// * `Result<EmbeddingStore>` - The store.
+    ///
+    /// # Errors
+    /// * `Error` - If the rows of a page are not consecutive, or the stored rows are quantized differently or too lossily to serve the params.
+    ///
+    pub(crate) fn from_matrix(ids: Vec<i64>, dimension: usize, stored: Matrix, params: QuantizationParams) -> Result<Self> {
+        let row_index = row_index(&ids)?;
+        let (matrix, full) = match stored {
+            Matrix::F32(values) if params.mode == Quantization::F32 => (Matrix::F32(values), None),
+            // Only the quantized copy is built in memory, the f32 rows stay wherever they already are
+            Matrix::F32(values) => (
+                Matrix::quantize(params.mode, values.to_vec(), dimension),
+                (params.rescore > 0).then_some(values),
+            ),
+            stored if stored.mode() == params.mode && params.rescore == 0 => (stored, None),
+            stored => {
+                return Err(anyhow::anyhow!(
+                    "Embeddings stored as {} cannot be served as {} with rescoring {}",
+                    stored.mode(),
+                    params.mode,
+                    params.rescore
+                ));
+            }
+        };
+        Ok(EmbeddingStore {
+            ids,
+            dimension,
+            matrix,
+            full,
+            row_index,
+        })
+    }
+
+    ///
+    /// The number of rows in the store.
+    ///
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    ///
+    /// Whether the store holds no rows.
+    ///
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    ///
+    /// The length of every row, 0 for an empty store.
+    ///
+    pub fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    ///
+    /// How the rows are stored.
+    ///
+    pub fn quantization(&self) -> Quantization {
+        self.matrix.mode()
+    }
+
+    ///
+    /// Whether `vector` returns the f32 rows rather than dequantized approximations of them.
+    ///
+    pub fn is_full_precision(&self) -> bool {
+        self.full.is_some() || self.matrix.mode() == Quantization::F32
+    }
+
+    ///
+    /// Whether the rows are viewed in a memory-mapped index rather than held on the heap.
+    ///
+    pub fn is_mapped(&self) -> bool {
+        self.matrix.is_mapped() || self.full.as_ref().is_some_and(|full| full.is_mapped())
+    }
+
+    ///
+    /// The page id of a row.
+    ///
+    pub fn id(&self, row: usize) -> i64 {
+        self.ids[row]
+    }
+
+    ///
+    /// The page ids of every row, in row order.
+    ///
+    pub fn ids(&self) -> &[i64] {
+        &self.ids
+    }
+
+    ///
+    /// The unit normalized embedding of a row, dequantized unless the store is full precision.
+    ///
+    pub fn vector(&self, row: usize) -> Cow<'_, [f32]> {
+        match &self.full {
+            Some(full) => Cow::Borrowed(&full[row * self.dimension..(row + 1) * self.dimension]),
+            None => self.matrix.row(row, self.dimension),
+        }
+    }
+
+    ///
+    /// The rows holding the passages of a page, if the store holds any for it.
+    ///
+    pub fn rows(&self, id: i64) -> Option<Range<usize>> {
+        self.row_index.get(&id).cloned()
+    }
+
+    ///
+    /// The position of a row among the passages of its page.
+    ///
+    pub fn passage(&self, row: usize) -> usize {
+        self.row_index.get(&self.ids[row]).map_or(0, |rows| row - rows.start)
+    }
+
+    ///
+    /// Remove every row.
+    ///
+    pub fn clear(&mut self) {
+        self.ids.clear();
+        self.dimension = 0;
+        self.matrix = Matrix::default();
+        self.full = None;
+        self.row_index.clear();
+    }
+
+    ///
+    /// Score every row against a query by exhaustive cosine similarity.
+    ///
+    /// # Arguments
+    /// * `query` - The query embedding, of the same dimension as the rows. It does not need to be normalized.
+    ///
+    /// # Returns
+    /// * `Vec<PageScore>` - The similarity of every row, carrying its passage, in row order.
+    ///
+    pub fn scores(&self, query: &[f32]) -> Vec<PageScore> {
+        let query = self.prepare(query);
+        self.similarities(&query)
+            .into_iter()
+            .enumerate()
+            .map(|(row, similarity)| self.row_score(row, similarity))
+            .collect()
+    }
+
+    ///
+    /// Score every row against many queries in one pass over the matrix, so each row is read once for all of them.
+    ///
+    /// # Arguments
+    /// * `queries` - The query embeddings, of the same dimension as the rows. They do not need to be normalized.
+    ///
+    /// # Returns
+    /// * `Vec<Vec<PageScore>>` - For every query in order, the similarity of every row in row order.
+    ///
+    pub fn scores_batch<Q: AsRef<[f32]>>(&self, queries: &[Q]) -> Vec<Vec<PageScore>> {
+        let queries: Vec<PreparedQuery> = queries.iter().map(|query| self.prepare(query.as_ref())).collect();
+        let similarities = self.batch_similarities(&queries);
+        (0..queries.len())
+            .map(|query| {
+                (0..self.len())
+                    .map(|row| self.row_score(row, similarities[row * queries.len() + query]))
+                    .collect()
+            })
+            .collect()
+    }
+
+    ///
+    /// Replace the quantized similarity of the best candidates with their f32 cosine similarity.
+    /// Does nothing unless the store keeps f32 rows beside a quantized matrix.
+    ///
+    /// # Arguments
+    /// * `query` - The query embedding the scores were computed for.
+    /// * `scores` - Passage scores of rows in this store, in any order.
+    /// * `candidates` - How many of the best scores to rescore.
+    ///
+    pub fn rescore(&self, query: &[f32], scores: &mut [PageScore], candidates: usize) {
+        let Some(full) = &self.full else {
+            return;
+        };
+        if candidates == 0 || scores.is_empty() {
+            return;
+        }
+        let mut query = query.to_vec();
+        normalize(&mut query);
+
+        let mut order: Vec<usize> = (0..scores.len()).collect();
+        if candidates < order.len() {
+            order.select_nth_unstable_by(candidates, |&a, &b| scores[b].cmp(&scores[a]));
+            order.truncate(candidates);
+        }
+        for index in order {
+            if let (Some(rows), Some(passage)) = (self.row_index.get(&scores[index].id), scores[index].passage)
+                && passage < rows.len()
+            {
+                let row = rows.start + passage;
+                scores[index].similarity = dot(&query, &full[row * self.dimension..(row + 1) * self.dimension]);
+            }
+        }
+    }
+
+    ///
+    /// The score of a row, addressed by its page and passage.
+    ///
+    pub(crate) fn row_score(&self, row: usize, similarity: f32) -> PageScore {
+        PageScore {
+            id: self.ids[row],
+            similarity,
+            passage: Some(self.passage(row)),
+        }
+    }
+
+    ///
+    /// Normalize a query and turn it into the form the matrix scores with.
+    ///
+    pub(crate) fn prepare(&self, query: &[f32]) -> PreparedQuery {
+        let mut query = query.to_vec();
+        normalize(&mut query);
+        self.matrix.prepare(query)
+    }
+
+    ///
+    /// The similarity of a prepared query to a row.
+    ///
+    pub(crate) fn similarity(&self, query: &PreparedQuery, row: usize) -> f32 {
+        self.matrix.similarity(query, row, self.dimension)
+    }
+
+    ///
+    /// Score a prepared query against every row, in row order.
+    ///
+    fn similarities(&self, query: &PreparedQuery) -> Vec<f32> {
+        #[cfg(feature = "parallel")]
+        if self.len() >= PARALLEL_MIN_ROWS {
+            use rayon::prelude::*;
+            return (0..self.len())
+                .into_par_iter()
+                .map(|row| self.similarity(query, row))
+                .collect();
+        }
+        (0..self.len()).map(|row| self.similarity(query, row)).collect()
+    }
+
+    ///
+    /// Score prepared queries against every row, row major: the scores of every query for a row sit together.
+    ///
+    fn batch_similarities(&self, queries: &[PreparedQuery]) -> Vec<f32> {
+        let row_similarities = |row: usize| queries.iter().map(move |query| self.similarity(query, row));
+        #[cfg(feature = "parallel")]
+        if self.len() >= PARALLEL_MIN_ROWS {
+            use rayon::prelude::*;
+            return (0..self.len())
+                .into_par_iter()
+                .flat_map_iter(row_similarities)
+                .collect();
+        }
+        (0..self.len()).flat_map(row_similarities).collect()
+    }
+}
+
+///
+/// Index the rows of every page, which have to be consecutive.
+///
+fn row_index(ids: &[i64]) -> Result<HashMap<i64, Range<usize>>> {
+    let mut row_index: HashMap<i64, Range<usize>> = HashMap::with_capacity(ids.len());
+    for (row, &id) in ids.iter().enumerate() {
+        match row_index.get_mut(&id) {
+            Some(rows) if rows.end == row => rows.end = row + 1,
+            Some(_) => return Err(anyhow::anyhow!("Rows of page id {} are not consecutive", id)),
+            None => {
+                row_index.insert(id, row..row + 1);
+            }
+        }
+    }
+    Ok(row_index)
+}
+
+///
+/// Scale a vector to unit length in place, an all zero vector is left as is.
+///
+/// # Arguments
+/// * `vector` - The vector to normalize.
+///
+pub fn normalize(vector: &mut [f32]) {
+    let norm = dot(vector, vector).sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|x| *x /= norm);
+    }
+}
+
+///
+/// Calculate the dot product of two `f32` vectors of the same length, which is their cosine similarity
+/// when both are unit normalized.
+///
+/// Sums into `LANES` independent accumulators so the loop vectorizes, then adds the remainder.
+///
+/// # Arguments
+/// * `a` - The first vector.
+/// * `b` - The second vector.
+///
+/// # Returns
+/// * `f32` - The dot product of the two vectors.
+///
+pub fn dot(a: &[f32], b: &[f32]) -> f32 {
+    let a_chunks = a.chunks_exact(LANES);
+    let b_chunks = b.chunks_exact(LANES);
+    let remainder: f32 = a_chunks
+        .remainder()
+        .iter()
+        .zip(b_chunks.remainder())
+        .map(|(x, y)| x * y)
+        .sum();
+
+    let mut sums = [0.0f32; LANES];
+    for (a, b) in a_chunks.zip(b_chunks) {
+        for ((sum, x), y) in sums.iter_mut().zip(a).zip(b) {
+            *sum += x * y;
+        }
+    }
+    sums.iter().sum::<f32>() + remainder
+}
+
+///
+/// Calculate the cosine similarity between two `f32` vectors of the same length.
+///
+/// # Arguments
+/// * `a` - The first vector.
+/// * `b` - The second vector.
+///
+/// # Returns
+/// * `f32` - The cosine similarity between the two vectors, 0 when either is all zeros.
+///
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let norm_a = dot(a, a).sqrt();
+    let norm_b = dot(b, b).sqrt();
+
+    if norm_a == 0.0 || norm_b == 0.0 {
+        0.0
+    } else {
+        dot(a, b) / (norm_a * norm_b)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quantize::Scaling;
+
+    const DIMENSION: usize = 16;
+
+    fn rows(count: usize) -> Vec<Embeddings> {
+        (0..count)
+            .map(|row| (0..DIMENSION).map(|d| ((row * DIMENSION + d) as f32 * 12.9898).sin()).collect())
+            .collect()
+    }
+
+    fn int8(rescore: usize) -> EmbeddingStore {
+        let params = QuantizationParams {
+            mode: Quantization::Int8(Scaling::PerVector),
+            rescore,
+        };
+        EmbeddingStore::quantized(vec![1, 1, 2, 3, 4, 5], rows(6), params).unwrap()
+    }
+
+    #[test]
+    fn rows_are_normalized_and_indexed_by_page() {
+        let store = EmbeddingStore::from_rows(vec![7, 7, 7, 9], rows(4)).unwrap();
+        assert_eq!((store.len(), store.dimension()), (4, DIMENSION));
+        assert!((0..4).all(|row| (dot(&store.vector(row), &store.vector(row)) - 1.0).abs() < 1e-5));
+        assert_eq!(store.rows(7), Some(0..3));
+        assert_eq!(store.rows(8), None);
+        assert_eq!((store.passage(2), store.passage(3)), (2, 0));
+        assert_eq!(store.scores(&rows(1)[0])[0].passage, Some(0));
+    }
+
+    #[test]
+    fn malformed_rows_are_rejected() {
+        assert!(EmbeddingStore::from_rows(vec![1, 2], rows(3)).is_err());
+        assert!(EmbeddingStore::from_rows(vec![1, 2, 1], rows(3)).is_err());
+        assert!(EmbeddingStore::from_rows(vec![1, 2], vec![vec![1.0, 0.0], vec![1.0]]).is_err());
+    }
+
+    #[test]
+    fn scores_are_cosine_similarities_in_row_order() {
+        let rows = rows(5);
+        let store = EmbeddingStore::from_rows((0..5).collect(), rows.clone()).unwrap();
+        let query: Vec<f32> = rows[3].iter().map(|value| value * 4.0).collect();
+        let scores = store.scores(&query);
+        assert_eq!(scores.iter().map(|score| score.id).collect::<Vec<_>>(), vec![0, 1, 2, 3, 4]);
+        for (score, row) in scores.iter().zip(&rows) {
+            assert!((score.similarity - cosine_similarity(&query, row)).abs() < 1e-5);
+        }
+        assert!((scores[3].similarity - 1.0).abs() < 1e-5);
+
+        let batch = store.scores_batch(&[query.clone(), rows[0].clone()]);
+        assert_eq!(batch[0], scores);
+        assert_eq!(batch[1], store.scores(&rows[0]));
+    }
+
+    #[test]
+    fn rescoring_replaces_the_best_candidates_with_f32_similarities() {
+        let store = int8(2);
+        assert!(store.is_full_precision());
+        let query = &rows(7)[6];
+        let quantized = store.scores(query);
+        let mut rescored = quantized.clone();
+        store.rescore(query, &mut rescored, 2);
+
+        let mut best: Vec<usize> = (0..quantized.len()).collect();
+        best.sort_by(|&a, &b| quantized[b].cmp(&quantized[a]));
+        for (rank, &index) in best.iter().enumerate() {
+            let exact = cosine_similarity(query, &rows(6)[index]);
+            if rank < 2 {
+                assert!((rescored[index].similarity - exact).abs() < 1e-5);
+            } else {
+                assert_eq!(rescored[index].similarity, quantized[index].similarity);
+            }
+        }
+    }
+
+    #[test]
+    fn rescoring_without_f32_rows_does_nothing() {
+        let store = int8(0);
+        assert!(!store.is_full_precision());
+        let query = &rows(7)[6];
+        let quantized = store.scores(query);
+        let mut rescored = quantized.clone();
+        store.rescore(query, &mut rescored, 6);
+        assert_eq!(
+            rescored.iter().map(|score| score.similarity).collect::<Vec<_>>(),
+            quantized.iter().map(|score| score.similarity).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn cosine_of_a_zero_vector_is_zero() {
+        assert_eq!(cosine_similarity(&[0.0, 0.0], &[1.0, 0.0]), 0.0);
+        let mut zero = vec![0.0; 3];
+        normalize(&mut zero);
+        assert_eq!(zero, vec![0.0; 3]);
+    }
+}
@@ -8,7 +8,7 @@
 
 use crate::consts::{BIT_MAX_RESULTS, BIT_TEMPERATURE, BIT_TEST_PAGE_NAMES};
 use anyhow::Result;
-use docueyes::engine::Engine;
+use docueyes::engine::{Engine, ResolveLevel};
 
 pub fn run(engine: &Engine) -> Result<()> {
     bit_1(engine)?;
@@ -17,9 +17,9 @@ pub fn run(engine: &Engine) -> Result<()> {
 
 fn bit_1(engine: &Engine) -> Result<()> {
     let search_return = engine.search("Salesforce use AI")?;
-    let resolved_pages = engine.resolve(search_return, BIT_TEMPERATURE, BIT_MAX_RESULTS);
-    for page in resolved_pages {
-        if !BIT_TEST_PAGE_NAMES.contains(&page.name.as_str()) {
+    let resolved_pages = engine.resolve(search_return, BIT_TEMPERATURE, BIT_MAX_RESULTS, ResolveLevel::To(0));
+    for result in resolved_pages {
+        if !BIT_TEST_PAGE_NAMES.contains(&result.page.name.as_str()) {
         }
     }
     Ok(())
@@ -0,0 +1,213 @@
+/*
+ *
+ * Config builds the engine configuration from the environment.
+ *
+ */
+
+use crate::consts::{
+    AGGREGATION_VAR, BATCH_SIZE_VAR, CHUNK_OVERLAP_VAR, CHUNK_TOKENS_VAR, CHUNKING_VAR, FIELDS_VAR, INSTANCES_VAR,
+    MAX_RERANK_CANDIDATES, MODEL_CACHE_VAR, MODEL_DIR_VAR, MODEL_TYPE_VAR, NAME_WEIGHT_VAR, OFFLINE_VAR,
+    QUANTIZATION_VAR, QUERY_CACHE_TTL_VAR, QUERY_CACHE_VAR, RERANK_CANDIDATES_VAR, RESCORE_VAR, THREADS_VAR,
+    VERIFY_INDEX_VAR,
+};
+#[cfg(feature = "rerank")]
+use crate::consts::RERANK_MODEL_VAR;
+use anyhow::Result;
+use docueyes::bert::{ModelConfig, ModelSource};
+use docueyes::chunk::{Aggregation, ChunkParams};
+#[cfg(feature = "rerank")]
+use docueyes::cross_encoder::CrossEncoderConfig;
+use docueyes::fields::{DEFAULT_NAME_WEIGHT, FieldStrategy};
+use docueyes::model::BatchParams;
+use docueyes::quantize::QuantizationParams;
+use docueyes::query_cache::QueryCacheParams;
+use docueyes::rerank::DEFAULT_RERANK_CANDIDATES;
+use std::env;
+use std::path::PathBuf;
+use std::time::Duration;
+
+///
+/// Build the model configuration from the environment.
+///
+/// A local model directory wins over a pinned cache directory, and offline mode is enabled by any of `1`, `true` or `yes`.
+/// Batch size, model instances and intra-op threads keep their defaults unless set.
+///
+/// # Returns
+/// - config `ModelConfig` the model configuration to hand to the engine
+///
+/// # Errors
+/// - If the selected model name is unknown, or a count is not a number
+///
+pub fn model_config() -> Result<ModelConfig> {
+    let defaults = ModelConfig::default();
+    let model_type = match env::var(MODEL_TYPE_VAR) {
+        Ok(name) => name.parse()?,
+        Err(_) => defaults.model_type,
+    };
+    let source = match (env::var_os(MODEL_DIR_VAR), env::var_os(MODEL_CACHE_VAR)) {
+        (Some(model_dir), _) => ModelSource::Local(PathBuf::from(model_dir)),
+        (None, cache_dir) => ModelSource::Remote {
+            cache_dir: cache_dir.map(PathBuf::from),
+        },
+    };
+    Ok(ModelConfig {
+        model_type,
+        source,
+        offline: env_flag(OFFLINE_VAR),
+        batch: BatchParams {
+            batch_size: env_count(BATCH_SIZE_VAR)?.unwrap_or(defaults.batch.batch_size),
+            ..defaults.batch
+        },
+        instances: env_count(INSTANCES_VAR)?.unwrap_or(defaults.instances),
+        intra_op_threads: env_count(THREADS_VAR)?,
+    })
+}
+
+///
+/// Build the embedding storage configuration from the environment.
+///
+/// The quantization defaults to full precision f32 and rescoring is off unless a candidate count is given.
+///
+/// # Returns
+/// - params `QuantizationParams` how the engine should store and score embeddings
+///
+/// # Errors
+/// - If the quantization name is unknown or the rescore count is not a number
+///
+pub fn quantization_params() -> Result<QuantizationParams> {
+    let mode = match env::var(QUANTIZATION_VAR) {
+        Ok(name) => name.parse()?,
+        Err(_) => QuantizationParams::default().mode,
+    };
+    let rescore = env_count(RESCORE_VAR)?.unwrap_or(0);
+    Ok(QuantizationParams { mode, rescore })
+}
+
+///
+/// Whether a memory-mapped embeddings index should have its checksum verified before it is served, off unless enabled
+/// by any of `1`, `true` or `yes`.
+///
+/// # Returns
+/// - verify `bool` whether to verify mapped indexes
+///
+pub fn verify_index() -> bool {
+    env_flag(VERIFY_INDEX_VAR)
+}
+
+///
+/// Build the passage chunking configuration from the environment.
+///
+/// Pages are embedded whole unless a strategy is given, and a page scores as its best passage unless an aggregation is given.
+///
+/// # Returns
+/// - (params, aggregation) `(ChunkParams, Aggregation)` how the engine should split pages and combine passage scores
+///
+/// # Errors
+/// - If the strategy or aggregation is unknown, or a count is not a number
+///
+pub fn chunk_params() -> Result<(ChunkParams, Aggregation)> {
+    let defaults = ChunkParams::default();
+    let strategy = match env::var(CHUNKING_VAR) {
+        Ok(name) => name.parse()?,
+        Err(_) => defaults.strategy,
+    };
+    let aggregation = match env::var(AGGREGATION_VAR) {
+        Ok(name) => name.parse()?,
+        Err(_) => Aggregation::default(),
+    };
+    let params = ChunkParams {
+        strategy,
+        max_tokens: env_count(CHUNK_TOKENS_VAR)?.unwrap_or(defaults.max_tokens),
+        overlap: env_count(CHUNK_OVERLAP_VAR)?.unwrap_or(defaults.overlap),
+    };
+    Ok((params, aggregation))
+}
+
+///
+/// Build the embedded field configuration from the environment.
+///
+/// Only the body is embedded unless a strategy is given. The name weight only matters when names are embedded separately.
+///
+/// # Returns
+/// - (fields, name_weight) `(FieldStrategy, f32)` which page fields to embed and the share of the name in a page score
+///
+/// # Errors
+/// - If the strategy is unknown, or the weight is not a number between 0 and 1
+///
+pub fn field_params() -> Result<(FieldStrategy, f32)> {
+    let fields = match env::var(FIELDS_VAR) {
+        Ok(name) => name.parse()?,
+        Err(_) => FieldStrategy::default(),
+    };
+    let name_weight = match env::var(NAME_WEIGHT_VAR) {
+        Ok(weight) => match weight.parse::<f32>() {
+            Ok(weight) if (0.0..=1.0).contains(&weight) => weight,
+            _ => return Err(anyhow::anyhow!("{} must be a number between 0 and 1", NAME_WEIGHT_VAR)),
+        },
+        Err(_) => DEFAULT_NAME_WEIGHT,
+    };
+    Ok((fields, name_weight))
+}
+
+///
+/// Build the cross-encoder configuration from the environment.
+///
+/// # Returns
+/// - config `Option<CrossEncoderConfig>` where to load the reranking model from, `None` when reranking is not configured
+///
+#[cfg(feature = "rerank")]
+pub fn cross_encoder_config() -> Option<CrossEncoderConfig> {
+    env::var_os(RERANK_MODEL_VAR).map(CrossEncoderConfig::new)
+}
+
+///
+/// Read how many candidates a search reranks when the request does not say.
+///
+/// # Returns
+/// - candidates `usize` the configured count, capped at `MAX_RERANK_CANDIDATES`
+///
+/// # Errors
+/// - If the count is not a number
+///
+pub fn rerank_candidates() -> Result<usize> {
+    let candidates = env_count(RERANK_CANDIDATES_VAR)?.unwrap_or(DEFAULT_RERANK_CANDIDATES);
+    Ok(candidates.min(MAX_RERANK_CANDIDATES))
+}
+
+///
+/// Build the query embedding cache configuration from the environment.
+///
+/// The cache keeps its default capacity unless one is given, 0 turns it off, and queries never expire unless a TTL in seconds is given.
+///
+/// # Returns
+/// - params `QueryCacheParams` how many query embeddings the engine keeps and for how long
+///
+/// # Errors
+/// - If the capacity or TTL is not a number
+///
+pub fn query_cache_params() -> Result<QueryCacheParams> {
+    let defaults = QueryCacheParams::default();
+    Ok(QueryCacheParams {
+        capacity: env_count(QUERY_CACHE_VAR)?.unwrap_or(defaults.capacity),
+        ttl: env_count(QUERY_CACHE_TTL_VAR)?.map(|seconds| Duration::from_secs(seconds as u64)),
+    })
+}
+
+///
+/// Read a count from the environment, `None` when it is not set.
+///
+fn env_count(name: &str) -> Result<Option<usize>> {
+    match env::var(name) {
+        Ok(count) => count
+            .parse()
+            .map(Some)
+            .map_err(|e| anyhow::anyhow!("{} must be a count: {}", name, e)),
+        Err(_) => Ok(None),
+    }
+}
+
+fn env_flag(name: &str) -> bool {
+    env::var(name)
+        .map(|value| matches!(value.to_lowercase().as_str(), "1" | "true" | "yes"))
+        .unwrap_or(false)
+}
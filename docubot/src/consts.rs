@@ -1,4 +1,5 @@
 use lazy_static::lazy_static;
+use std::time::Duration;
 
 // BIT specific constants
 pub const BIT_TEMPERATURE: f32 = 0.34; // 0.34
@@ -23,11 +24,72 @@ pub const BANNER: &str = r"
           **<<Kilroy Was Here>>**
 ";
 pub const TEMPERATURE: f32 = 0.34;
+// Lexical and fused scores are not cosine similarities, anything that matched at all is kept
+pub const FUSED_TEMPERATURE: f32 = 0.0;
+// Reranked scores are cross-encoder relevance probabilities, so they clear a threshold of their own
+pub const RERANK_TEMPERATURE: f32 = 0.1;
+// Upper bound on the candidates a single request may rerank, every one is a full cross-encoder pass
+pub const MAX_RERANK_CANDIDATES: usize = 100;
 pub const MAX_RESULTS: usize = 1111;
-pub const EMBEDDINGS_PATH: &str = "embeddings.txt";
+pub const EMBEDDINGS_PATH: &str = "embeddings.bin";
+pub const LEGACY_EMBEDDINGS_PATH: &str = "embeddings.txt";
+pub const LEXICAL_INDEX_PATH: &str = "lexical.json";
+pub const BM25_K1: f32 = 1.2;
+pub const BM25_B: f32 = 0.75;
+pub const ANN_INDEX_PATH: &str = "embeddings.hnsw";
+pub const ANN_MIN_PAGES: usize = 10_000;
+pub const ANN_RECALL_QUERIES: usize = 50;
+pub const HNSW_M: usize = 16;
+pub const HNSW_EF_CONSTRUCTION: usize = 200;
+pub const HNSW_EF_SEARCH: usize = 64;
 pub const SERVER_LOCATION: &str = "0.0.0.0:8080";
+pub const OK: u16 = 200;
+// Answered to requests with a missing, malformed or invalid query or parameter
+pub const BAD_REQUEST: u16 = 400;
+// Answered to requests for similar pages of a page id the corpus does not hold
+pub const NOT_FOUND: u16 = 404;
 pub const MAX_QUERY_LENGTH: usize = 512;
 pub const MIN_QUERY_LENGTH: usize = 10;
+// Most queries a single batch search request may carry, the CLI batch mode reads any number
+pub const MAX_BATCH_QUERIES: usize = 1000;
+// Related pages returned by /pages/{id}/similar unless the request asks for another count
+pub const SIMILAR_RESULTS: usize = 10;
 pub const CORPUS_PATH: &str = "corpus.json";
 pub const SERVER_SPIN_UP_ATTEMPTS: u64 = 10;
+// How often build progress is logged when there is no terminal to draw a progress bar on
+pub const PROGRESS_LOG_INTERVAL: Duration = Duration::from_secs(10);
+// Truncated passages named one by one when embeddings are built, the token report always lists them all
+pub const TRUNCATION_LOG_LIMIT: usize = 20;
 
+
+// Model selection and source configuration, read from the environment at startup
+pub const MODEL_TYPE_VAR: &str = "DOCUBOT_MODEL";
+pub const MODEL_DIR_VAR: &str = "DOCUBOT_MODEL_DIR";
+pub const MODEL_CACHE_VAR: &str = "DOCUBOT_MODEL_CACHE";
+pub const OFFLINE_VAR: &str = "DOCUBOT_OFFLINE";
+pub const BATCH_SIZE_VAR: &str = "DOCUBOT_BATCH_SIZE";
+pub const INSTANCES_VAR: &str = "DOCUBOT_MODEL_INSTANCES";
+pub const THREADS_VAR: &str = "DOCUBOT_THREADS";
+
+// Embedding storage, read from the environment at startup
+pub const QUANTIZATION_VAR: &str = "DOCUBOT_QUANTIZATION";
+pub const RESCORE_VAR: &str = "DOCUBOT_RESCORE";
+pub const VERIFY_INDEX_VAR: &str = "DOCUBOT_VERIFY_INDEX";
+
+// Passage chunking, read from the environment at startup
+pub const CHUNKING_VAR: &str = "DOCUBOT_CHUNKING";
+pub const CHUNK_TOKENS_VAR: &str = "DOCUBOT_CHUNK_TOKENS";
+pub const CHUNK_OVERLAP_VAR: &str = "DOCUBOT_CHUNK_OVERLAP";
+pub const AGGREGATION_VAR: &str = "DOCUBOT_AGGREGATION";
+
+// Embedded page fields, read from the environment at startup
+pub const FIELDS_VAR: &str = "DOCUBOT_FIELDS";
+pub const NAME_WEIGHT_VAR: &str = "DOCUBOT_NAME_WEIGHT";
+
+// Cross-encoder reranking, read from the environment at startup
+pub const RERANK_MODEL_VAR: &str = "DOCUBOT_RERANK_MODEL";
+pub const RERANK_CANDIDATES_VAR: &str = "DOCUBOT_RERANK_CANDIDATES";
+
+// Query embedding cache, read from the environment at startup
+pub const QUERY_CACHE_VAR: &str = "DOCUBOT_QUERY_CACHE";
+pub const QUERY_CACHE_TTL_VAR: &str = "DOCUBOT_QUERY_CACHE_TTL";
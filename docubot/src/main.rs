@@ -12,20 +12,37 @@
  */
 
 mod bits;
+mod config;
 mod consts;
 mod server;
 mod logg;
+mod progress;
 
 use colored::*;
 use docueyes::corpus::load_corpus;
-use docueyes::engine::Engine;
+#[cfg(feature = "rerank")]
+use docueyes::cross_encoder::BertCrossEncoder;
+use docueyes::engine::{Engine, RefreshSummary, TruncatedPassage};
+use docueyes::ann::HnswParams;
+use docueyes::lexical::Bm25Params;
+use std::collections::HashMap;
 use std::env;
 use std::fs;
+use std::io::{self, BufWriter, Write};
 use std::sync::{Arc, Mutex};
-use crate::consts::{CORPUS_PATH, EMBEDDINGS_PATH};
+use std::time::Instant;
+use crate::consts::{
+    ANN_INDEX_PATH, ANN_MIN_PAGES, ANN_RECALL_QUERIES, BM25_B, BM25_K1, CORPUS_PATH, EMBEDDINGS_PATH, HNSW_EF_CONSTRUCTION,
+    HNSW_EF_SEARCH, HNSW_M, LEGACY_EMBEDDINGS_PATH, LEXICAL_INDEX_PATH, TRUNCATION_LOG_LIMIT,
+};
+#[cfg(not(feature = "rerank"))]
+use crate::consts::RERANK_MODEL_VAR;
 use crate::logg::Logg;
+use crate::progress::ProgressReporter;
 use crate::server::spinup_server;
 
+// The banner print predates the lints
+#[allow(clippy::print_with_newline, clippy::useless_format)]
 fn main() -> anyhow::Result<()> {
 
     Logg::start_logger("docu-log")?;
@@ -34,48 +51,310 @@ fn main() -> anyhow::Result<()> {
     print!("{}\n", format!("{}", consts::BANNER).purple().bold());
 
     let corpus = load_corpus(CORPUS_PATH)?;
-    let engine = Arc::new(Mutex::new(Engine::new(corpus)));
+    let page_count = corpus.pages.len();
+    let recall_queries: Vec<String> = corpus.pages.iter().take(ANN_RECALL_QUERIES).map(|page| page.name.clone()).collect();
+    let model_config = config::model_config()?;
+    Logg::info(format!(
+        "Loading model {} from {:?} (offline: {}, instances: {}, batch size: {})",
+        model_config.model_type,
+        model_config.source,
+        model_config.offline,
+        model_config.instances,
+        model_config.batch.batch_size
+    ));
+    let quantization = config::quantization_params()?;
+    Logg::info(format!(
+        "Storing embeddings as {} (rescoring {} candidates)",
+        quantization.mode, quantization.rescore
+    ));
+    let (chunking, aggregation) = config::chunk_params()?;
+    Logg::info(format!(
+        "Chunking pages by {} ({} tokens, {} overlap), scoring pages by {}",
+        chunking.strategy, chunking.max_tokens, chunking.overlap, aggregation
+    ));
+    let (fields, name_weight) = config::field_params()?;
+    Logg::info(format!("Embedding {} fields (name weight {})", fields, name_weight));
+    let query_cache = config::query_cache_params()?;
+    match query_cache.ttl {
+        Some(ttl) => Logg::info(format!("Caching up to {} query embeddings for {}s", query_cache.capacity, ttl.as_secs())),
+        None => Logg::info(format!("Caching up to {} query embeddings", query_cache.capacity)),
+    }
+    let mut engine = Engine::new(corpus, &model_config)?;
+    engine.set_quantization(quantization);
+    engine.set_verify_mapped(config::verify_index());
+    engine.set_chunking(chunking);
+    engine.set_aggregation(aggregation);
+    engine.set_fields(fields);
+    engine.set_name_weight(name_weight);
+    engine.set_query_cache(query_cache);
+    load_reranker(&mut engine)?;
+    let rerank_candidates = engine.has_reranker().then(config::rerank_candidates).transpose()?;
+    if let Some(candidates) = rerank_candidates {
+        Logg::info(format!("Reranking the best {} candidates of every search by default", candidates));
+    }
+
+    // Report which pages the model cuts short, then exit without touching the embeddings
+    if args.get(1) == Some(&String::from("--token-report")) {
+        return token_report(&engine);
+    }
+    let engine = Arc::new(Mutex::new(engine));
 
     // Based on file existence and CLI arguments handle loading and compilation of embeddings
     let engine_clone = Arc::clone(&engine);
     if args.get(1) == Some(&String::from("--recompile")) {
-        engine_clone.lock().unwrap().build_embeddings()?;
         Logg::info("Embeddings recompiling triggered".to_string());
-        Logg::info("Embeddings recompiled successfully".to_string());
-        Logg::info("Caching generating embeddings".to_string());
-        engine_clone.lock().unwrap().cache_embeddings(EMBEDDINGS_PATH)?;
-        Logg::info("Embeddings cached successfully".to_string());
+        compile_embeddings(&engine_clone)?;
     } else {
-        match fs::exists("embeddings.txt") {
-            Ok(true) => {
-                Logg::info("Loading embeddings from found file".to_string());
-                engine_clone.lock().unwrap().load_embeddings(EMBEDDINGS_PATH)?;
-                Logg::info("Embeddings loaded successfully".to_string());
+        match (fs::exists(EMBEDDINGS_PATH), fs::exists(LEGACY_EMBEDDINGS_PATH)) {
+            (Ok(true), _) => {
+                Logg::info("Loading embeddings from found index".to_string());
+                let loaded = engine_clone.lock().unwrap().load_embeddings(EMBEDDINGS_PATH);
+                match loaded {
+                    Ok(summary) => {
+                        log_refresh(&summary);
+                        if summary.is_stale() {
+                            engine_clone.lock().unwrap().cache_embeddings(EMBEDDINGS_PATH)?;
+                            Logg::info("Refreshed embeddings cached successfully".to_string());
+                        }
+                        if engine_clone.lock().unwrap().embeddings_mapped() {
+                            Logg::info("Embeddings mapped from index successfully".to_string());
+                        } else {
+                            Logg::info("Embeddings loaded successfully".to_string());
+                        }
+                    }
+                    Err(e) => {
+                        Logg::warn(format!("Cached embeddings rejected, recompiling: {}", e));
+                        compile_embeddings(&engine_clone)?;
+                    }
+                }
+            }
+            (Ok(false), Ok(true)) => {
+                Logg::info("Importing embeddings from legacy JSON file".to_string());
+                let imported = engine_clone.lock().unwrap().import_embeddings_json(LEGACY_EMBEDDINGS_PATH);
+                match imported {
+                    Ok(summary) => {
+                        log_refresh(&summary);
+                        engine_clone.lock().unwrap().cache_embeddings(EMBEDDINGS_PATH)?;
+                        Logg::info("Legacy embeddings converted to index successfully".to_string());
+                    }
+                    Err(e) => {
+                        Logg::warn(format!("Legacy embeddings rejected, recompiling: {}", e));
+                        compile_embeddings(&engine_clone)?;
+                    }
+                }
             }
-            Ok(false) => {
+            (Ok(false), Ok(false)) => {
                 Logg::info("Embeddings not found, compiling embeddings".to_string());
-                engine_clone.lock().unwrap().build_embeddings()?;
-                Logg::info("Embeddings compiled successfully".to_string());
-                Logg::info("Caching generated embeddings".to_string());
-                engine_clone.lock().unwrap().cache_embeddings(EMBEDDINGS_PATH)?;
-                Logg::info("Embeddings cached successfully".to_string());
+                compile_embeddings(&engine_clone)?;
             }
-            Err(e) => {
+            (Err(e), _) | (_, Err(e)) => {
                 Logg::error(format!("{:?}", e));
             },
         }
     }
 
+    // The lexical index is cheap to build, so any problem with the cached copy just triggers a rebuild
+    let bm25_params = Bm25Params { k1: BM25_K1, b: BM25_B };
+    let lexical_loaded = engine_clone.lock().unwrap().load_lexical_index(LEXICAL_INDEX_PATH, bm25_params);
+    match lexical_loaded {
+        Ok(()) => Logg::info("Lexical index loaded successfully".to_string()),
+        Err(e) => {
+            Logg::info(format!("Building lexical index: {}", e));
+            engine_clone.lock().unwrap().build_lexical_index(bm25_params);
+            engine_clone.lock().unwrap().cache_lexical_index(LEXICAL_INDEX_PATH)?;
+            Logg::info("Lexical index cached successfully".to_string());
+        }
+    }
+
+    // Brute force search is fast enough for small corpora, the ANN graph only pays off on large ones
+    if page_count >= ANN_MIN_PAGES {
+        let hnsw_params = HnswParams { m: HNSW_M, ef_construction: HNSW_EF_CONSTRUCTION, ef_search: HNSW_EF_SEARCH };
+        let ann_loaded = engine_clone.lock().unwrap().load_ann_index(ANN_INDEX_PATH, hnsw_params);
+        match ann_loaded {
+            Ok(()) => Logg::info("ANN index loaded successfully".to_string()),
+            Err(e) => {
+                Logg::info(format!("Building ANN index: {}", e));
+                engine_clone.lock().unwrap().build_ann_index(hnsw_params);
+                engine_clone.lock().unwrap().cache_ann_index(ANN_INDEX_PATH)?;
+                Logg::info("ANN index cached successfully".to_string());
+            }
+        }
+        let sample: Vec<&str> = recall_queries.iter().map(|query| query.as_str()).collect();
+        let recall = engine_clone.lock().unwrap().ann_recall(&sample, 10)?;
+        Logg::info(format!("ANN recall@10 against brute force: {:.3}", recall));
+    }
+
     // Run the BIT (Basic Information Tool) module
     // Logg::warn("Running BIT tests".to_string());
     // bits::run(&engine)?;
     // Logg::warn("BIT tests all PASSED".to_string());
 
+    // Answer every query in a file as JSONL, then exit without serving
+    if args.get(1) == Some(&String::from("--search-batch")) {
+        let queries_path = args
+            .get(2)
+            .ok_or_else(|| anyhow::anyhow!("--search-batch needs a queries file"))?;
+        return search_batch_file(&engine_clone.lock().unwrap(), queries_path, args.get(3), rerank_candidates);
+    }
+
     Logg::warn("Entering maine".to_string());
     println!("{}", "Running".green().bold());
-    let main_control_thread = spinup_server(engine_clone);
+    let main_control_thread = spinup_server(engine_clone, rerank_candidates);
     main_control_thread.join().unwrap();
     println!("{}", "Dead".green().bold());
     Logg::warn("Dead".to_string());
     Ok(())
 }
+
+///
+/// Loads the cross-encoder searches are reranked with, when a model directory is configured
+///
+/// # Arguments
+/// - engine `Engine` an instance of the current DocuBot search engine
+///
+#[cfg(feature = "rerank")]
+fn load_reranker(engine: &mut Engine) -> anyhow::Result<()> {
+    if let Some(cross_encoder_config) = config::cross_encoder_config() {
+        Logg::info(format!("Loading cross-encoder from {}", cross_encoder_config.model_dir.display()));
+        engine.set_reranker(BertCrossEncoder::new(&cross_encoder_config)?);
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "rerank"))]
+fn load_reranker(_engine: &mut Engine) -> anyhow::Result<()> {
+    if env::var_os(RERANK_MODEL_VAR).is_some() {
+        Logg::warn(format!("{} is set but this build has no rerank feature, searches are not reranked", RERANK_MODEL_VAR));
+    }
+    Ok(())
+}
+
+///
+/// Builds embeddings for the whole corpus and writes them to the index file
+///
+/// # Arguments
+/// - engine `Engine` an instance of the current DocuBot search engine
+///
+fn compile_embeddings(engine: &Arc<Mutex<Engine>>) -> anyhow::Result<()> {
+    let reporter = ProgressReporter::new("Embedding corpus");
+    let built = engine
+        .lock()
+        .unwrap()
+        .build_embeddings_with_progress(&|progress| reporter.update(progress));
+    reporter.finish();
+    let summary = built?;
+    Logg::info(format!(
+        "Embeddings compiled successfully: {} pages as {} passages in {:.1}s ({:.1} pages/s)",
+        summary.pages,
+        summary.passages,
+        summary.elapsed.as_secs_f64(),
+        summary.pages_per_second()
+    ));
+    if !summary.truncated.is_empty() {
+        let engine = engine.lock().unwrap();
+        Logg::warn(format!(
+            "{} passages run past the model token limit and were partially ignored, run --token-report for the full list",
+            summary.truncated.len()
+        ));
+        for truncated in summary.truncated.iter().take(TRUNCATION_LOG_LIMIT) {
+            Logg::warn(describe_truncation(&engine, truncated));
+        }
+    }
+    Logg::info("Caching generated embeddings".to_string());
+    engine.lock().unwrap().cache_embeddings(EMBEDDINGS_PATH)?;
+    Logg::info("Embeddings cached successfully".to_string());
+    Ok(())
+}
+
+///
+/// Prints every passage the model truncates, and how far past its token limit each one runs
+///
+/// # Arguments
+/// - engine `Engine` an instance of the current DocuBot search engine
+///
+fn token_report(engine: &Engine) -> anyhow::Result<()> {
+    let report = engine.truncation_report()?;
+    let Some(max_tokens) = report.max_tokens else {
+        println!("The model reads texts of any length, nothing is truncated");
+        return Ok(());
+    };
+    for truncated in &report.truncated {
+        println!("{}", describe_truncation(engine, truncated));
+    }
+    let summary = format!(
+        "{} of {} passages across {} pages exceed the {} token limit",
+        report.truncated.len(),
+        report.passages,
+        report.pages(),
+        max_tokens
+    );
+    println!("{}", summary.bold());
+    Logg::info(summary);
+    Ok(())
+}
+
+///
+/// Searches every query in a file, one per line, and writes the resolved pages of each as a line of JSON
+///
+/// Queries are searched with the default options, as if sent to `/search` with only `q` set.
+///
+/// # Arguments
+/// - engine `Engine` an instance of the current DocuBot search engine
+/// - queries_path `&str` the file of queries, blank lines are skipped
+/// - output_path `Option<&String>` the file to write the results to, standard output when missing
+/// - rerank_candidates `Option<usize>` how many candidates a search reranks, `None` without a cross-encoder
+///
+fn search_batch_file(
+    engine: &Engine,
+    queries_path: &str,
+    output_path: Option<&String>,
+    rerank_candidates: Option<usize>,
+) -> anyhow::Result<()> {
+    let queries: Vec<String> = fs::read_to_string(queries_path)?
+        .lines()
+        .map(|line| line.trim().to_string())
+        .filter(|line| !line.is_empty())
+        .collect();
+    Logg::info(format!("Searching {} queries from {}", queries.len(), queries_path));
+    let started = Instant::now();
+    let results = server::run_batch(engine, &queries, &HashMap::new(), rerank_candidates)?;
+    let mut output: Box<dyn Write> = match output_path {
+        Some(path) => Box::new(BufWriter::new(fs::File::create(path)?)),
+        None => Box::new(io::stdout().lock()),
+    };
+    for result in &results {
+        serde_json::to_writer(&mut output, result)?;
+        writeln!(output)?;
+    }
+    output.flush()?;
+    Logg::info(format!(
+        "Searched {} queries in {:.1}s",
+        results.len(),
+        started.elapsed().as_secs_f64()
+    ));
+    Ok(())
+}
+
+///
+/// Describes a truncated passage by its page, for reports and warnings
+///
+fn describe_truncation(engine: &Engine, truncated: &TruncatedPassage) -> String {
+    let name = engine.page(truncated.id).map_or("", |page| page.name.as_str());
+    format!(
+        "Page {} \"{}\" passage {}: {} tokens, {} past the limit",
+        truncated.id, name, truncated.passage, truncated.tokens, truncated.excess
+    )
+}
+
+///
+/// Logs how cached embeddings were reconciled with the corpus
+///
+fn log_refresh(summary: &RefreshSummary) {
+    Logg::info(format!(
+        "Embeddings reused: {}, recomputed: {}, removed: {}, requantized: {}, fields changed: {}",
+        summary.reused, summary.recomputed, summary.removed, summary.requantized, summary.fields_changed
+    ));
+    if let Some(error) = &summary.mapping_error {
+        Logg::info(format!("Embeddings index read rather than mapped: {}", error));
+    }
+}
@@ -0,0 +1,104 @@
+/*
+ *
+ * Progress renders embedding build progress, as a progress bar with an ETA on a terminal
+ * and as periodic log lines everywhere else.
+ *
+ */
+
+use crate::consts::PROGRESS_LOG_INTERVAL;
+use crate::logg::Logg;
+use docueyes::model::EmbeddingProgress;
+use indicatif::{ProgressBar, ProgressStyle};
+use std::io::{stderr, IsTerminal};
+use std::sync::Mutex;
+use std::time::Instant;
+
+///
+/// Reports the progress of a long running embedding build.
+///
+/// # Fields
+/// - label `&str` what is being built
+/// - bar `Option<ProgressBar>` the progress bar, only when stderr is a terminal
+/// - started `Instant` when the build started
+/// - last_log `Mutex<Instant>` when progress was last logged, to space out log lines
+///
+pub struct ProgressReporter {
+    label: &'static str,
+    bar: Option<ProgressBar>,
+    started: Instant,
+    last_log: Mutex<Instant>,
+}
+
+impl ProgressReporter {
+    ///
+    /// Start reporting, drawing a bar when stderr is a terminal
+    ///
+    /// # Arguments
+    /// - label `&str` what is being built, shown on the bar and in log lines
+    ///
+    pub fn new(label: &'static str) -> Self {
+        let bar = stderr().is_terminal().then(|| {
+            let bar = ProgressBar::new(0);
+            bar.set_style(
+                ProgressStyle::default_bar()
+                    .template("{msg} [{bar:40.cyan/blue}] {pos}/{len} passages, batch {prefix} ({per_sec}, ETA {eta})")
+                    .progress_chars("=> "),
+            );
+            bar.set_message(label);
+            bar
+        });
+        let now = Instant::now();
+        ProgressReporter {
+            label,
+            bar,
+            started: now,
+            last_log: Mutex::new(now),
+        }
+    }
+
+    ///
+    /// Report a finished batch, safe to call from several threads at once
+    ///
+    /// Progress is counted in passages rather than pages, as that is what the model encodes.
+    /// The two only agree when pages are embedded whole, chunking makes the total larger than the page count
+    ///
+    /// # Arguments
+    /// - progress `EmbeddingProgress` the passages done and the batch that just finished
+    ///
+    pub fn update(&self, progress: EmbeddingProgress) {
+        if let Some(bar) = &self.bar {
+            bar.set_length(progress.total as u64);
+            bar.set_prefix(format!("{}/{}", progress.batch, progress.batches));
+            // Batches from a pool finish out of order, so never let the bar run backwards
+            if progress.done as u64 > bar.position() {
+                bar.set_position(progress.done as u64);
+            }
+            return;
+        }
+
+        let mut last_log = self.last_log.lock().unwrap();
+        if last_log.elapsed() < PROGRESS_LOG_INTERVAL && progress.done < progress.total {
+            return;
+        }
+        *last_log = Instant::now();
+        Logg::info(format!(
+            "{}: {}/{} passages ({:.0}%), batch {}/{}, {:.0}s elapsed",
+            self.label,
+            progress.done,
+            progress.total,
+            progress.done as f64 * 100.0 / progress.total.max(1) as f64,
+            progress.batch,
+            progress.batches,
+            self.started.elapsed().as_secs_f64()
+        ));
+    }
+
+    ///
+    /// Stop reporting, clearing the bar so later output starts on a clean line
+    ///
+    pub fn finish(&self) {
+        if let Some(bar) = &self.bar {
+            bar.finish_and_clear();
+        }
+    }
+}
@@ -1,10 +1,15 @@
+use std::collections::HashMap;
+use std::io::Cursor;
 use std::sync::{Arc, Mutex};
 use chrono::{DateTime, Local, Utc};
-use tiny_http::{Header, Response, Server};
-use docueyes::corpus::Page;
-use docueyes::engine::Engine;
-use crate::consts::{MAX_QUERY_LENGTH, MAX_RESULTS, MIN_QUERY_LENGTH, SERVER_LOCATION, SERVER_SPIN_UP_ATTEMPTS, TEMPERATURE};
-use serde::Serialize;
+use tiny_http::{Header, Method, Request, Response, Server};
+use docueyes::engine::{Engine, PageScore, ResolveLevel, SearchResult};
+use docueyes::hybrid::{DEFAULT_RRF_K, Fusion, SearchMode};
+use docueyes::mmr::MmrParams;
+use docueyes::query_cache::QueryCacheStats;
+use docueyes::rerank::RerankParams;
+use crate::consts::{BAD_REQUEST, FUSED_TEMPERATURE, MAX_BATCH_QUERIES, MAX_QUERY_LENGTH, MAX_RERANK_CANDIDATES, MAX_RESULTS, MIN_QUERY_LENGTH, NOT_FOUND, OK, RERANK_TEMPERATURE, SERVER_LOCATION, SERVER_SPIN_UP_ATTEMPTS, SIMILAR_RESULTS, TEMPERATURE};
+use serde::{Deserialize, Serialize};
 use crate::logg::Logg;
 
 #[derive(Serialize, Debug)]
@@ -14,13 +19,61 @@ enum SuccessCode {
     Unknown,
 }
 
+#[derive(Serialize, Debug)]
+struct RankedPage {
+    #[serde(flatten)]
+    result: SearchResult,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    semantic_score: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lexical_score: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rerank_score: Option<f32>,
+}
+
 #[derive(Serialize, Debug)]
 struct RespBody {
     #[serde(serialize_with = "serialize_datetime")]
     datetime: DateTime<Local>,
     code: SuccessCode,
     query: String,
-    resolved: Vec<Page>
+    resolved: Vec<RankedPage>
+}
+
+#[derive(Deserialize, Debug)]
+struct BatchRequest {
+    queries: Vec<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct BatchResult {
+    query: String,
+    code: SuccessCode,
+    resolved: Vec<RankedPage>,
+}
+
+#[derive(Serialize, Debug)]
+struct BatchRespBody {
+    #[serde(serialize_with = "serialize_datetime")]
+    datetime: DateTime<Local>,
+    code: SuccessCode,
+    results: Vec<BatchResult>,
+}
+
+#[derive(Serialize, Debug)]
+struct SimilarRespBody {
+    #[serde(serialize_with = "serialize_datetime")]
+    datetime: DateTime<Local>,
+    code: SuccessCode,
+    page_id: i64,
+    resolved: Vec<RankedPage>,
+}
+
+#[derive(Serialize, Debug)]
+struct StatsBody {
+    #[serde(serialize_with = "serialize_datetime")]
+    datetime: DateTime<Local>,
+    query_cache: QueryCacheStats,
 }
 
 fn serialize_datetime<S>(
@@ -33,16 +86,478 @@ where
     serializer.serialize_str(&dt.to_rfc3339())
 }
 
+///
+/// How a query is searched, read from the request parameters
+///
+/// # Fields
+/// - search_mode `SearchMode` which scorer to use
+/// - resolve_level `ResolveLevel` which part of the ranking to return
+/// - rerank `usize` how many of the best candidates the cross-encoder reorders before resolving, 0 to skip it
+/// - diversity `Option<MmrParams>` how to diversify the resolved ranking, `None` to keep it by similarity
+///
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct SearchOptions {
+    search_mode: SearchMode,
+    resolve_level: ResolveLevel,
+    rerank: usize,
+    diversity: Option<MmrParams>,
+}
+
+///
+/// Splits a request URL into its path and decoded query parameters
+///
+/// # Arguments
+/// - url `&str` the raw request URL, e.g. `/search?q=what%20is%20crm&level=first`
+///
+/// # Returns
+/// - (path, params) `(&str, HashMap<String, String>)` the path and every `key=value` pair after the `?`
+///
+fn split_url(url: &str) -> (&str, HashMap<String, String>) {
+    let (path, query_string) = url.split_once('?').unwrap_or((url, ""));
+    let params = query_string
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (decode_component(key), decode_component(value))
+        })
+        .collect();
+    (path, params)
+}
+
+///
+/// Reads the page id out of a `/pages/{id}/similar` path
+///
+/// # Arguments
+/// - path `&str` the request path
+///
+/// # Returns
+/// - id `Option<i64>` the page id, `None` when the path is not a similar pages path
+///
+fn parse_similar_path(path: &str) -> Option<i64> {
+    path.strip_prefix("/pages/")?.strip_suffix("/similar")?.parse().ok()
+}
+
+///
+/// Decodes `+` and `%XX` escapes in a URL component, leaving malformed escapes as they are
+///
+fn decode_component(component: &str) -> String {
+    let bytes = component.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let high = (bytes[i + 1] as char).to_digit(16);
+                let low = (bytes[i + 2] as char).to_digit(16);
+                match (high, low) {
+                    (Some(high), Some(low)) => {
+                        decoded.push((high * 16 + low) as u8);
+                        i += 2;
+                    }
+                    _ => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+///
+/// Reads the resolve level from the `level` and `n` query parameters
+///
+/// # Arguments
+/// - params `HashMap<String, String>` the decoded query parameters
+///
+/// # Returns
+/// - level `ResolveLevel` one of `first`, `mid`, `last` or `to` (advancing `n` results), defaulting to `to` with `n` of 0
+///
+fn parse_resolve_level(params: &HashMap<String, String>) -> Result<ResolveLevel, String> {
+    let steps = match params.get("n") {
+        Some(n) => n.parse::<usize>().map_err(|e| format!("invalid n {}: {}", n, e))?,
+        None => 0,
+    };
+    match params.get("level").map(|level| level.to_lowercase()).as_deref() {
+        None | Some("to") => Ok(ResolveLevel::To(steps)),
+        Some("first") => Ok(ResolveLevel::First),
+        Some("mid") => Ok(ResolveLevel::Mid),
+        Some("last") => Ok(ResolveLevel::Last),
+        Some(other) => Err(format!("unknown level {}", other)),
+    }
+}
+
+///
+/// Reads the search mode from the `mode`, `fusion`, `k` and `weight` query parameters
+///
+/// Queries shorter than `MIN_QUERY_LENGTH` are usually bare keywords, so without an explicit mode they are searched hybrid.
+///
+/// # Arguments
+/// - params `HashMap<String, String>` the decoded query parameters
+/// - query `&str` the query being searched
+///
+/// # Returns
+/// - mode `SearchMode` one of `semantic`, `lexical` or `hybrid`, fused by `rrf` (with `k`) or `blend` (with semantic `weight`)
+///
+fn parse_search_mode(params: &HashMap<String, String>, query: &str) -> Result<SearchMode, String> {
+    let fusion = match params.get("fusion").map(|fusion| fusion.to_lowercase()).as_deref() {
+        None | Some("rrf") => Fusion::ReciprocalRank {
+            k: parse_param(params, "k", DEFAULT_RRF_K)?,
+        },
+        Some("blend") => Fusion::WeightedBlend {
+            semantic_weight: parse_param(params, "weight", 0.5)?,
+        },
+        Some(other) => return Err(format!("unknown fusion {}", other)),
+    };
+    match params.get("mode").map(|mode| mode.to_lowercase()).as_deref() {
+        None if query.len() < MIN_QUERY_LENGTH => Ok(SearchMode::Hybrid(fusion)),
+        None | Some("semantic") => Ok(SearchMode::Semantic),
+        Some("lexical") => Ok(SearchMode::Lexical),
+        Some("hybrid") => Ok(SearchMode::Hybrid(fusion)),
+        Some(other) => Err(format!("unknown mode {}", other)),
+    }
+}
+
+///
+/// Reads how many candidates to rerank from the `rerank` query parameter
+///
+/// # Arguments
+/// - params `HashMap<String, String>` the decoded query parameters
+/// - default `Option<usize>` the configured candidate count, `None` when no cross-encoder is loaded
+///
+/// # Returns
+/// - candidates `usize` the requested count capped at `MAX_RERANK_CANDIDATES`, the default when absent, 0 to skip reranking
+///
+fn parse_rerank(params: &HashMap<String, String>, default: Option<usize>) -> Result<usize, String> {
+    let Some(candidates) = params.get("rerank") else {
+        return Ok(default.unwrap_or(0));
+    };
+    let candidates = candidates
+        .parse::<usize>()
+        .map_err(|e| format!("invalid rerank {}: {}", candidates, e))?;
+    match default {
+        Some(_) => Ok(candidates.min(MAX_RERANK_CANDIDATES)),
+        None if candidates == 0 => Ok(0),
+        None => Err("no cross-encoder is loaded".to_string()),
+    }
+}
+
+///
+/// Reads the diversification from the `mmr` query parameter
+///
+/// # Arguments
+/// - params `HashMap<String, String>` the decoded query parameters
+///
+/// # Returns
+/// - diversity `Option<MmrParams>` the lambda between 0 (only novelty) and 1 (only similarity), `None` to keep the plain ranking
+///
+fn parse_diversity(params: &HashMap<String, String>) -> Result<Option<MmrParams>, String> {
+    let Some(lambda) = params.get("mmr") else {
+        return Ok(None);
+    };
+    match lambda.parse::<f32>() {
+        Ok(lambda) if (0.0..=1.0).contains(&lambda) => Ok(Some(MmrParams {
+            lambda,
+            ..MmrParams::default()
+        })),
+        _ => Err(format!("invalid mmr {}: expected a lambda between 0 and 1", lambda)),
+    }
+}
+
+///
+/// Reads every search option from the query parameters
+///
+/// # Arguments
+/// - params `HashMap<String, String>` the decoded query parameters
+/// - query `&str` the query being searched
+/// - rerank_candidates `Option<usize>` how many candidates a search reranks unless it asks otherwise, `None` without a cross-encoder
+///
+/// # Returns
+/// - options `SearchOptions` how to search the query, or why a parameter is invalid
+///
+pub(crate) fn parse_options(
+    params: &HashMap<String, String>,
+    query: &str,
+    rerank_candidates: Option<usize>,
+) -> Result<SearchOptions, String> {
+    Ok(SearchOptions {
+        search_mode: parse_search_mode(params, query)?,
+        resolve_level: parse_resolve_level(params)?,
+        rerank: parse_rerank(params, rerank_candidates)?,
+        diversity: parse_diversity(params)?,
+    })
+}
+
+fn parse_param(params: &HashMap<String, String>, name: &str, default: f32) -> Result<f32, String> {
+    match params.get(name) {
+        Some(value) => value.parse().map_err(|e| format!("invalid {} {}: {}", name, value, e)),
+        None => Ok(default),
+    }
+}
+
+///
+/// Runs a query through the selected scorer and resolves the ranked pages
+///
+/// With an ANN graph loaded a semantic search ranks only the `MAX_RESULTS` nearest pages instead of every page, so
+/// `mid` and `last` windows and ranks fall within those. Reranking and diversification read at most
+/// `MAX_RERANK_CANDIDATES` and `DEFAULT_MMR_CANDIDATES` of the best, which stay within them either way.
+///
+/// # Arguments
+/// - engine `Engine` an instance of the current DocuBot search engine
+/// - query `&str` the query to search for
+/// - options `SearchOptions` how to search the query
+/// - semantic `Option<Vec<PageScore>>` semantic scores already computed for the query by a batch search
+///
+/// # Returns
+/// - resolved `Vec<RankedPage>` the resolved pages with their rank in the whole ranking, component scores and best matching passage
+///
+fn run_search(
+    engine: &Engine,
+    query: &str,
+    options: SearchOptions,
+    semantic: Option<Vec<PageScore>>,
+) -> anyhow::Result<Vec<RankedPage>> {
+    let SearchOptions { search_mode, resolve_level, rerank, diversity } = options;
+    let (scores, temperature, components) = match (search_mode, semantic) {
+        (SearchMode::Semantic, Some(scores)) => (scores, TEMPERATURE, HashMap::new()),
+        (SearchMode::Semantic, None) if engine.has_ann_index() => {
+            // The graph only reaches the nearest pages, ranking every page would cost what it saves
+            (engine.search_approximate(query, MAX_RESULTS)?, TEMPERATURE, HashMap::new())
+        }
+        (SearchMode::Semantic, None) => (engine.search(query)?, TEMPERATURE, HashMap::new()),
+        (SearchMode::Lexical, _) => (engine.search_lexical(query)?, FUSED_TEMPERATURE, HashMap::new()),
+        (SearchMode::Hybrid(fusion), _) => {
+            let fused = engine.search_hybrid(query, fusion, TEMPERATURE)?;
+            let scores = fused.iter().map(|hybrid| hybrid.page_score()).collect();
+            let components = fused.into_iter().map(|hybrid| (hybrid.id, hybrid)).collect();
+            (scores, FUSED_TEMPERATURE, components)
+        }
+    };
+
+    let first_stage: HashMap<i64, f32> = scores.iter().map(|score| (score.id, score.similarity)).collect();
+    let (scores, temperature) = if rerank > 0 {
+        let params = RerankParams { candidates: rerank };
+        (engine.rerank(query, scores, params)?, RERANK_TEMPERATURE)
+    } else {
+        (scores, temperature)
+    };
+
+    let resolved = match diversity {
+        Some(params) => engine.resolve_diverse(scores, temperature, MAX_RESULTS, resolve_level, params),
+        None => engine.resolve(scores, temperature, MAX_RESULTS, resolve_level),
+    };
+    let resolved = resolved
+        .into_iter()
+        .map(|result| {
+            let first_stage = first_stage.get(&result.page.id).copied();
+            let rerank_score = (rerank > 0).then_some(result.similarity);
+            let (semantic_score, lexical_score) = match (search_mode, components.get(&result.page.id)) {
+                (SearchMode::Semantic, _) => (first_stage, None),
+                (SearchMode::Lexical, _) => (None, first_stage),
+                (SearchMode::Hybrid(_), Some(hybrid)) => (hybrid.semantic, hybrid.lexical),
+                (SearchMode::Hybrid(_), None) => (None, None),
+            };
+            RankedPage {
+                result,
+                semantic_score,
+                lexical_score,
+                rerank_score,
+            }
+        })
+        .collect();
+    Ok(resolved)
+}
+
+///
+/// Finds the pages most like a page and resolves the ones that clear the temperature
+///
+/// # Arguments
+/// - engine `Engine` an instance of the current DocuBot search engine
+/// - id `i64` the page to find related pages for
+/// - k `usize` the most pages to return
+///
+/// # Returns
+/// - resolved `Vec<RankedPage>` the related pages with their rank, similarity and best matching passage
+///
+fn run_similar(engine: &Engine, id: i64, k: usize) -> anyhow::Result<Vec<RankedPage>> {
+    let scores = engine.similar_to(id, k)?;
+    let resolved = engine
+        .resolve(scores, TEMPERATURE, k, ResolveLevel::To(0))
+        .into_iter()
+        .map(|result| RankedPage {
+            semantic_score: Some(result.similarity),
+            result,
+            lexical_score: None,
+            rerank_score: None,
+        })
+        .collect();
+    Ok(resolved)
+}
+
+///
+/// Runs many queries, sharing one model batch and one pass over the embeddings between the exact semantic ones
+///
+/// The ANN graph and the other scorers still go query by query, and a query that fails does not fail the others.
+///
+/// # Arguments
+/// - engine `Engine` an instance of the current DocuBot search engine
+/// - queries `&[String]` the queries to search for
+/// - params `HashMap<String, String>` the decoded query parameters, applied to every query
+/// - rerank_candidates `Option<usize>` how many candidates a search reranks unless it asks otherwise, `None` without a cross-encoder
+///
+/// # Returns
+/// - results `Vec<BatchResult>` the resolved pages of every query, in order, or an error when a parameter is invalid
+///
+pub(crate) fn run_batch(
+    engine: &Engine,
+    queries: &[String],
+    params: &HashMap<String, String>,
+    rerank_candidates: Option<usize>,
+) -> anyhow::Result<Vec<BatchResult>> {
+    let options: Vec<SearchOptions> = queries
+        .iter()
+        .map(|query| parse_options(params, query, rerank_candidates))
+        .collect::<Result<_, _>>()
+        .map_err(anyhow::Error::msg)?;
+    let batched: Vec<usize> = (0..queries.len())
+        .filter(|&index| {
+            is_valid_query(&queries[index])
+                && matches!(options[index].search_mode, SearchMode::Semantic)
+                && !engine.has_ann_index()
+        })
+        .collect();
+    let texts: Vec<&str> = batched.iter().map(|&index| queries[index].as_str()).collect();
+    let mut semantic: HashMap<usize, Vec<PageScore>> = match engine.search_batch(&texts) {
+        Ok(scores) => batched.into_iter().zip(scores).collect(),
+        Err(e) => {
+            Logg::error(format!("Failed to batch search, searching queries one by one: {}", e));
+            HashMap::new()
+        }
+    };
+
+    let results = queries
+        .iter()
+        .zip(options)
+        .enumerate()
+        .map(|(index, (query, options))| {
+            let resolved = if is_valid_query(query) {
+                run_search(engine, query, options, semantic.remove(&index))
+            } else {
+                Err(anyhow::anyhow!("query must be 1 to {} bytes", MAX_QUERY_LENGTH))
+            };
+            match resolved {
+                Ok(resolved) => BatchResult {
+                    query: query.clone(),
+                    code: SuccessCode::Success,
+                    resolved,
+                },
+                Err(e) => {
+                    Logg::error(format!("Failed to search batch query {} cause: {}", index, e));
+                    BatchResult {
+                        query: query.clone(),
+                        code: SuccessCode::Failed,
+                        resolved: Vec::new(),
+                    }
+                }
+            }
+        })
+        .collect();
+    Ok(results)
+}
+
+fn is_valid_query(query: &str) -> bool {
+    !query.is_empty() && query.len() <= MAX_QUERY_LENGTH
+}
+
+///
+/// Reads a batch search request body and runs its queries
+///
+/// # Arguments
+/// - engine `Engine` an instance of the current DocuBot search engine
+/// - body `&str` the request body, a JSON object with a `queries` array
+/// - params `HashMap<String, String>` the decoded query parameters, applied to every query
+/// - rerank_candidates `Option<usize>` how many candidates a search reranks unless it asks otherwise, `None` without a cross-encoder
+///
+/// # Returns
+/// - results `Vec<BatchResult>` the resolved pages of every query, in order
+///
+fn serve_batch(
+    engine: &Arc<Mutex<Engine>>,
+    body: &str,
+    params: &HashMap<String, String>,
+    rerank_candidates: Option<usize>,
+) -> anyhow::Result<Vec<BatchResult>> {
+    let request: BatchRequest = serde_json::from_str(body)?;
+    if request.queries.len() > MAX_BATCH_QUERIES {
+        return Err(anyhow::anyhow!(
+            "{} queries is over the batch limit of {}",
+            request.queries.len(),
+            MAX_BATCH_QUERIES
+        ));
+    }
+    run_batch(&engine.lock().unwrap(), &request.queries, params, rerank_candidates)
+}
+
+///
+/// Serializes a response body to a JSON response carrying the server headers
+///
+/// # Arguments
+/// - body `T` the response body
+///
+/// # Returns
+/// - response `Response` the response ready to send
+///
+fn json_response<T: Serialize>(body: &T) -> Response<Cursor<Vec<u8>>> {
+    Response::from_string(serde_json::to_string(body).unwrap_or_else(|e| {
+        Logg::error(format!("Failed to serialize response body: {}", e));
+        "Error".to_string()
+    }))
+        .with_header(Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap_or_else(|e| {
+            Logg::error("FATAL FATAL FATAL".to_string());
+            Logg::error(format!("Failed to create response header: {:?}.", e));
+            std::process::exit(1);
+        }))
+        .with_header(Header::from_bytes(&b"Maker"[..], &b"Kilroy Was Here"[..]).unwrap_or_else(|e| {
+            Logg::error("FATAL FATAL FATAL".to_string());
+            Logg::error(format!("Failed to create response header: {:?}.", e));
+            std::process::exit(1);
+        }))
+}
+
+///
+/// Answers a request that cannot be served with a 400 and a failed search body, so clients always get JSON back
+///
+/// # Arguments
+/// - request `Request` the request to answer
+/// - query `String` the query the request carried, empty when it had none
+/// - reason `String` why the request was rejected, logged
+///
+fn reject(request: Request, query: String, reason: String) {
+    Logg::warn(format!("Rejecting request: {}", reason));
+    let response_body = RespBody {
+        datetime: DateTime::from(Utc::now()),
+        code: SuccessCode::Failed,
+        query,
+        resolved: Vec::new(),
+    };
+    if let Err(e) = request.respond(json_response(&response_body).with_status_code(BAD_REQUEST)) {
+        Logg::error(format!("Failed to send response to server {}", e));
+    }
+}
+
 ///
 /// Spins up an instance of the API server for Docubot
 ///
 /// # Arguments
 /// - engine `Engine` an instance of the current DocuBot search engine
+/// - rerank_candidates `Option<usize>` how many candidates a search reranks unless it asks otherwise, `None` without a cross-encoder
 ///
 /// # Returns
 /// - handle 'JoinHandle' a handle to the newly created thread
 ///
-pub fn spinup_server(engine: Arc<Mutex<Engine>>) -> std::thread::JoinHandle<()> {
+pub fn spinup_server(engine: Arc<Mutex<Engine>>, rerank_candidates: Option<usize>) -> std::thread::JoinHandle<()> {
     // TODO harden this code and probably make it it's own struct
     let mut delay = SERVER_SPIN_UP_ATTEMPTS;
     let server = loop {
@@ -59,58 +574,123 @@ pub fn spinup_server(engine: Arc<Mutex<Engine>>) -> std::thread::JoinHandle<()>
     std::thread::spawn(move || {
         Logg::info(format!("Server at {}", SERVER_LOCATION));
 
-        for request in server.incoming_requests() {
-            let url = request.url();
+        for mut request in server.incoming_requests() {
+            let url = request.url().to_string();
 
-            if url.contains("/search?q=") {
-                let query = url.strip_prefix("/search?q=").unwrap_or("");
-                let norm_query = query.replace("%20", " ");
+            let (path, params) = split_url(&url);
 
-                let mut success_code = SuccessCode::Unknown;
-                // Safety checks
-                if norm_query.is_empty() {
-                    Logg::error("Query is empty".to_string());
+            if path == "/search" && params.contains_key("q") {
+                let query = params.get("q").cloned().unwrap_or_default();
+                if !is_valid_query(&query) {
+                    reject(request, query, format!("query must be 1 to {} bytes", MAX_QUERY_LENGTH));
+                    continue;
                 }
-                if norm_query.len() <= MAX_QUERY_LENGTH || norm_query.len() >= MIN_QUERY_LENGTH {
-                    let search_return = engine_clone.lock().unwrap().search(&*norm_query).unwrap_or_else(|e| {
-                        Logg::error(format!("Failed to search query cause: {}", e));
-                        success_code = SuccessCode::Failed;
-                        Vec::new()
-                    });
-
-                    Logg::info("Query good, serving".to_string());
+                let options = match parse_options(&params, &query, rerank_candidates) {
+                    Ok(options) => options,
+                    Err(e) => {
+                        reject(request, query, e);
+                        continue;
+                    }
+                };
 
-                    let resolved_pages =
-                        engine_clone.lock().unwrap().resolve(search_return, TEMPERATURE, MAX_RESULTS);
+                let mut success_code = SuccessCode::Unknown;
+                Logg::info(format!("Query good, serving with {:?}", options.search_mode));
+                let resolved = run_search(&engine_clone.lock().unwrap(), &query, options, None).unwrap_or_else(|e| {
+                    Logg::error(format!("Failed to search query cause: {}", e));
+                    success_code = SuccessCode::Failed;
+                    Vec::new()
+                });
 
+                if !matches!(success_code, SuccessCode::Failed) {
                     success_code = SuccessCode::Success;
-                    let response_body = RespBody {
-                        datetime: DateTime::from(Utc::now()),
-                        code: success_code,
-                        query: query.parse().unwrap(),
-                        resolved: resolved_pages,
-                    };
-
-                    // TODO: Switch to JSON response
-                    let response = Response::from_string(serde_json::to_string(&response_body).unwrap_or_else(|e| {
-                        Logg::error(format!("Failed to serialize response body: {}", e));
-                        "Error".to_string()
-                    }))
-                        .with_header(Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap_or_else(|e| {
-                            Logg::error("FATAL FATAL FATAL".to_string());
-                            Logg::error(format!("Failed to create response header: {:?}.", e));
-                            std::process::exit(1);
-                        }))
-                        .with_header(Header::from_bytes(&b"Maker"[..], &b"Kilroy Was Here"[..]).unwrap_or_else(|e| {
-                            Logg::error("FATAL FATAL FATAL".to_string());
-                            Logg::error(format!("Failed to create response header: {:?}.", e));
-                            std::process::exit(1);
-                        }));
-
-                    if let Err(e) = request.respond(response) {
-                        Logg::error(format!("Failed to send response to server {}", e));
+                }
+                let response_body = RespBody {
+                    datetime: DateTime::from(Utc::now()),
+                    code: success_code,
+                    query,
+                    resolved,
+                };
+
+                let response = json_response(&response_body);
+
+                if let Err(e) = request.respond(response) {
+                    Logg::error(format!("Failed to send response to server {}", e));
+                }
+            } else if path == "/search/batch" && *request.method() == Method::Post {
+                let mut body = String::new();
+                let results = match request.as_reader().read_to_string(&mut body) {
+                    Ok(_) => serve_batch(&engine_clone, &body, &params, rerank_candidates),
+                    Err(e) => Err(e.into()),
+                };
+                let response_body = match results {
+                    Ok(results) => {
+                        Logg::info(format!("Batch of {} queries served", results.len()));
+                        BatchRespBody {
+                            datetime: DateTime::from(Utc::now()),
+                            code: SuccessCode::Success,
+                            results,
+                        }
+                    }
+                    Err(e) => {
+                        Logg::error(format!("Failed to serve batch cause: {}", e));
+                        BatchRespBody {
+                            datetime: DateTime::from(Utc::now()),
+                            code: SuccessCode::Failed,
+                            results: Vec::new(),
+                        }
                     }
+                };
+                // Short of a dropped connection, a failure here is a malformed body, too many queries or an invalid parameter
+                let status = if matches!(response_body.code, SuccessCode::Failed) { BAD_REQUEST } else { OK };
+                if let Err(e) = request.respond(json_response(&response_body).with_status_code(status)) {
+                    Logg::error(format!("Failed to send response to server {}", e));
+                }
+            } else if let Some(page_id) = parse_similar_path(path) {
+                let k = match params.get("k").map(|k| k.parse::<usize>()) {
+                    Some(Ok(k)) => k.min(MAX_RESULTS),
+                    Some(Err(e)) => {
+                        reject(request, String::new(), format!("invalid k: {}", e));
+                        continue;
+                    }
+                    None => SIMILAR_RESULTS,
+                };
+                let resolved = {
+                    let engine = engine_clone.lock().unwrap();
+                    if engine.page(page_id).is_none() {
+                        Err((NOT_FOUND, format!("no page with id {}", page_id)))
+                    } else {
+                        // Short of an unknown id, a failure here is a page without embeddings
+                        run_similar(&engine, page_id, k).map_err(|e| (BAD_REQUEST, e.to_string()))
+                    }
+                };
+                let (code, resolved, status) = match resolved {
+                    Ok(resolved) => (SuccessCode::Success, resolved, OK),
+                    Err((status, e)) => {
+                        Logg::warn(format!("Failed to find pages similar to {} cause: {}", page_id, e));
+                        (SuccessCode::Failed, Vec::new(), status)
+                    }
+                };
+                let response_body = SimilarRespBody {
+                    datetime: DateTime::from(Utc::now()),
+                    code,
+                    page_id,
+                    resolved,
+                };
+                if let Err(e) = request.respond(json_response(&response_body).with_status_code(status)) {
+                    Logg::error(format!("Failed to send response to server {}", e));
+                }
+            } else if path == "/stats" {
+                let stats = StatsBody {
+                    datetime: DateTime::from(Utc::now()),
+                    query_cache: engine_clone.lock().unwrap().query_cache_stats(),
+                };
+                if let Err(e) = request.respond(json_response(&stats)) {
+                    Logg::error(format!("Failed to send response to server {}", e));
                 }
+            } else {
+                let query = params.get("q").cloned().unwrap_or_default();
+                let reason = format!("no route for {} {}", request.method(), path);
+                reject(request, query, reason);
             }
         }
     })